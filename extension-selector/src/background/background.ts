@@ -10,7 +10,10 @@ import { browser, Runtime } from 'webextension-polyfill-ts';
 
 import { initHostPeerConnection, initLocalPeerConnection, WSMessageHandler } from '../lib/rtcConnection';
 import { settings } from '../lib/settings';
-import { ActionResponseMessage, RoomInfoMessage, ToBackgroundMessage } from '../lib/types';
+import {
+  ActionResponseMessage, FromContentMessage, NowPlaying, PROTOCOL_VERSION, RoomInfoMessage,
+  StatusUpdateMessage, ToBackgroundMessage, ToHostMessage,
+} from '../lib/types';
 
 type ListenerConnInfo = {
   connection: RTCPeerConnection,
@@ -101,77 +104,125 @@ const createRoomManager = (
 ): RoomManager => {
   const uri = `${settings.WS_SERVER}/rooms`
     + `/${encodeURIComponent(currentRoom)}`
-    + `/host?token=${encodeURIComponent(authToken)}`;
+    + `/host?token=${encodeURIComponent(authToken)}`
+    // starting a room here means we want it here, even if another tab still has it
+    + '&takeover=true';
   const ws = new WebSocket(uri);
 
   const handlers = new Map();
   const listenerConns: Map<string, ListenerConnInfo> = new Map();
-  const senders: Map<string, RTCRtpSender> = new Map();
-  ws.onmessage = async ({ data }) => {
-    console.debug('[host] received websocket message', data);
-    const { from, msg } = JSON.parse(data);
-    const handler = handlers.get(from);
-    if (handler) {
-      // old listener, there's a handler for that
-      console.debug('[host] handling message', JSON.parse(msg));
-      handler(JSON.parse(msg));
-    } else {
-      // new listener, create a new connection to that listener
-      console.log('[host] new listener', from);
-      let pc: RTCPeerConnection;
-      try {
-        pc = await initHostPeerConnection(handlers, ws, from);
-      } catch (err) {
-        console.error(err);
-        return;
-      }
-      myStream.getAudioTracks().forEach((track) => {
-        const sender = pc.addTrack(track, myStream);
-        senders.set(track.id, sender);
-      });
-      const cleanupListener = () => {
-        pc.close();
-        handlers.delete(from);
-        listenerConns.delete(from);
-      };
-      pc.onconnectionstatechange = () => {
-        if (['closed'].includes(pc.connectionState)) {
-          console.log('[host] connection closed');
-          cleanupListener();
-        }
-      };
-      pc.oniceconnectionstatechange = () => {
-        if (['closed', 'failed', 'completed'].includes(pc.iceConnectionState)) {
-          console.log('[host] ice connection', pc.iceConnectionState);
-          cleanupListener();
-        }
-      };
-      listenerConns.set(from, {
-        connection: pc,
-        senders,
-      });
+  // listener UUID -> peer connection being set up
+  const joining: Map<string, Promise<void>> = new Map();
+
+  const removeListener = (id: string) => {
+    listenerConns.get(id)?.connection.close();
+    handlers.delete(id);
+    listenerConns.delete(id);
+  };
+
+  const addListener = async (id: string) => {
+    let pc: RTCPeerConnection;
+    try {
+      pc = await initHostPeerConnection(handlers, ws, id);
+    } catch (err) {
+      console.error(err);
+      return;
     }
+    const senders: Map<string, RTCRtpSender> = new Map();
+    myStream.getAudioTracks().forEach((track) => {
+      const sender = pc.addTrack(track, myStream);
+      senders.set(track.id, sender);
+    });
+    pc.onconnectionstatechange = () => {
+      if (['closed'].includes(pc.connectionState)) {
+        console.log('[host] connection closed');
+        removeListener(id);
+      }
+    };
+    pc.oniceconnectionstatechange = () => {
+      if (['closed', 'failed', 'completed'].includes(pc.iceConnectionState)) {
+        console.log('[host] ice connection', pc.iceConnectionState);
+        removeListener(id);
+      }
+    };
+    listenerConns.set(id, {
+      connection: pc,
+      senders,
+    });
   };
 
-  // make sure host connection stays alive
-  const keepalive = () => {
-    if (!ws) {
-      console.error('[background] ws not alive anymore');
+  ws.onopen = () => {
+    ws.send(JSON.stringify({
+      type: 'hello',
+      version: PROTOCOL_VERSION,
+    }));
+  };
+  ws.onmessage = async ({ data }) => {
+    console.debug('[host] received websocket message', data);
+    const message: ToHostMessage = JSON.parse(data);
+    if (message.type === 'welcome') {
+      console.log('[host] connected with protocol version', message.version);
       return;
     }
-    if (ws.readyState === WebSocket.CLOSED) {
-      console.log('[background] ws closed, stopping keepalive');
+    if (message.type === 'error') {
+      console.error('[host] server rejected message', message.code, message.message);
       return;
     }
-    if (ws.readyState === WebSocket.OPEN) {
-      console.log('[background] sending keepalive');
-      ws.send(JSON.stringify({
-        type: 'KeepAlive',
-      }));
+    if (message.type === 'listener-joined') {
+      // adding tracks starts negotiation with the new listener
+      console.log('[host] new listener', message.id, message.display_name);
+      const added = addListener(message.id);
+      joining.set(message.id, added);
+      await added;
+      joining.delete(message.id);
+      return;
+    }
+    if (message.type === 'resume') {
+      // the room was kept open while we were away, connect to anyone we don't know yet
+      console.log('[host] resumed room with listeners', message.listeners);
+      await Promise.all(message.listeners
+        .filter((id) => !listenerConns.has(id) && !joining.has(id))
+        .map(async (id) => {
+          const added = addListener(id);
+          joining.set(id, added);
+          await added;
+          joining.delete(id);
+        }));
+      return;
+    }
+    if (message.type === 'room-updated') {
+      console.log('[host] room settings changed', message.room);
+      return;
+    }
+    if (message.type === 'chat' || message.type === 'chat-deleted'
+      || message.type === 'chat-scrollback') {
+      // nowhere to show chat in the extension yet
+      console.debug('[host] chat', message);
+      return;
+    }
+    if (message.type === 'room-closed') {
+      // the server closes the websocket right after this
+      console.log('[host] room closed', message.reason);
+      listenerConns.forEach((_, id) => removeListener(id));
+      return;
+    }
+    if (message.type === 'listener-left') {
+      console.log('[host] listener left', message.id);
+      await joining.get(message.id);
+      removeListener(message.id);
+      return;
+    }
+    const { from, signal } = message;
+    // signals can arrive while the connection is still being set up
+    await joining.get(from);
+    const handler = handlers.get(from);
+    if (handler) {
+      console.debug('[host] handling signal', signal);
+      handler(signal);
+    } else {
+      console.error('[host] signal from unknown listener', from);
     }
-    setTimeout(keepalive, settings.WS_KEEPALIVE_MS);
   };
-  keepalive();
 
   return {
     currentRoom,
@@ -181,9 +232,29 @@ const createRoomManager = (
   };
 };
 
+// tells listeners and the room list what's playing
+const sendNowPlaying = (roomManage: RoomManager, { status, track }: StatusUpdateMessage) => {
+  // the server wants a hello first, which goes out as soon as the socket opens
+  if (roomManage.ws.readyState !== WebSocket.OPEN) return;
+  const nowPlaying: NowPlaying = {
+    status,
+    title: track?.title ?? null,
+    artist: track?.artist ?? null,
+    artwork_url: track?.artworkUrl ?? null,
+    duration: track?.duration ?? null,
+    position: track?.position ?? null,
+  };
+  roomManage.ws.send(JSON.stringify({ type: 'now-playing', ...nowPlaying }));
+};
+
 const initialize = (managerParam: MediaStreamManager) => {
   const manager = managerParam;
   createAudioElement(manager.mediaStream);
+  browser.runtime.onMessage.addListener((message: FromContentMessage) => {
+    if (message.description === 'status-update' && manager.roomManage) {
+      sendNowPlaying(manager.roomManage, message);
+    }
+  });
   browser.runtime.onConnect.addListener((port) => {
     console.log('[background] found connection attempt', port);
     if (port.name !== 'popup') {
@@ -11,7 +11,7 @@ import { initLocalPeerConnection } from '../lib/rtcConnection';
 import captureStream from '../lib/polyfill';
 import generateId from '../lib/lib';
 import {
-  AllStatusesMessage, PlayStatus, StatusUpdateMessage, ToContentMessage,
+  AllStatusesMessage, PlayStatus, StatusUpdateMessage, ToContentMessage, TrackInfo,
 } from '../lib/types';
 
 type StreamInfo = {
@@ -20,8 +20,11 @@ type StreamInfo = {
   stream: MediaStream,
   senders: Map<string, RTCRtpSender>,
   vacantSenders: RTCRtpSender[],
+  onPlaybackChange: () => void,
 };
 
+const PLAYBACK_EVENTS = ['play', 'pause', 'seeked', 'loadedmetadata'];
+
 declare global {
   interface Window { RadiowoHasRun?: boolean; }
 }
@@ -90,12 +93,29 @@ declare global {
 
   const getPlayStatus = (el: HTMLMediaElement): PlayStatus => (el.paused ? 'paused' : 'playing');
 
-  const sendStreamUpdate = (streamId: number, status: PlayStatus) => {
+  const finiteOrNull = (n: number) => (Number.isFinite(n) ? n : null);
+
+  const getTrackInfo = (el: HTMLMediaElement): TrackInfo => {
+    // pages that integrate with media keys describe the track, otherwise the tab title will do
+    const metadata = navigator.mediaSession?.metadata;
+    const artwork = metadata?.artwork.find(({ src }) => /^https?:\/\//.test(src));
+    return {
+      title: metadata?.title || document.title || null,
+      artist: metadata?.artist || null,
+      artworkUrl: artwork?.src ?? null,
+      duration: finiteOrNull(el.duration),
+      position: finiteOrNull(el.currentTime),
+    };
+  };
+
+  const sendStreamUpdate = (streamId: number, el: HTMLMediaElement) => {
+    const status = getPlayStatus(el);
     console.log('[content] send update', streamId, status);
     browser.runtime.sendMessage(undefined, {
       description: 'status-update',
       streamId,
       status,
+      track: getTrackInfo(el),
     } as StatusUpdateMessage)
       .catch((e) => console.error(e));
   };
@@ -148,14 +168,18 @@ declare global {
       senders.set(track.id, sender);
     });
     console.log('[content] created pc and added tracks', senders);
+    // keeps the room's now playing up to date
+    const onPlaybackChange = () => sendStreamUpdate(streamId, element);
+    PLAYBACK_EVENTS.forEach((event) => element.addEventListener(event, onPlaybackChange));
     tabStreams.set(streamId, {
       element,
       pc,
       stream,
       senders,
       vacantSenders: [],
+      onPlaybackChange,
     });
-    sendStreamUpdate(streamId, getPlayStatus(element));
+    sendStreamUpdate(streamId, element);
 
     // if the stream modifies its tracks, we should update the peer connection as well
     stream.onremovetrack = ({ track }) => {
@@ -198,7 +222,10 @@ declare global {
     if (!value) {
       return;
     }
-    const { pc, stream } = value;
+    const {
+      element, pc, stream, onPlaybackChange,
+    } = value;
+    PLAYBACK_EVENTS.forEach((event) => element.removeEventListener(event, onPlaybackChange));
     pc.close();
     stream.onremovetrack = null;
     stream.onaddtrack = null;
@@ -1,6 +1,7 @@
 import { Runtime } from 'webextension-polyfill-ts';
 import 'webrtc-adapter';
 import { settings } from './settings';
+import { Signal } from './types';
 
 type TurnCreds = {
   username: string,
@@ -116,15 +117,20 @@ export async function initLocalPeerConnection(portParam: Runtime.Port, polite: b
   return pc;
 }
 
-const wsSendToListener = (ws: WebSocket, clientId: string, msg) => {
+const wsSendToListener = (ws: WebSocket, clientId: string, signal: Signal) => {
   ws.send(JSON.stringify({
-    type: 'ToListener',
+    type: 'signal',
     to: clientId,
-    msg: JSON.stringify(msg),
+    signal,
   }));
 };
 
-export type WSMessageHandler = ({ description, candidate }) => Promise<void>;
+const descriptionSignal = (description: RTCSessionDescription): Signal => {
+  const { type, sdp } = sanitize(description);
+  return { type, sdp };
+};
+
+export type WSMessageHandler = (signal: Signal) => Promise<void>;
 
 export async function initHostPeerConnection(
   handlers: Map<string, WSMessageHandler>,
@@ -145,9 +151,9 @@ export async function initHostPeerConnection(
       // @ts-ignore: missing argument is OK
       await pc.setLocalDescription();
       console.debug('[host] local description', pc.localDescription);
-      wsSendToListener(ws, clientId, {
-        description: sanitize(pc.localDescription),
-      });
+      if (pc.localDescription) {
+        wsSendToListener(ws, clientId, descriptionSignal(pc.localDescription));
+      }
     } catch (err) {
       console.error(err);
     } finally {
@@ -155,16 +161,16 @@ export async function initHostPeerConnection(
     }
   };
   pc.onicecandidate = ({ candidate }) => wsSendToListener(ws, clientId, {
-    candidate: sanitize(candidate),
+    type: 'ice-candidate',
+    candidate: candidate ? sanitize(candidate) : null,
   });
 
   let ignoreOffer = false;
-  handlers.set(clientId, async (obj) => {
-    console.log('[host] obj:', obj);
-    const { candidate, description } = obj;
-    console.debug('[host] received message with', description, candidate);
+  handlers.set(clientId, async (signal) => {
+    console.debug('[host] received signal', signal);
     try {
-      if (description) {
+      if (signal.type === 'offer' || signal.type === 'answer') {
+        const description = { type: signal.type, sdp: signal.sdp };
         const offerCollision = description.type === 'offer'
           && (makingOffer || pc.signalingState !== 'stable');
         ignoreOffer = !polite && offerCollision;
@@ -175,20 +181,27 @@ export async function initHostPeerConnection(
         if (description.type === 'offer') {
           // @ts-ignore: missing argument is OK
           await pc.setLocalDescription();
-          wsSendToListener(ws, clientId, {
-            description: sanitize(pc.localDescription),
-          });
+          if (pc.localDescription) {
+            wsSendToListener(ws, clientId, descriptionSignal(pc.localDescription));
+          }
         }
-      } else if (candidate) {
+      } else if (signal.type === 'ice-candidate' && signal.candidate) {
+        const { candidate } = signal;
         console.debug('[host] candidate', candidate);
         try {
           await pc.addIceCandidate(candidate);
         } catch (err) {
           if (!ignoreOffer) throw err;
         }
+      } else if (signal.type === 'bye') {
+        console.log('[host] listener said bye', clientId, signal.reason);
+        pc.close();
+      } else if (signal.type === 'error') {
+        console.error('[host] listener reported error', clientId, signal.message);
       }
     } catch (err) {
       console.error(err);
+      wsSendToListener(ws, clientId, { type: 'error', message: String(err) });
     }
   });
 
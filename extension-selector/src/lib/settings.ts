@@ -3,7 +3,6 @@ export type Settings = {
   WS_SERVER: string,
   WEB_SERVER: string,
   ICE_SERVER: string | null,
-  WS_KEEPALIVE_MS: number,
   DEBUG: boolean,
 };
 
@@ -16,7 +15,6 @@ switch (process.env.ENV) {
       WS_SERVER: 'wss://radiowo.edwlee.dev/ws',
       WEB_SERVER: 'https://radiowo.edwlee.dev',
       ICE_SERVER: 'turn:radiowo.edwlee.dev:3478',
-      WS_KEEPALIVE_MS: 10000,
       DEBUG: false,
     };
     break;
@@ -27,7 +25,6 @@ switch (process.env.ENV) {
       WS_SERVER: 'ws://localhost:3030',
       WEB_SERVER: 'http://localhost:5000',
       ICE_SERVER: null,
-      WS_KEEPALIVE_MS: 10000,
       DEBUG: true,
     };
     break;
@@ -38,7 +35,6 @@ switch (process.env.ENV) {
       WS_SERVER: 'ws://192.168.1.128:58008/ws',
       WEB_SERVER: 'http://192.168.1.128:58008',
       ICE_SERVER: null,
-      WS_KEEPALIVE_MS: 10000,
       DEBUG: true,
     };
     break;
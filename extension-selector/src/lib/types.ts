@@ -32,10 +32,21 @@ export type ToContentMessage =
 // from content script to popup messages
 // -------------------------------------
 
+// whatever the page tells us about what it's playing
+export type TrackInfo = {
+  title: string | null,
+  artist: string | null,
+  artworkUrl: string | null,
+  // seconds
+  duration: number | null,
+  position: number | null,
+};
+
 export type StatusUpdateMessage = {
   description: 'status-update',
   streamId: number,
   status: PlayStatus,
+  track?: TrackInfo,
 }
 
 export type AllStatusesMessage = {
@@ -80,3 +91,69 @@ export type RoomInfoMessage = {
 };
 
 export type FromBackgroundMessage = RoomInfoMessage | ActionResponseMessage;
+
+// ----------------------------------------
+// signaling server websocket messages
+// ----------------------------------------
+
+export const PROTOCOL_VERSION = 1;
+
+export type Signal =
+  { type: 'offer' | 'answer', sdp: string }
+  | { type: 'ice-candidate', candidate: RTCIceCandidateInit | null }
+  | { type: 'renegotiate' }
+  | { type: 'bye', reason?: string }
+  | { type: 'error', message: string };
+
+export type RoomClosedReason = 'host-left' | 'room-deleted' | 'host-replaced';
+
+export type RoomDetails = {
+  name: string,
+  description: string | null,
+  tags: string[],
+  visibility: 'public' | 'unlisted' | 'private',
+  max_listeners: number | null,
+  require_login: boolean,
+  schedule: {
+    start: string,
+    end: string,
+    recurrence: 'daily' | 'weekly' | null,
+  } | null,
+};
+
+export type ChatMessage = {
+  id: number,
+  author: { role: 'host' }
+    | { role: 'listener', id: string, display_name: string | null, user_id: string | null },
+  text: string,
+  sent_at: string,
+};
+
+export type ServerErrorMessage = {
+  type: 'error',
+  code: string,
+  message: string,
+};
+
+export type NowPlaying = {
+  status: PlayStatus,
+  title: string | null,
+  artist: string | null,
+  artwork_url: string | null,
+  duration: number | null,
+  position: number | null,
+};
+
+export type ToHostMessage =
+  { type: 'welcome', version: number }
+  // user_id is null for anonymous listeners
+  | { type: 'signal', from: string, user_id: string | null, signal: Signal }
+  | { type: 'listener-joined', id: string, display_name: string | null, user_id: string | null }
+  | { type: 'listener-left', id: string }
+  | { type: 'resume', listeners: string[] }
+  | { type: 'room-updated', room: RoomDetails }
+  | { type: 'chat', message: ChatMessage }
+  | { type: 'chat-deleted', id: number }
+  | { type: 'chat-scrollback', messages: ChatMessage[] }
+  | { type: 'room-closed', reason: RoomClosedReason }
+  | ServerErrorMessage;
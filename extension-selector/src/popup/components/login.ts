@@ -19,6 +19,7 @@ const submitHandler = async (setState: SetStateFn) => {
       const state: State = {
         ...CLEAN_STATE,
         authToken: obj.authToken,
+        refreshToken: obj.refreshToken,
         user: obj.user,
       };
       await setStorageState(state);
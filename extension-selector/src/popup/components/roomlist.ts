@@ -1,5 +1,6 @@
 import { createRoom, deleteRoom, getMyRooms } from '../net';
 import { CLEAN_STATE, SetStateFn, State } from '../state';
+import { freshAuthToken } from '../storage';
 import { bgPingPong, hideAllExcept } from './util';
 
 export const renderRoomsList = async (state: State, setState: SetStateFn) => {
@@ -22,13 +23,15 @@ export const renderRoomsList = async (state: State, setState: SetStateFn) => {
     const actionEntry = document.createElement('td');
     const useEntryButton = document.createElement('button');
     useEntryButton.textContent = 'Host';
-    useEntryButton.onclick = () => {
+    useEntryButton.onclick = async () => {
       console.log('[popup] clicked to use room:', info.id);
-      if (state.authToken) {
+      // the host connection only checks the token as it opens
+      const authToken = await freshAuthToken(state, setState);
+      if (authToken) {
         bgPingPong({
           command: 'start-room',
           roomId: info.id,
-          authToken: state.authToken,
+          authToken,
         }, (msg) => {
           if (msg.description === 'action-response') {
             if (msg.success) {
@@ -46,8 +49,9 @@ export const renderRoomsList = async (state: State, setState: SetStateFn) => {
     deleteButton.textContent = 'Delete';
     deleteButton.onclick = async () => {
       console.log('[popup] clicked to delete room:', info.id);
-      if (state.authToken) {
-        const roomsRes = await deleteRoom(state.authToken, info.id);
+      const authToken = await freshAuthToken(state, setState);
+      if (authToken) {
+        const roomsRes = await deleteRoom(authToken, info.id);
         if (roomsRes === 'AuthError') {
           setState(CLEAN_STATE);
           return;
@@ -81,8 +85,9 @@ export const renderRoomsList = async (state: State, setState: SetStateFn) => {
   createButton.textContent = 'Create';
   createButton.onclick = async () => {
     console.log('[popup] clicked to create room:', nameInput.value);
-    if (state.authToken) {
-      const roomsRes = await createRoom(state.authToken, nameInput.value);
+    const authToken = await freshAuthToken(state, setState);
+    if (authToken) {
+      const roomsRes = await createRoom(authToken, nameInput.value);
       if (roomsRes === 'AuthError') {
         setState(CLEAN_STATE);
         return;
@@ -105,7 +110,11 @@ export const attemptGetRooms = async (state: State, setState: SetStateFn) => {
     return;
   }
 
-  const roomsRes = await getMyRooms(state.authToken);
+  const authToken = await freshAuthToken(state, setState);
+  if (!authToken) {
+    return;
+  }
+  const roomsRes = await getMyRooms(authToken);
   if (roomsRes === 'AuthError') {
     setState(CLEAN_STATE);
     console.error('[popup] get rooms auth error');
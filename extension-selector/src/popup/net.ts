@@ -9,10 +9,27 @@ export type UserInfo = {
 export type RoomInfo = {
   id: string,
   name: string,
+  // the user's role, rooms they were made a member of are listed too
+  role?: 'owner' | 'co-host' | 'moderator',
+  listener_count?: number,
+  // who's listening, only for the rooms the user owns
+  listeners?: ConnectedListener[],
 }
 
-export type LoginInfo = {
+export type ConnectedListener = {
+  id: string,
+  display_name?: string,
+  user_id?: string,
+  joined_at: string,
+}
+
+export type SessionTokens = {
+  // short-lived, get a new one with the refresh token
   authToken: string,
+  refreshToken: string,
+};
+
+export type LoginInfo = SessionTokens & {
   user: UserInfo,
 };
 
@@ -44,6 +61,7 @@ export const login = async (email: string, password: string): Promise<LoginInfo
   return response.json().then((loggedInResponse) => {
     const toRet = {
       authToken: loggedInResponse.token,
+      refreshToken: loggedInResponse.refresh_token,
       user: loggedInResponse.result,
     };
     return toRet;
@@ -67,28 +85,65 @@ export const signup = async (email: string, password: string): Promise<LoginInfo
   return response.json().then((loggedInResponse) => {
     const toRet = {
       authToken: loggedInResponse.token,
+      refreshToken: loggedInResponse.refresh_token,
       user: loggedInResponse.result,
     };
     return toRet;
   });
 };
 
-export type GetRoomsResponse = RoomInfo[] | 'AuthError' | string;
-
-export const getMyRooms = async (userToken: string): Promise<GetRoomsResponse> => {
-  const response = await fetch(`${settings.API_SERVER}/my/rooms`, {
+export const refreshSession = async (
+  refreshToken: string,
+): Promise<SessionTokens | 'AuthError' | string> => {
+  const response = await fetch(`${settings.API_SERVER}/my/sessions/refresh`, {
+    method: 'POST',
     headers: {
-      Authorization: `Bearer ${userToken}`,
+      'Content-Type': 'application/json',
     },
+    body: JSON.stringify({
+      refresh_token: refreshToken,
+    }),
   });
   if (!response.ok) {
     if (response.status === 401) {
-      // unauthorized
+      // the session was revoked or ran out
       return 'AuthError';
     }
     return handleError(response);
   }
-  return response.json();
+  return response.json().then((refreshed) => ({
+    authToken: refreshed.token,
+    refreshToken: refreshed.refresh_token,
+  }));
+};
+
+export type GetRoomsResponse = RoomInfo[] | 'AuthError' | string;
+
+// all of them, a page at a time
+export const getMyRooms = async (userToken: string): Promise<GetRoomsResponse> => {
+  const rooms: RoomInfo[] = [];
+  let cursor: string | null = null;
+  do {
+    const query = cursor ? `?cursor=${encodeURIComponent(cursor)}` : '';
+    // eslint-disable-next-line no-await-in-loop
+    const response = await fetch(`${settings.API_SERVER}/my/rooms${query}`, {
+      headers: {
+        Authorization: `Bearer ${userToken}`,
+      },
+    });
+    if (!response.ok) {
+      if (response.status === 401) {
+        // unauthorized
+        return 'AuthError';
+      }
+      return handleError(response);
+    }
+    // eslint-disable-next-line no-await-in-loop
+    const page = await response.json();
+    rooms.push(...page.rooms);
+    cursor = page.next_cursor;
+  } while (cursor);
+  return rooms;
 };
 
 export const createRoom = async (userToken: string, name: string): Promise<RoomInfo | string> => {
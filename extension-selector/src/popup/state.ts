@@ -5,6 +5,7 @@ export type StreamsStore = Map<number, Map<number, PlayStatus>>;
 
 export type State = {
   authToken?: string,
+  refreshToken?: string,
   user?: UserInfo,
   allRooms?: RoomInfo[],
   currentRoom?: string,
@@ -16,6 +17,7 @@ type StateChangeListener = (state: State, setState: SetStateFn) => void;
 
 export const CLEAN_STATE: State = {
   authToken: undefined,
+  refreshToken: undefined,
   user: undefined,
   allRooms: undefined,
   currentRoom: undefined,
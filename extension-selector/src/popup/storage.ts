@@ -1,22 +1,75 @@
 import { browser } from 'webextension-polyfill-ts';
-import { CLEAN_STATE, State, StateManager } from './state';
+import { refreshSession } from './net';
+import {
+  CLEAN_STATE, SetStateFn, State, StateManager,
+} from './state';
 
 const stateKeys = [
   'authToken',
+  'refreshToken',
   'user',
 ];
 
-export const getStorageState = async (manager: StateManager): Promise<void> => {
-  const storageState = await browser.storage.local.get(stateKeys) as State;
-  console.debug('storage state', storageState);
-  manager.setState({ ...CLEAN_STATE, ...storageState });
+// seconds since the epoch, 0 if the token can't be read
+const tokenExpiry = (token: string): number => {
+  try {
+    const payload = token.split('.')[1].replace(/-/g, '+').replace(/_/g, '/');
+    return JSON.parse(atob(payload)).exp;
+  } catch {
+    return 0;
+  }
+};
+
+// a new access token if this one is about to run out, as changes to the state
+const refreshTokens = async (state: State): Promise<State> => {
+  if (!state.authToken || !state.refreshToken) {
+    return {};
+  }
+  if (tokenExpiry(state.authToken) - 60 > Date.now() / 1000) {
+    return {};
+  }
+  const refreshed = await refreshSession(state.refreshToken);
+  if (refreshed === 'AuthError') {
+    return CLEAN_STATE;
+  } if (typeof (refreshed) === 'string') {
+    console.error('[popup] refresh session error:', refreshed);
+    return {};
+  }
+  return refreshed;
 };
 
 export const setStorageState = async (state: State) => {
   const toSet = {
     authToken: state.authToken,
+    refreshToken: state.refreshToken,
     user: state.user,
   };
 
   await browser.storage.local.set(toSet);
 };
+
+export const getStorageState = async (manager: StateManager): Promise<void> => {
+  const storageState = await browser.storage.local.get(stateKeys) as State;
+  console.debug('storage state', storageState);
+  const state = { ...CLEAN_STATE, ...storageState };
+  // the stored access token has likely run out since the popup was last opened
+  const changes = await refreshTokens(state);
+  if (Object.keys(changes).length > 0) {
+    await setStorageState({ ...state, ...changes });
+  }
+  manager.setState({ ...state, ...changes });
+};
+
+// the access token, refreshed first if it's about to run out. null if logged out
+export const freshAuthToken = async (
+  state: State,
+  setState: SetStateFn,
+): Promise<string | null> => {
+  const changes = await refreshTokens(state);
+  const updated = { ...state, ...changes };
+  if (Object.keys(changes).length > 0) {
+    await setStorageState(updated);
+    setState(changes);
+  }
+  return updated.authToken || null;
+};
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE sessions (
+  id                 uuid        NOT NULL,
+  user_id            uuid        NOT NULL,
+  refresh_token_hash BYTEA       NOT NULL,
+  created_at         TIMESTAMPTZ NOT NULL,
+  refreshed_at       TIMESTAMPTZ NOT NULL,
+  expires_at         TIMESTAMPTZ NOT NULL,
+  revoked_at         TIMESTAMPTZ,
+  PRIMARY KEY(id),
+  UNIQUE(refresh_token_hash),
+  CONSTRAINT fk_user
+    FOREIGN KEY(user_id)
+      REFERENCES users(id)
+      ON DELETE CASCADE
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions(user_id);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE rooms ADD COLUMN max_listeners INTEGER CHECK (max_listeners > 0);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rooms
+    DROP COLUMN visibility,
+    DROP COLUMN join_password_hash;
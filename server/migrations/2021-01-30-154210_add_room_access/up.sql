@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE rooms
+    ADD COLUMN visibility VARCHAR NOT NULL DEFAULT 'public'
+        CHECK (visibility IN ('public', 'unlisted', 'private')),
+    ADD COLUMN join_password_hash VARCHAR;
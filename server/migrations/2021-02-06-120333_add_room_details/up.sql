@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE rooms
+    ADD COLUMN description VARCHAR,
+    ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE room_bans (
+  id         uuid        NOT NULL,
+  room_id    uuid        NOT NULL,
+  -- a ban matches on whichever of these are set
+  ip_address VARCHAR,
+  user_id    uuid,
+  created_at TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY(id),
+  CHECK (ip_address IS NOT NULL OR user_id IS NOT NULL),
+  CONSTRAINT fk_room
+    FOREIGN KEY(room_id)
+      REFERENCES rooms(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_user
+    FOREIGN KEY(user_id)
+      REFERENCES users(id)
+      ON DELETE CASCADE
+);
+
+CREATE INDEX room_bans_room_id_idx ON room_bans(room_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rooms
+    DROP COLUMN require_login;
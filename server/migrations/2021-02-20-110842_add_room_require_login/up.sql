@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE rooms
+    ADD COLUMN require_login BOOLEAN NOT NULL DEFAULT false;
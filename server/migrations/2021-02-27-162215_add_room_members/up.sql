@@ -0,0 +1,23 @@
+-- Your SQL goes here
+CREATE TABLE room_members (
+  room_id    uuid        NOT NULL,
+  user_id    uuid        NOT NULL,
+  role       VARCHAR     NOT NULL CHECK (role IN ('owner', 'co-host', 'moderator')),
+  created_at TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY(room_id, user_id),
+  CONSTRAINT fk_room
+    FOREIGN KEY(room_id)
+      REFERENCES rooms(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_user
+    FOREIGN KEY(user_id)
+      REFERENCES users(id)
+      ON DELETE CASCADE
+);
+
+CREATE INDEX room_members_user_id_idx ON room_members(user_id);
+-- the owner is also rooms.user_id, there's only ever the one
+CREATE UNIQUE INDEX room_members_owner_idx ON room_members(room_id) WHERE role = 'owner';
+
+INSERT INTO room_members (room_id, user_id, role, created_at)
+    SELECT id, user_id, 'owner', created_at FROM rooms;
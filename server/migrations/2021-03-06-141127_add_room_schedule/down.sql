@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rooms
+    DROP CONSTRAINT rooms_schedule_check,
+    DROP COLUMN scheduled_start,
+    DROP COLUMN scheduled_end,
+    DROP COLUMN recurrence;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE rooms
+    ADD COLUMN scheduled_start TIMESTAMPTZ,
+    ADD COLUMN scheduled_end TIMESTAMPTZ,
+    ADD COLUMN recurrence VARCHAR CHECK (recurrence IN ('daily', 'weekly')),
+    ADD CONSTRAINT rooms_schedule_check CHECK (
+        (scheduled_start IS NULL AND scheduled_end IS NULL AND recurrence IS NULL)
+        OR (scheduled_start IS NOT NULL AND scheduled_end > scheduled_start)
+    );
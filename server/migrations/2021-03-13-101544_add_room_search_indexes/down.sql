@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX rooms_tags_idx;
+DROP INDEX rooms_room_name_trgm_idx;
+DROP EXTENSION IF EXISTS pg_trgm;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+-- for ILIKE '%...%' name searches
+CREATE INDEX rooms_room_name_trgm_idx ON rooms USING gin (room_name gin_trgm_ops);
+-- for tags @> '{...}'
+CREATE INDEX rooms_tags_idx ON rooms USING gin (tags);
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+-- one per stretch of time a room was live
+CREATE TABLE room_sessions (
+  id             uuid        NOT NULL,
+  room_id        uuid        NOT NULL,
+  started_at     TIMESTAMPTZ NOT NULL,
+  -- NULL while the room is live
+  ended_at       TIMESTAMPTZ,
+  peak_listeners INTEGER     NOT NULL DEFAULT 0,
+  PRIMARY KEY(id),
+  CONSTRAINT fk_room
+    FOREIGN KEY(room_id)
+      REFERENCES rooms(id)
+      ON DELETE CASCADE
+);
+
+CREATE INDEX room_sessions_room_id_idx ON room_sessions(room_id, started_at);
@@ -0,0 +1,15 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users ADD COLUMN pass_hash BYTEA;
+ALTER TABLE users ADD COLUMN salt BYTEA;
+
+UPDATE users SET
+  salt = decode(rpad(split_part(password_hash, '$', 4), 24, '='), 'base64'),
+  pass_hash = decode(split_part(password_hash, '$', 5), 'base64')
+WHERE password_hash LIKE '$bcrypt-raw$c=10$%';
+
+-- anything rehashed since can't be converted back, so those users won't be able to log in
+UPDATE users SET salt = '', pass_hash = '' WHERE pass_hash IS NULL;
+
+ALTER TABLE users ALTER COLUMN pass_hash SET NOT NULL;
+ALTER TABLE users ALTER COLUMN salt SET NOT NULL;
+ALTER TABLE users DROP COLUMN password_hash;
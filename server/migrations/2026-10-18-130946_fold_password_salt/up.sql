@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- hashes become PHC strings. existing ones were made with the raw bcrypt implementation at cost 10,
+-- and are rehashed with the configured hasher the next time each user logs in
+ALTER TABLE users ADD COLUMN password_hash VARCHAR(255);
+
+UPDATE users SET password_hash =
+  '$bcrypt-raw$c=10$'
+  || rtrim(encode(salt, 'base64'), '=')
+  || '$'
+  || rtrim(encode(pass_hash, 'base64'), '=');
+
+ALTER TABLE users ALTER COLUMN password_hash SET NOT NULL;
+ALTER TABLE users DROP COLUMN pass_hash;
+ALTER TABLE users DROP COLUMN salt;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE relay_payloads;
+DROP TABLE room_hosts;
@@ -0,0 +1,25 @@
+-- Your SQL goes here
+-- which server instance each live room's host is connected to, when running several
+CREATE TABLE room_hosts (
+  room_id      uuid        NOT NULL,
+  instance_id  uuid        NOT NULL,
+  -- the host dropped and the room waits for it to come back
+  reconnecting BOOLEAN     NOT NULL DEFAULT FALSE,
+  -- bumped by the instance while it's up, rows that stop being bumped are left from a crash
+  updated_at   TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY(room_id),
+  CONSTRAINT fk_room
+    FOREIGN KEY(room_id)
+      REFERENCES rooms(id)
+      ON DELETE CASCADE
+);
+
+CREATE INDEX room_hosts_instance_id_idx ON room_hosts(instance_id);
+
+-- messages between instances that are too large for a NOTIFY payload
+CREATE TABLE relay_payloads (
+  id         uuid        NOT NULL,
+  payload    TEXT        NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY(id)
+);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE room_hosts
+  DROP COLUMN now_playing,
+  DROP COLUMN listener_count;
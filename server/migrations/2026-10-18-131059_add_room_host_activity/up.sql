@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- how the room is doing, for the room listings of the instances it isn't live on. Refreshed by
+-- its own instance every ping interval
+ALTER TABLE room_hosts
+  ADD COLUMN listener_count INTEGER NOT NULL DEFAULT 0,
+  -- what the host last said it's playing, as JSON
+  ADD COLUMN now_playing    TEXT;
@@ -1,41 +1,53 @@
 use std::convert::TryFrom;
 
-use chrono::{Duration, Utc};
-use futures::future;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use warp::{Filter, Rejection};
 
-use crate::{errors::MyError, settings::JWT_SECRET};
+use crate::{
+    db::PgPool, errors::MyError, handlers::util::db_txn, schema::sessions::dsl::*,
+    settings::settings,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AuthClaims {
-    exp: usize,           // expiration time
-    iat: usize,           // issued at
-    nbf: usize,           // not before
-    radiowo_userid: Uuid, // namespaced uuid
+    exp: usize,              // expiration time
+    iat: usize,              // issued at
+    nbf: usize,              // not before
+    radiowo_userid: Uuid,    // namespaced uuid
+    radiowo_sessionid: Uuid, // session the token was issued for, so it can be revoked
 }
 
-pub fn get_token(user_id: &Uuid) -> Result<String, MyError> {
+#[derive(Debug, Serialize, Deserialize)]
+struct InviteClaims {
+    exp: usize,
+    iat: usize,
+    nbf: usize,
+    radiowo_invite_roomid: Uuid, // room the invite lets listeners into
+}
+
+// (issued at, expires at) as timestamps for the claims
+fn token_times(lifetime: u64) -> Result<(usize, usize, DateTime<Utc>), MyError> {
     let now = Utc::now();
-    // 1 week expiration date for all tokens. it's quite long, but we're stateless and token theft will only last for 1 week (hopefully)
-    let next_week = now
-        .clone()
-        .checked_add_signed(Duration::weeks(1))
+    let lifetime = i64::try_from(lifetime)?;
+    let expires = now
+        .checked_add_signed(Duration::seconds(lifetime))
         .ok_or(MyError::UnexpectedError)?;
-    let now_ts = usize::try_from(now.clone().timestamp())?;
-    let claims = AuthClaims {
-        exp: usize::try_from(next_week.timestamp())?,
-        iat: now_ts,
-        nbf: now_ts,
-        radiowo_userid: user_id.clone(),
-    };
+    Ok((
+        usize::try_from(now.timestamp())?,
+        usize::try_from(expires.timestamp())?,
+        expires,
+    ))
+}
 
+fn sign<T: Serialize>(claims: &T) -> Result<String, MyError> {
     encode(
         &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(JWT_SECRET.as_bytes()),
+        claims,
+        &EncodingKey::from_secret(settings().jwt_secret.as_bytes()),
     )
     .map_err(|e| -> MyError {
         error!("{:#?}", e);
@@ -43,38 +55,126 @@ pub fn get_token(user_id: &Uuid) -> Result<String, MyError> {
     })
 }
 
-pub fn for_authorized() -> impl Filter<Extract = (Uuid,), Error = Rejection> + Clone {
-    warp::header::<String>("authorization").and_then(|token: String| {
-        let mut iter = token.split_ascii_whitespace();
-        match iter.next() {
-            Some(s) if s.to_ascii_lowercase() == "bearer" => (),
-            _ => {
-                return future::err(Rejection::from(MyError::AuthError(
-                    "authorization header missing value".to_owned(),
-                )));
-            }
-        };
-        let token = match iter.next() {
-            None => {
-                return future::err(Rejection::from(MyError::AuthError(
-                    "authorization header not bearer".to_owned(),
-                )));
-            }
-            Some(token) => token,
-        };
-        let token_data = decode::<AuthClaims>(
-            token,
-            &DecodingKey::from_secret(JWT_SECRET.as_bytes()),
-            &Validation::default(),
-        );
-        match token_data {
-            Err(e) => {
-                error!("{:#?}", e);
-                future::err(Rejection::from(MyError::AuthError(
-                    "unable to decode token".to_owned(),
-                )))
+pub fn get_token(for_user_id: &Uuid, session_id: &Uuid) -> Result<String, MyError> {
+    let (now_ts, expires_ts, _) = token_times(settings().access_token_lifetime)?;
+    sign(&AuthClaims {
+        exp: expires_ts,
+        iat: now_ts,
+        nbf: now_ts,
+        radiowo_userid: *for_user_id,
+        radiowo_sessionid: *session_id,
+    })
+}
+
+/// Signs an invite to the room that's good for `lifetime` seconds, returning it and when it
+/// expires.
+pub fn get_invite_token(room_id: &Uuid, lifetime: u64) -> Result<(String, DateTime<Utc>), MyError> {
+    let (now_ts, expires_ts, expires) = token_times(lifetime)?;
+    let token = sign(&InviteClaims {
+        exp: expires_ts,
+        iat: now_ts,
+        nbf: now_ts,
+        radiowo_invite_roomid: *room_id,
+    })?;
+    Ok((token, expires))
+}
+
+pub fn check_invite_token(token: &str, room_id: &Uuid) -> Result<(), MyError> {
+    let token_data = decode::<InviteClaims>(
+        token,
+        &DecodingKey::from_secret(settings().jwt_secret.as_bytes()),
+        &Validation::default(),
+    );
+    match token_data {
+        Ok(data) if data.claims.radiowo_invite_roomid == *room_id => Ok(()),
+        Ok(_) => Err(MyError::AuthError("invite is for another room".to_owned())),
+        Err(e) => {
+            debug!("bad invite: {}", e);
+            Err(MyError::AuthError(
+                "invite is invalid or expired".to_owned(),
+            ))
+        }
+    }
+}
+
+fn decode_token(token: &str) -> Result<AuthClaims, MyError> {
+    let token_data = decode::<AuthClaims>(
+        token,
+        &DecodingKey::from_secret(settings().jwt_secret.as_bytes()),
+        &Validation::default(),
+    );
+    match token_data {
+        Err(e) => {
+            error!("{:#?}", e);
+            Err(MyError::AuthError("unable to decode token".to_owned()))
+        }
+        Ok(data) => Ok(data.claims),
+    }
+}
+
+// a token is only as good as the session it was issued for
+async fn check_session(pool: PgPool, claims: AuthClaims) -> Result<Uuid, Rejection> {
+    let session_id = claims.radiowo_sessionid;
+    let active = db_txn(pool, true, move |db| {
+        let found = sessions
+            .find(session_id)
+            .select(revoked_at)
+            .first::<Option<DateTime<Utc>>>(db)
+            .optional()?;
+        Ok(matches!(found, Some(None)))
+    })
+    .await?;
+
+    if active {
+        Ok(claims.radiowo_userid)
+    } else {
+        Err(Rejection::from(MyError::AuthError(
+            "session has been revoked".to_owned(),
+        )))
+    }
+}
+
+// the claims of the token in an authorization header
+fn decode_bearer(header: &str) -> Result<AuthClaims, MyError> {
+    let mut iter = header.split_ascii_whitespace();
+    match iter.next() {
+        Some(s) if s.eq_ignore_ascii_case("bearer") => (),
+        _ => {
+            return Err(MyError::AuthError(
+                "authorization header missing value".to_owned(),
+            ));
+        }
+    };
+    match iter.next() {
+        None => Err(MyError::AuthError(
+            "authorization header not bearer".to_owned(),
+        )),
+        Some(token) => decode_token(token),
+    }
+}
+
+pub fn for_authorized(pool: &PgPool) -> impl Filter<Extract = (Uuid,), Error = Rejection> + Clone {
+    let pool = pool.clone();
+    warp::header::<String>("authorization")
+        .and_then(|header: String| async move { decode_bearer(&header).map_err(Rejection::from) })
+        .and_then(move |claims| check_session(pool.clone(), claims))
+}
+
+// like for_authorized, but without an authorization header it's None rather than a rejection
+pub fn for_maybe_authorized(
+    pool: &PgPool,
+) -> impl Filter<Extract = (Option<Uuid>,), Error = Rejection> + Clone {
+    let pool = pool.clone();
+    warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+        let pool = pool.clone();
+        async move {
+            match header {
+                None => Ok(None),
+                Some(header) => {
+                    let claims = decode_bearer(&header).map_err(Rejection::from)?;
+                    check_session(pool, claims).await.map(Some)
+                }
             }
-            Ok(data) => future::ok(data.claims.radiowo_userid),
         }
     })
 }
@@ -84,21 +184,37 @@ pub struct WSAuthInfo {
     pub token: String,
 }
 
-pub fn for_authorized_ws() -> impl Filter<Extract = (Uuid,), Error = Rejection> + Clone {
-    warp::query::<WSAuthInfo>().and_then(|info: WSAuthInfo| {
-        let token_data = decode::<AuthClaims>(
-            &info.token,
-            &DecodingKey::from_secret(JWT_SECRET.as_bytes()),
-            &Validation::default(),
-        );
-        match token_data {
-            Err(e) => {
-                error!("{:#?}", e);
-                future::err(Rejection::from(MyError::AuthError(
-                    "unable to decode token".to_owned(),
-                )))
+pub fn for_authorized_ws(
+    pool: &PgPool,
+) -> impl Filter<Extract = (Uuid,), Error = Rejection> + Clone {
+    let pool = pool.clone();
+    warp::query::<WSAuthInfo>()
+        .and_then(
+            |info: WSAuthInfo| async move { decode_token(&info.token).map_err(Rejection::from) },
+        )
+        .and_then(move |claims| check_session(pool.clone(), claims))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaybeWSAuthInfo {
+    pub token: Option<String>,
+}
+
+// like for_authorized_ws, but without a token it's None rather than a rejection
+pub fn for_maybe_authorized_ws(
+    pool: &PgPool,
+) -> impl Filter<Extract = (Option<Uuid>,), Error = Rejection> + Clone {
+    let pool = pool.clone();
+    warp::query::<MaybeWSAuthInfo>().and_then(move |info: MaybeWSAuthInfo| {
+        let pool = pool.clone();
+        async move {
+            match info.token {
+                None => Ok(None),
+                Some(token) => {
+                    let claims = decode_token(&token).map_err(Rejection::from)?;
+                    check_session(pool, claims).await.map(Some)
+                }
             }
-            Ok(data) => future::ok(data.claims.radiowo_userid),
         }
     })
 }
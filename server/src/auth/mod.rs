@@ -1,5 +1,7 @@
 mod jwt;
 mod pass;
+mod session;
 
 pub use jwt::*;
 pub use pass::*;
+pub use session::*;
@@ -1,10 +1,268 @@
+use std::{convert::TryFrom, str::FromStr};
+
+use argon2::{
+    password_hash::{
+        rand_core::OsRng, Output, PasswordHash, PasswordHasher as _, PasswordVerifier as _,
+        SaltString,
+    },
+    Algorithm, Argon2, Params, Version,
+};
+use crypto::{bcrypt::bcrypt, util::fixed_time_eq};
 use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use crate::{errors::MyError, settings::settings};
+
+// fixed by the bcrypt implementation
+const BCRYPT_SALT_LEN: usize = 16;
+const BCRYPT_OUTPUT_LEN: usize = 24;
+// the implementation panics outside of this
+const BCRYPT_PASSWORD_LEN: std::ops::RangeInclusive<usize> = 1..=72;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HasherKind {
+    Argon2id,
+    Bcrypt,
+}
+
+impl FromStr for HasherKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "argon2id" => Ok(HasherKind::Argon2id),
+            "bcrypt" => Ok(HasherKind::Bcrypt),
+            _ => Err(format!("unknown password hasher: {}", s)),
+        }
+    }
+}
+
+/// A password hashing scheme. Hashes are stored as PHC strings
+/// (`$<id>$<params>$<salt>$<hash>`), so the scheme can be picked back out of a stored hash.
+pub trait PasswordHasher {
+    /// The PHC algorithm identifier of hashes made by this hasher.
+    fn id(&self) -> &'static str;
+
+    fn hash(&self, password: &str) -> Result<String, MyError>;
+
+    /// Checks the password against a hash with this hasher's id, in constant time.
+    fn verify(&self, password: &str, hash: &PasswordHash) -> Result<bool, MyError>;
+
+    /// Whether the hash was made with different parameters than this hasher would use now.
+    fn needs_rehash(&self, hash: &PasswordHash) -> bool;
+}
+
+pub struct Argon2idHasher {
+    params: Params,
+}
+
+impl Argon2idHasher {
+    pub fn new(memory_cost: u32, time_cost: u32, parallelism: u32) -> Result<Self, MyError> {
+        let params = Params::new(memory_cost, time_cost, parallelism, None).map_err(|e| {
+            error!("invalid argon2 params: {}", e);
+            MyError::UnexpectedError
+        })?;
+        Ok(Self { params })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
 
-use crate::settings::MAX_SALT_LEN;
+impl PasswordHasher for Argon2idHasher {
+    fn id(&self) -> &'static str {
+        "argon2id"
+    }
+
+    fn hash(&self, password: &str) -> Result<String, MyError> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| {
+                error!("{:#?}", e);
+                MyError::UnexpectedError
+            })
+    }
+
+    fn verify(&self, password: &str, hash: &PasswordHash) -> Result<bool, MyError> {
+        // the params are read from the hash itself, so old hashes still verify
+        match Argon2::default().verify_password(password.as_bytes(), hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => {
+                error!("{:#?}", e);
+                Err(MyError::UnexpectedError)
+            }
+        }
+    }
+
+    fn needs_rehash(&self, hash: &PasswordHash) -> bool {
+        match Params::try_from(hash) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+/// The original scheme: raw bcrypt (no NUL terminator, no `$2b$` encoding) over the password
+/// bytes, so it isn't compatible with other bcrypt implementations.
+pub struct BcryptHasher {
+    cost: u32,
+}
+
+impl BcryptHasher {
+    pub fn new(cost: u32) -> Self {
+        Self { cost }
+    }
+
+    fn cost_of(hash: &PasswordHash) -> Option<u32> {
+        hash.params.get_decimal("c")
+    }
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn id(&self) -> &'static str {
+        "bcrypt-raw"
+    }
+
+    fn hash(&self, password: &str) -> Result<String, MyError> {
+        if !BCRYPT_PASSWORD_LEN.contains(&password.len()) {
+            return Err(MyError::BadRequest(format!(
+                "password must be between {} and {} bytes",
+                BCRYPT_PASSWORD_LEN.start(),
+                BCRYPT_PASSWORD_LEN.end()
+            )));
+        }
+        let mut salt = [0u8; BCRYPT_SALT_LEN];
+        thread_rng().fill_bytes(&mut salt);
+        let mut output = [0u8; BCRYPT_OUTPUT_LEN];
+        bcrypt(self.cost, &salt, password.as_bytes(), &mut output);
+
+        let encode_error = |e| -> MyError {
+            error!("{:#?}", e);
+            MyError::UnexpectedError
+        };
+        let salt = SaltString::encode_b64(&salt).map_err(encode_error)?;
+        let output = Output::new(&output).map_err(encode_error)?;
+        Ok(format!(
+            "${}$c={}${}${}",
+            self.id(),
+            self.cost,
+            salt.as_str(),
+            output
+        ))
+    }
+
+    fn verify(&self, password: &str, hash: &PasswordHash) -> Result<bool, MyError> {
+        let malformed = || {
+            error!("malformed {} hash", self.id());
+            MyError::UnexpectedError
+        };
+        let cost = Self::cost_of(hash)
+            .filter(|cost| (4..=31).contains(cost))
+            .ok_or_else(malformed)?;
+        let mut salt = [0u8; BCRYPT_SALT_LEN];
+        let salt = hash
+            .salt
+            .ok_or_else(malformed)?
+            .decode_b64(&mut salt)
+            .map_err(|_| malformed())?;
+        let expected = hash.hash.ok_or_else(malformed)?;
+        if salt.len() != BCRYPT_SALT_LEN || expected.len() != BCRYPT_OUTPUT_LEN {
+            return Err(malformed());
+        }
+        // it couldn't have been hashed
+        if !BCRYPT_PASSWORD_LEN.contains(&password.len()) {
+            return Ok(false);
+        }
+
+        let mut output = [0u8; BCRYPT_OUTPUT_LEN];
+        bcrypt(cost, salt, password.as_bytes(), &mut output);
+        Ok(fixed_time_eq(&output, expected.as_bytes()))
+    }
+
+    fn needs_rehash(&self, hash: &PasswordHash) -> bool {
+        Self::cost_of(hash) != Some(self.cost)
+    }
+}
+
+/// The hasher new passwords are hashed with, as configured.
+pub fn current_hasher() -> Result<Box<dyn PasswordHasher>, MyError> {
+    hasher_of_kind(settings().password_hasher)
+}
+
+fn hasher_of_kind(kind: HasherKind) -> Result<Box<dyn PasswordHasher>, MyError> {
+    let settings = settings();
+    Ok(match kind {
+        HasherKind::Argon2id => Box::new(Argon2idHasher::new(
+            settings.argon2_memory_cost,
+            settings.argon2_time_cost,
+            settings.argon2_parallelism,
+        )?),
+        HasherKind::Bcrypt => Box::new(BcryptHasher::new(settings.bcrypt_cost)),
+    })
+}
+
+fn hasher_for_id(id: &str) -> Result<Option<Box<dyn PasswordHasher>>, MyError> {
+    for kind in &[HasherKind::Argon2id, HasherKind::Bcrypt] {
+        let hasher = hasher_of_kind(*kind)?;
+        if hasher.id() == id {
+            return Ok(Some(hasher));
+        }
+    }
+    Ok(None)
+}
+
+pub enum Verified {
+    /// The password matched. `rehashed` is set if the stored hash is outdated and should be
+    /// replaced.
+    Valid {
+        rehashed: Option<String>,
+    },
+    Invalid,
+}
+
+pub fn hash_password(password: &str) -> Result<String, MyError> {
+    task::block_in_place(|| current_hasher()?.hash(password))
+}
+
+/// Checks the password against a stored PHC string.
+pub fn verify_password(password: &str, stored: &str) -> Result<Verified, MyError> {
+    task::block_in_place(|| {
+        let hash = PasswordHash::new(stored).map_err(|e| {
+            error!("unable to parse stored password hash: {}", e);
+            MyError::UnexpectedError
+        })?;
+        let hasher = hasher_for_id(hash.algorithm.as_str())?.ok_or_else(|| {
+            error!("no hasher for stored password hash: {}", hash.algorithm);
+            MyError::UnexpectedError
+        })?;
+        if !hasher.verify(password, &hash)? {
+            return Ok(Verified::Invalid);
+        }
+
+        let current = current_hasher()?;
+        let rehashed = if current.id() != hasher.id() || current.needs_rehash(&hash) {
+            Some(current.hash(password)?)
+        } else {
+            None
+        };
+        Ok(Verified::Valid { rehashed })
+    })
+}
 
-pub fn gen_salt() -> [u8; MAX_SALT_LEN] {
-    let mut unencoded = [0u8; MAX_SALT_LEN];
-    let mut rng = thread_rng();
-    rng.fill_bytes(&mut unencoded);
-    unencoded
+/// Does about as much work as a real verification, so that a missing user can't be told apart
+/// from a wrong password by timing.
+pub fn dummy_verify(password: &str) {
+    if let Err(e) = hash_password(password) {
+        error!("{:#?}", e);
+    }
 }
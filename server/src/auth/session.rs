@@ -0,0 +1,21 @@
+use crypto::{digest::Digest, sha2::Sha256};
+use rand::{thread_rng, RngCore};
+
+const REFRESH_TOKEN_LEN: usize = 32;
+
+/// Opaque refresh token handed to the client. Only its hash is stored.
+pub fn gen_refresh_token() -> String {
+    let mut unencoded = [0u8; REFRESH_TOKEN_LEN];
+    let mut rng = thread_rng();
+    rng.fill_bytes(&mut unencoded);
+    base64::encode_config(unencoded, base64::URL_SAFE_NO_PAD)
+}
+
+// refresh tokens are random, so a fast unsalted hash is enough to make a leaked table useless
+pub fn hash_refresh_token(token: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    let mut output = vec![0u8; hasher.output_bytes()];
+    hasher.result(&mut output);
+    output
+}
@@ -1,9 +1,14 @@
+use std::io::Write;
+
 use chrono::{DateTime, Utc};
 use diesel::{
-    pg::PgConnection,
+    deserialize::{self, FromSql},
+    pg::{Pg, PgConnection},
     r2d2::{ConnectionManager, Pool, PooledConnection},
+    serialize::{self, IsNull, Output, ToSql},
+    sql_types::Text,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::schema::*;
@@ -17,8 +22,7 @@ pub struct User {
     pub display_name: String,
     pub email: String,
     pub created_at: DateTime<Utc>,
-    pub pass_hash: Vec<u8>,
-    pub salt: Vec<u8>,
+    pub password_hash: String,
 }
 
 #[derive(Debug, Serialize, Queryable)]
@@ -40,6 +44,82 @@ impl From<User> for UserQueryResult {
     }
 }
 
+/// Who can find and join a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Text"]
+pub enum Visibility {
+    /// Listed, anyone can join
+    Public,
+    /// Not listed, anyone with the room's ID can join
+    Unlisted,
+    /// Not listed, joining takes an invite
+    Private,
+}
+
+impl Visibility {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for Visibility {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for Visibility {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"public" => Ok(Visibility::Public),
+            b"unlisted" => Ok(Visibility::Unlisted),
+            b"private" => Ok(Visibility::Private),
+            _ => Err("unrecognized room visibility".into()),
+        }
+    }
+}
+
+/// How often a scheduled room's broadcast repeats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Text"]
+pub enum Recurrence {
+    Daily,
+    Weekly,
+}
+
+impl Recurrence {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Recurrence::Daily => "daily",
+            Recurrence::Weekly => "weekly",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for Recurrence {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for Recurrence {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"daily" => Ok(Recurrence::Daily),
+            b"weekly" => Ok(Recurrence::Weekly),
+            _ => Err("unrecognized room recurrence".into()),
+        }
+    }
+}
+
 #[derive(Debug, Identifiable, Associations, Queryable, Insertable, Serialize)]
 #[belongs_to(User)]
 pub struct Room {
@@ -48,6 +128,180 @@ pub struct Room {
     pub room_name: String,
     pub created_at: DateTime<Utc>,
     pub last_connected: Option<DateTime<Utc>>,
+    // no limit if None
+    pub max_listeners: Option<i32>,
+    pub visibility: Visibility,
+    // listeners need the password unless they have an invite
+    #[serde(skip_serializing)]
+    pub join_password_hash: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    // anonymous listeners aren't let in
+    pub require_login: bool,
+    // all None for rooms without a schedule, the end is set whenever the start is
+    pub scheduled_start: Option<DateTime<Utc>>,
+    pub scheduled_end: Option<DateTime<Utc>>,
+    // the first broadcast is the one at scheduled_start
+    pub recurrence: Option<Recurrence>,
+}
+
+// fields that are None are left alone
+#[derive(Debug, Default, AsChangeset)]
+#[table_name = "rooms"]
+pub struct RoomChanges {
+    pub room_name: Option<String>,
+    pub description: Option<Option<String>>,
+    pub tags: Option<Vec<String>>,
+    pub max_listeners: Option<Option<i32>>,
+    pub visibility: Option<Visibility>,
+    pub join_password_hash: Option<Option<String>>,
+    pub require_login: Option<bool>,
+    pub scheduled_start: Option<Option<DateTime<Utc>>>,
+    pub scheduled_end: Option<Option<DateTime<Utc>>>,
+    pub recurrence: Option<Option<Recurrence>>,
+}
+
+impl RoomChanges {
+    // diesel refuses to run an update without changes
+    pub fn is_empty(&self) -> bool {
+        self.room_name.is_none()
+            && self.description.is_none()
+            && self.tags.is_none()
+            && self.max_listeners.is_none()
+            && self.visibility.is_none()
+            && self.join_password_hash.is_none()
+            && self.require_login.is_none()
+            && self.scheduled_start.is_none()
+            && self.scheduled_end.is_none()
+            && self.recurrence.is_none()
+    }
+}
+
+/// What a member of a room gets to do there, see `Role::can`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "kebab-case")]
+#[sql_type = "Text"]
+pub enum Role {
+    /// Also rooms.user_id, every room has exactly one
+    Owner,
+    CoHost,
+    Moderator,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Permission {
+    /// Change the room's settings, delete it and manage its members
+    Manage,
+    /// Host the room, taking over from whoever is hosting it, and hand out invites
+    Host,
+    /// Kick, ban and mute listeners and delete chat messages
+    Moderate,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::CoHost => "co-host",
+            Role::Moderator => "moderator",
+        }
+    }
+
+    pub fn can(self, permission: Permission) -> bool {
+        matches!(
+            (self, permission),
+            (Role::Owner, _)
+                | (Role::CoHost, Permission::Host)
+                | (Role::CoHost, Permission::Moderate)
+                | (Role::Moderator, Permission::Moderate)
+        )
+    }
+}
+
+impl ToSql<Text, Pg> for Role {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for Role {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"owner" => Ok(Role::Owner),
+            b"co-host" => Ok(Role::CoHost),
+            b"moderator" => Ok(Role::Moderator),
+            _ => Err("unrecognized room role".into()),
+        }
+    }
+}
+
+#[derive(Debug, Identifiable, Associations, Queryable, Insertable)]
+#[belongs_to(Room)]
+#[primary_key(room_id, user_id)]
+pub struct RoomMember {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Identifiable, Associations, Queryable, Insertable, Serialize)]
+#[belongs_to(Room)]
+pub struct RoomBan {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub ip_address: Option<String>,
+    pub user_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A stretch of time a room was live, from its host connecting until the room closed.
+#[derive(Debug, Identifiable, Associations, Queryable, Insertable, Serialize)]
+#[belongs_to(Room)]
+pub struct RoomSession {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub started_at: DateTime<Utc>,
+    // None while the room is live
+    pub ended_at: Option<DateTime<Utc>>,
+    // the most listeners in the room at once
+    pub peak_listeners: i32,
+}
+
+/// The server instance a live room's host is connected to.
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "room_hosts"]
+pub struct HostedRoom {
+    pub room_id: Uuid,
+    pub instance_id: Uuid,
+    // the host dropped and the room waits for it to come back
+    pub reconnecting: bool,
+    pub updated_at: DateTime<Utc>,
+    // admitted listeners, as of the last refresh
+    pub listener_count: i32,
+    // what the host last said it's playing, as JSON
+    pub now_playing: Option<String>,
+}
+
+#[derive(Debug, Identifiable, Associations, Queryable, Insertable)]
+#[belongs_to(User)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub refresh_token_hash: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+    pub refreshed_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Queryable)]
+pub struct SessionQueryResult {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub refreshed_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
 }
 
 pub fn pg_pool(db_url: String) -> PgPool {
@@ -14,6 +14,7 @@ pub enum MyError {
     DBConnectionError,
     WSConnectionAlreadyExists,
     AuthError(String),
+    BadRequest(String),
     DBError(diesel::result::Error),
 }
 
@@ -64,6 +65,10 @@ pub async fn handle_error(err: Rejection) -> Result<impl Reply, Rejection> {
                 code = StatusCode::FORBIDDEN;
                 message = msg.clone();
             }
+            MyError::BadRequest(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                message = msg.clone();
+            }
             MyError::WSConnectionAlreadyExists => {
                 code = StatusCode::CONFLICT;
                 message = "Attempted to connect when connection already exists.".to_owned();
@@ -74,6 +79,10 @@ pub async fn handle_error(err: Rejection) -> Result<impl Reply, Rejection> {
                 message = "Database is likely busy. Try again later.".to_owned();
             }
             MyError::DBError(db_err) => match db_err {
+                diesel::result::Error::NotFound => {
+                    code = StatusCode::NOT_FOUND;
+                    message = "Resource not found".to_owned();
+                }
                 diesel::result::Error::InvalidCString(_) => {
                     code = StatusCode::BAD_REQUEST;
                     message =
@@ -109,7 +118,7 @@ pub async fn handle_error(err: Rejection) -> Result<impl Reply, Rejection> {
 
     let json = warp::reply::json(&ErrorMessage {
         code: code.as_u16(),
-        message: message.into(),
+        message,
     });
     Ok(warp::reply::with_status(json, code))
 }
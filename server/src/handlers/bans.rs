@@ -0,0 +1,125 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use chrono::Utc;
+use diesel::{delete, dsl::exists, insert_into, prelude::*, select};
+use uuid::Uuid;
+use warp::{hyper::StatusCode, reject, reply::json};
+
+use crate::schema::room_bans;
+use crate::{
+    db::{Permission, PgPool, RoomBan},
+    errors::MyError,
+};
+
+use super::{members::check_permission, util::db_txn};
+
+/// What a ban on this address matches: the address itself for IPv4, the whole /64 for IPv6
+/// since that's what a single subscriber usually gets.
+pub fn ban_fingerprint(ip: IpAddr) -> String {
+    let ip = match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        ip => ip,
+    };
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => {
+            let mut segments = v6.segments();
+            for segment in segments.iter_mut().skip(4) {
+                *segment = 0;
+            }
+            format!("{}/64", Ipv6Addr::from(segments))
+        }
+    }
+}
+
+// true if a ban matches either the address or the user
+pub async fn is_banned(
+    pool: PgPool,
+    room_id: Uuid,
+    fingerprint: Option<String>,
+    user: Option<Uuid>,
+) -> Result<bool, MyError> {
+    if fingerprint.is_none() && user.is_none() {
+        return Ok(false);
+    }
+    db_txn(pool, true, move |db| {
+        // NULL equals nothing, so whichever of the two is missing matches no ban
+        let banned = select(exists(
+            room_bans::table
+                .filter(room_bans::room_id.eq(room_id))
+                .filter(
+                    room_bans::ip_address
+                        .eq(fingerprint)
+                        .or(room_bans::user_id.eq(user)),
+                ),
+        ))
+        .get_result(db)?;
+        Ok(banned)
+    })
+    .await
+}
+
+pub async fn save_ban(
+    pool: PgPool,
+    room_id: Uuid,
+    fingerprint: Option<String>,
+    user: Option<Uuid>,
+) -> Result<RoomBan, MyError> {
+    db_txn(pool, false, move |db| {
+        let ban = RoomBan {
+            id: Uuid::new_v4(),
+            room_id,
+            ip_address: fingerprint,
+            user_id: user,
+            created_at: Utc::now(),
+        };
+        insert_into(room_bans::table).values(&ban).execute(db)?;
+        Ok(ban)
+    })
+    .await
+}
+
+// only bans the host made persistent, the others end with the room
+pub async fn list_bans(
+    room_id: Uuid,
+    pool: PgPool,
+    req_user_id: Uuid,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let bans = db_txn(pool, true, move |db| {
+        check_permission(db, room_id, req_user_id, Permission::Moderate, "list bans")?;
+        let bans: Vec<RoomBan> = room_bans::table
+            .filter(room_bans::room_id.eq(room_id))
+            .order(room_bans::created_at.desc())
+            .load(db)?;
+        Ok(bans)
+    })
+    .await?;
+    Ok(json(&bans))
+}
+
+pub async fn delete_ban(
+    room_id: Uuid,
+    ban_id: Uuid,
+    pool: PgPool,
+    req_user_id: Uuid,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    db_txn(pool, false, move |db| {
+        check_permission(db, room_id, req_user_id, Permission::Moderate, "lift ban")?;
+        let deleted = delete(
+            room_bans::table
+                .filter(room_bans::id.eq(ban_id))
+                .filter(room_bans::room_id.eq(room_id)),
+        )
+        .execute(db)?;
+        if deleted == 0 {
+            return Err(MyError::DBError(diesel::result::Error::NotFound));
+        }
+        Ok(())
+    })
+    .await
+    .map_err(reject::custom)?;
+    Ok(StatusCode::NO_CONTENT)
+}
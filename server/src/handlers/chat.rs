@@ -0,0 +1,85 @@
+use std::collections::{HashSet, VecDeque};
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::settings::settings;
+
+use super::{
+    live_room::ListenerIdentity,
+    protocol::{ChatAuthor, ChatMessage},
+};
+
+/// A live room's chat: the last few messages, for whoever joins next, and who's muted.
+#[derive(Default)]
+pub struct ChatRoom {
+    next_id: u64,
+    // oldest first, at most chat_scrollback long
+    scrollback: VecDeque<ChatMessage>,
+    // muted until the room closes, even if they reconnect
+    muted: HashSet<MuteKey>,
+}
+
+/// What a mute goes by: the account if there is one, otherwise where the listener connects from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MuteKey {
+    User(Uuid),
+    Address(String),
+    // only the connection, for listeners with nothing else to go by
+    Listener(Uuid),
+}
+
+impl MuteKey {
+    pub fn of(listener: Uuid, identity: &ListenerIdentity) -> Self {
+        match (identity.user_id, &identity.fingerprint) {
+            (Some(user), _) => MuteKey::User(user),
+            (None, Some(fingerprint)) => MuteKey::Address(fingerprint.clone()),
+            (None, None) => MuteKey::Listener(listener),
+        }
+    }
+}
+
+impl ChatRoom {
+    pub fn post(&mut self, author: ChatAuthor, text: String) -> ChatMessage {
+        self.next_id += 1;
+        let message = ChatMessage {
+            id: self.next_id,
+            author,
+            text,
+            sent_at: Utc::now(),
+        };
+        self.scrollback.push_back(message.clone());
+        while self.scrollback.len() > settings().chat_scrollback {
+            self.scrollback.pop_front();
+        }
+        message
+    }
+
+    // false if the message isn't in the scrollback
+    pub fn delete(&mut self, id: u64) -> bool {
+        let before = self.scrollback.len();
+        self.scrollback.retain(|message| message.id != id);
+        self.scrollback.len() != before
+    }
+
+    pub fn scrollback(&self) -> Vec<ChatMessage> {
+        self.scrollback.iter().cloned().collect()
+    }
+
+    pub fn set_muted(&mut self, key: MuteKey, muted: bool) {
+        if muted {
+            self.muted.insert(key);
+        } else {
+            self.muted.remove(&key);
+        }
+    }
+
+    pub fn is_muted(&self, key: &MuteKey) -> bool {
+        self.muted.contains(key)
+    }
+
+    // a mute that only went by the connection is no use once it's gone
+    pub fn forget(&mut self, listener: &Uuid) {
+        self.muted.remove(&MuteKey::Listener(*listener));
+    }
+}
@@ -0,0 +1,45 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::errors::MyError;
+
+/// Where a page of a room listing ended, so the next one starts right after it. Which of these a
+/// listing uses depends on how it's sorted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "after", rename_all = "snake_case")]
+pub enum RoomCursor {
+    Created {
+        created_at: DateTime<Utc>,
+        id: Uuid,
+    },
+    Connected {
+        last_connected: Option<DateTime<Utc>>,
+        created_at: DateTime<Utc>,
+        id: Uuid,
+    },
+    // among the rooms with a host, by how many listeners they had then
+    Listened {
+        listeners: usize,
+        id: Uuid,
+    },
+    // among scheduled rooms, by when their next broadcast started then
+    Upcoming {
+        start: DateTime<Utc>,
+        id: Uuid,
+    },
+}
+
+impl RoomCursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursors serialize");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(cursor: &str) -> Result<RoomCursor, MyError> {
+        base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| MyError::BadRequest("invalid cursor".to_owned()))
+    }
+}
@@ -0,0 +1,173 @@
+use std::{collections::HashSet, convert::Infallible};
+
+use futures::{future, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::{self, RecvError},
+    mpsc,
+};
+use uuid::Uuid;
+use warp::sse;
+
+use crate::{db::Visibility, errors::MyError};
+
+use super::protocol::{NowPlaying, RoomDetails};
+
+/// What changed about a room, as far as the room directory goes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DirectoryChange {
+    RoomCreated { room: RoomDetails },
+    RoomDeleted,
+    // not sent when the host comes back within the reconnect grace period
+    HostOnline,
+    // once the room closes, so not while the host is reconnecting
+    HostOffline,
+    // admitted listeners, not counting those waiting in line
+    ListenerCountChanged { listener_count: usize },
+    NowPlaying { now_playing: NowPlaying },
+}
+
+impl DirectoryChange {
+    fn name(&self) -> &'static str {
+        match self {
+            DirectoryChange::RoomCreated { .. } => "room-created",
+            DirectoryChange::RoomDeleted => "room-deleted",
+            DirectoryChange::HostOnline => "host-online",
+            DirectoryChange::HostOffline => "host-offline",
+            DirectoryChange::ListenerCountChanged { .. } => "listener-count-changed",
+            DirectoryChange::NowPlaying { .. } => "now-playing",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirectoryEvent {
+    pub room_id: Uuid,
+    pub owner_id: Uuid,
+    // unlisted rooms only go to those who asked for them by ID
+    #[serde(skip)]
+    pub visibility: Visibility,
+    #[serde(flatten)]
+    pub change: DirectoryChange,
+}
+
+// subscribers that fall further behind than this miss events
+const DIRECTORY_EVENTS: usize = 64;
+
+/// This instance's room directory changes, to pass on to the other instances.
+pub type DirectoryOutbox = mpsc::UnboundedReceiver<DirectoryEvent>;
+
+/// Where room directory changes go out to everyone on GET /rooms/events, whichever instance
+/// they're connected to.
+#[derive(Clone)]
+pub struct RoomEvents {
+    subscribers: broadcast::Sender<DirectoryEvent>,
+    outbox: mpsc::UnboundedSender<DirectoryEvent>,
+}
+
+impl RoomEvents {
+    pub fn new() -> (Self, DirectoryOutbox) {
+        let (subscribers, _) = broadcast::channel(DIRECTORY_EVENTS);
+        let (outbox, outgoing) = mpsc::unbounded_channel();
+        let events = Self {
+            subscribers,
+            outbox,
+        };
+        (events, outgoing)
+    }
+
+    pub fn send(
+        &self,
+        room_id: Uuid,
+        owner_id: Uuid,
+        visibility: Visibility,
+        change: DirectoryChange,
+    ) {
+        // private rooms aren't anyone's business
+        if visibility == Visibility::Private {
+            return;
+        }
+        let event = DirectoryEvent {
+            room_id,
+            owner_id,
+            visibility,
+            change,
+        };
+        // fails once nothing passes them on anymore, the subscribers here still hear about it
+        let _ = self.outbox.send(event.clone());
+        self.publish(event);
+    }
+
+    /// Tells only the subscribers on this instance, e.g. about a change another one relayed.
+    pub fn publish(&self, event: DirectoryEvent) {
+        // fails when nobody is subscribed, which is fine
+        let _ = self.subscribers.send(event);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventsOptions {
+    // the owner's user ID
+    pub owner: Option<Uuid>,
+    // comma separated room IDs
+    pub rooms: Option<String>,
+}
+
+struct EventsFilter {
+    owner: Option<Uuid>,
+    rooms: Option<HashSet<Uuid>>,
+}
+
+impl EventsFilter {
+    fn matches(&self, event: &DirectoryEvent) -> bool {
+        let by_room = match &self.rooms {
+            None => event.visibility == Visibility::Public,
+            Some(rooms) => rooms.contains(&event.room_id),
+        };
+        by_room && self.owner.is_none_or(|owner| owner == event.owner_id)
+    }
+}
+
+pub async fn stream_room_events(
+    opts: EventsOptions,
+    room_events: RoomEvents,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let rooms = match opts.rooms {
+        None => None,
+        Some(rooms) => Some(
+            rooms
+                .split(',')
+                .map(|room| room.trim().parse::<Uuid>())
+                .collect::<Result<HashSet<Uuid>, _>>()
+                .map_err(|_| {
+                    warp::reject::custom(MyError::BadRequest(
+                        "rooms has to be comma separated room IDs".to_owned(),
+                    ))
+                })?,
+        ),
+    };
+    let filter = EventsFilter {
+        owner: opts.owner,
+        rooms,
+    };
+    let events = room_events
+        .subscribers
+        .subscribe()
+        .into_stream()
+        .filter_map(move |event| {
+            let event = match event {
+                Ok(event) if filter.matches(&event) => Some(event),
+                Ok(_) => None,
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("room events subscriber missed {} events", skipped);
+                    None
+                }
+                Err(RecvError::Closed) => None,
+            };
+            future::ready(event.map(|event| {
+                Ok::<_, Infallible>((sse::event(event.change.name()), sse::json(event)))
+            }))
+        });
+    Ok(sse::reply(sse::keep_alive().stream(events)))
+}
@@ -0,0 +1,58 @@
+use std::{str::FromStr, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// What happens to a listener's message when the host's send queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackpressurePolicy {
+    /// Drop the message and tell the listener.
+    Drop,
+    /// Disconnect the listener.
+    Disconnect,
+}
+
+impl FromStr for BackpressurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(BackpressurePolicy::Drop),
+            "disconnect" => Ok(BackpressurePolicy::Disconnect),
+            _ => Err(format!("unknown backpressure policy: {}", s)),
+        }
+    }
+}
+
+/// Allows `burst` messages at once, refilled at `per_sec` messages a second.
+pub struct TokenBucket {
+    per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        Self {
+            per_sec: f64::from(per_sec),
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Whether there's room for another message, using it up if so.
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
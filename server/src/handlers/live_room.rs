@@ -0,0 +1,1272 @@
+use std::{
+    cmp::max,
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    mem,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use futures::future;
+use serde::Serialize;
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, watch,
+    },
+    task, time,
+};
+use uuid::Uuid;
+use warp::ws::Message;
+
+use crate::{
+    db::{PgPool, RoomSession, Visibility},
+    schema::room_sessions,
+    settings::settings,
+};
+
+use super::{
+    chat::{ChatRoom, MuteKey},
+    directory::{DirectoryChange, RoomEvents},
+    limits::BackpressurePolicy,
+    metrics::{metrics, Metrics},
+    protocol::{
+        ChatAuthor, ChatMessage, CloseCode, ErrorCode, NowPlaying, RoomClosedReason, RoomDetails,
+        Signal, ToHostMessage, ToListenerMessage,
+    },
+    room_conns::{Cluster, Conns, LiveRooms},
+    util::db_txn,
+};
+
+pub type WSSender = mpsc::Sender<Result<Message, warp::Error>>;
+
+pub async fn send_json<T: Serialize>(sender: &mut WSSender, msg: &T) -> Result<(), ()> {
+    let json = serde_json::to_string(msg).map_err(|e| {
+        error!("unable to serialize message: {}", e);
+    })?;
+    sender.send(Ok(Message::text(json))).await.map_err(|e| {
+        error!("unable to send message, likely disconnected {}", e);
+    })
+}
+
+/// What became of a relayed message.
+pub enum Relayed {
+    Sent,
+    // the receiver's queue is full
+    Full,
+    Gone,
+}
+
+// relays don't wait for room in the receiver's queue, so a slow peer can't hold up the sender
+pub fn try_send_json<T: Serialize>(sender: &mut WSSender, msg: &T) -> Relayed {
+    let json = match serde_json::to_string(msg) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("unable to serialize message: {}", e);
+            return Relayed::Gone;
+        }
+    };
+    match sender.try_send(Ok(Message::text(json))) {
+        Ok(()) => Relayed::Sent,
+        Err(TrySendError::Full(_)) => Relayed::Full,
+        Err(TrySendError::Closed(_)) => Relayed::Gone,
+    }
+}
+
+pub async fn send_close(sender: &mut WSSender, code: CloseCode) {
+    let close = Message::close_with(code.code(), code.reason());
+    if sender.send(Ok(close)).await.is_err() {
+        debug!("connection already gone, not closing it");
+    }
+}
+
+// closes the connection once there's room in its queue, without holding up the room
+fn close_later(mut sender: WSSender, code: CloseCode) {
+    task::spawn(async move {
+        send_close(&mut sender, code).await;
+    });
+}
+
+/// Who's behind a listener connection, as far as bans go.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerIdentity {
+    // where it connects from, see ban_fingerprint. None if the address is unknown
+    pub fingerprint: Option<String>,
+    // None for anonymous listeners
+    pub user_id: Option<Uuid>,
+}
+
+impl ListenerIdentity {
+    pub fn is_bannable(&self) -> bool {
+        self.fingerprint.is_some() || self.user_id.is_some()
+    }
+
+    // true if a ban on other covers this listener too
+    fn matches(&self, other: &ListenerIdentity) -> bool {
+        let same_address = self.fingerprint.is_some() && self.fingerprint == other.fingerprint;
+        let same_user = self.user_id.is_some() && self.user_id == other.user_id;
+        same_address || same_user
+    }
+}
+
+// a listener waiting for a spot in a full room
+struct WaitingListener {
+    id: Uuid,
+    display_name: Option<String>,
+    user_id: Option<Uuid>,
+    sender: WSSender,
+}
+
+/// A listener in a room, as its members see it in their room listings.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectedListener {
+    pub id: Uuid,
+    pub display_name: Option<String>,
+    // None for anonymous listeners
+    pub user_id: Option<Uuid>,
+    // when it was let in, after any wait in line
+    pub joined_at: DateTime<Utc>,
+}
+
+/// How a room's stretch of being live went, for room_sessions.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionStats {
+    pub session_id: Uuid,
+    pub peak_listeners: usize,
+}
+
+pub fn peak_of(stats: &SessionStats) -> i32 {
+    i32::try_from(stats.peak_listeners).unwrap_or(i32::MAX)
+}
+
+// everyone listening to a room, or waiting to
+struct RoomListeners {
+    // the room_sessions row of this stretch of being live
+    session_id: Uuid,
+    // inserting the row, which ending the session waits for
+    session_started: Option<task::JoinHandle<()>>,
+    // the most listeners admitted at once
+    peak_listeners: usize,
+    // the listener count GET /rooms/events last heard of
+    announced_listeners: usize,
+    // no limit if None
+    max_listeners: Option<usize>,
+    // Random connection UUID -> Sender to listener
+    admitted: HashMap<Uuid, WSSender>,
+    // in order of arrival
+    waiting: VecDeque<WaitingListener>,
+    chat: ChatRoom,
+    // listener UUID -> who it is
+    identities: HashMap<Uuid, ListenerIdentity>,
+    // banned until the room closes, persistent bans are in the database
+    banned_fingerprints: HashSet<String>,
+    banned_users: HashSet<Uuid>,
+    // admitted listener UUID -> who it is and since when
+    presence: HashMap<Uuid, ConnectedListener>,
+}
+
+// a listener taken out of the room, or out of the queue
+struct RemovedListener {
+    id: Uuid,
+    sender: WSSender,
+    was_admitted: bool,
+}
+
+impl RoomListeners {
+    fn new(capacity: Option<usize>) -> Self {
+        Self {
+            session_id: Uuid::new_v4(),
+            session_started: None,
+            peak_listeners: 0,
+            announced_listeners: 0,
+            max_listeners: capacity,
+            admitted: HashMap::new(),
+            waiting: VecDeque::new(),
+            chat: ChatRoom::default(),
+            identities: HashMap::new(),
+            banned_fingerprints: HashSet::new(),
+            banned_users: HashSet::new(),
+            presence: HashMap::new(),
+        }
+    }
+
+    fn stats(&self) -> SessionStats {
+        SessionStats {
+            session_id: self.session_id,
+            peak_listeners: self.peak_listeners,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_listeners
+            .is_some_and(|max| self.admitted.len() >= max)
+    }
+
+    // everyone admitted or in line
+    fn connections(&self) -> Vec<(Uuid, WSSender)> {
+        self.admitted
+            .iter()
+            .map(|(id, sender)| (*id, sender.clone()))
+            .chain(
+                self.waiting
+                    .iter()
+                    .map(|waiting| (waiting.id, waiting.sender.clone())),
+            )
+            .collect()
+    }
+
+    // moves listeners from the queue into the room while there's space
+    fn admit_waiting(&mut self) -> Vec<WaitingListener> {
+        let mut admitted = vec![];
+        while !self.is_full() {
+            match self.waiting.pop_front() {
+                None => break,
+                Some(waiting) => {
+                    self.admit(
+                        waiting.id,
+                        waiting.display_name.clone(),
+                        waiting.user_id,
+                        waiting.sender.clone(),
+                    );
+                    admitted.push(waiting);
+                }
+            }
+        }
+        admitted
+    }
+
+    fn admit(
+        &mut self,
+        listener: Uuid,
+        display_name: Option<String>,
+        account: Option<Uuid>,
+        sender: WSSender,
+    ) {
+        self.admitted.insert(listener, sender);
+        let presence = ConnectedListener {
+            id: listener,
+            display_name,
+            user_id: account,
+            joined_at: Utc::now(),
+        };
+        self.presence.insert(listener, presence);
+        self.peak_listeners = max(self.peak_listeners, self.admitted.len());
+    }
+
+    // None if the listener isn't in the room or the queue
+    fn remove(&mut self, listener: Uuid) -> Option<RemovedListener> {
+        self.chat.forget(&listener);
+        self.identities.remove(&listener);
+        self.presence.remove(&listener);
+        if let Some(sender) = self.admitted.remove(&listener) {
+            return Some(RemovedListener {
+                id: listener,
+                sender,
+                was_admitted: true,
+            });
+        }
+        let i = self
+            .waiting
+            .iter()
+            .position(|waiting| waiting.id == listener)?;
+        self.waiting.remove(i).map(|waiting| RemovedListener {
+            id: waiting.id,
+            sender: waiting.sender,
+            was_admitted: false,
+        })
+    }
+
+    // every connection a ban on the identity covers
+    fn connected_as(&self, identity: &ListenerIdentity) -> Vec<Uuid> {
+        self.identities
+            .iter()
+            .filter(|(_, other)| other.matches(identity))
+            .map(|(listener, _)| *listener)
+            .collect()
+    }
+
+    fn mute_key(&self, listener: Uuid) -> MuteKey {
+        let identity = self.identities.get(&listener).cloned().unwrap_or_default();
+        MuteKey::of(listener, &identity)
+    }
+
+    fn is_banned(&self, identity: &ListenerIdentity) -> bool {
+        let by_address = identity
+            .fingerprint
+            .as_ref()
+            .is_some_and(|fingerprint| self.banned_fingerprints.contains(fingerprint));
+        let by_user = identity
+            .user_id
+            .as_ref()
+            .is_some_and(|user| self.banned_users.contains(user));
+        by_address || by_user
+    }
+
+    fn ban(&mut self, identity: &ListenerIdentity) {
+        if let Some(fingerprint) = &identity.fingerprint {
+            self.banned_fingerprints.insert(fingerprint.clone());
+        }
+        if let Some(user) = identity.user_id {
+            self.banned_users.insert(user);
+        }
+    }
+
+    fn queue_positions(&self) -> Vec<(Uuid, WSSender, usize)> {
+        self.waiting
+            .iter()
+            .enumerate()
+            .map(|(i, waiting)| (waiting.id, waiting.sender.clone(), i + 1))
+            .collect()
+    }
+}
+
+/// Where a listener ends up after saying hello.
+pub enum Placement {
+    // with the chat scrollback as of then, and whether the listener was muted before reconnecting
+    Admitted {
+        scrollback: Vec<ChatMessage>,
+        muted: bool,
+    },
+    // 1-based position in line
+    Queued(usize),
+    Full,
+    NoHost,
+    Banned,
+}
+
+/// Why a moderation command didn't go through.
+pub enum ModerationError {
+    // for whoever sent the command
+    Rejected(ErrorCode, String),
+    // the room isn't live anymore
+    RoomClosed,
+}
+
+fn unknown_listener(listener: Uuid) -> ModerationError {
+    ModerationError::Rejected(
+        ErrorCode::UnknownListener,
+        format!("listener not found: {}", listener),
+    )
+}
+
+/// A host connection taking charge of a room.
+#[derive(Clone)]
+pub struct HostArrival {
+    pub conn_id: Uuid,
+    pub sender: WSSender,
+    pub owner_id: Uuid,
+    pub visibility: Visibility,
+    // no limit if None
+    pub capacity: Option<usize>,
+}
+
+// a room's host, or the spot it left while it reconnects
+struct Host {
+    // tells this connection apart from the host's earlier and later ones
+    conn_id: Uuid,
+    // None while the host is reconnecting
+    sender: Option<WSSender>,
+    // None until the host says
+    now_playing: Option<NowPlaying>,
+    // for GET /rooms/events, kept up to date by RoomCommand::Update
+    owner_id: Uuid,
+    visibility: Visibility,
+}
+
+/// How a live room is doing as of the last command its task handled, for whoever only needs a
+/// look rather than an answer.
+#[derive(Debug, Clone)]
+pub struct RoomStatus {
+    // the connection in charge, also while it's reconnecting
+    pub host_conn: Uuid,
+    pub reconnecting: bool,
+    pub now_playing: Option<NowPlaying>,
+    // admitted listeners, not counting those waiting in line
+    pub listener_count: usize,
+    pub session: SessionStats,
+}
+
+fn status_of(host: &Host, listeners: &RoomListeners) -> RoomStatus {
+    RoomStatus {
+        host_conn: host.conn_id,
+        reconnecting: host.sender.is_none(),
+        now_playing: host.now_playing.clone(),
+        listener_count: listeners.admitted.len(),
+        session: listeners.stats(),
+    }
+}
+
+/// What a live room's task can be asked to do. If the room closes first, the command is dropped
+/// along with its reply.
+pub enum RoomCommand {
+    // a host connection took over the room, replies true if it's the host back from a reconnect
+    HostConnected {
+        host: HostArrival,
+        reply: oneshot::Sender<bool>,
+    },
+    HostDisconnected {
+        conn_id: Uuid,
+    },
+    // the host said hello, so it's told what it missed. Replies Err if it couldn't be
+    HostReady {
+        conn_id: Uuid,
+        resumed: bool,
+        reply: oneshot::Sender<Result<(), ()>>,
+    },
+    NowPlaying {
+        conn_id: Uuid,
+        now_playing: NowPlaying,
+    },
+    // puts a listener in the room, or in line for it
+    Place {
+        id: Uuid,
+        display_name: Option<String>,
+        identity: ListenerIdentity,
+        sender: WSSender,
+        reply: oneshot::Sender<Placement>,
+    },
+    // the listener was told it's in, so the host hears about it
+    Joined {
+        id: Uuid,
+    },
+    Left {
+        id: Uuid,
+    },
+    // replies Err if the author isn't allowed to chat
+    Chat {
+        author: ChatAuthor,
+        text: String,
+        reply: oneshot::Sender<Result<(), (ErrorCode, &'static str)>>,
+    },
+    // replies None if the listener isn't let in
+    SignalHost {
+        from: Uuid,
+        signal: Signal,
+        reply: oneshot::Sender<Option<Relayed>>,
+    },
+    SignalListener {
+        to: Uuid,
+        signal: Signal,
+        reply: oneshot::Sender<Relayed>,
+    },
+    // who's behind a listener in the room or in line for it
+    Identify {
+        listener: Uuid,
+        reply: oneshot::Sender<Option<ListenerIdentity>>,
+    },
+    IsBanned {
+        identity: ListenerIdentity,
+        reply: oneshot::Sender<bool>,
+    },
+    DeleteChat {
+        message_id: u64,
+        reply: oneshot::Sender<Result<(), ModerationError>>,
+    },
+    Mute {
+        listener: Uuid,
+        muted: bool,
+        reply: oneshot::Sender<Result<(), ModerationError>>,
+    },
+    Kick {
+        listener: Uuid,
+        reply: oneshot::Sender<Result<(), ModerationError>>,
+    },
+    // replies with who's banned now. Listeners with nothing to ban them by are only kicked
+    Ban {
+        listener: Uuid,
+        reply: oneshot::Sender<Result<ListenerIdentity, ModerationError>>,
+    },
+    Update {
+        visibility: Visibility,
+        capacity: Option<usize>,
+        details: RoomDetails,
+    },
+    Presence {
+        reply: oneshot::Sender<Vec<ConnectedListener>>,
+    },
+    Close {
+        reason: RoomClosedReason,
+        reply: oneshot::Sender<()>,
+    },
+}
+
+/// A live room, as everyone but its own task gets to see it: commands go in one at a time, the
+/// room's connections are only ever touched by the task.
+#[derive(Clone)]
+pub struct LiveRoom {
+    commands: mpsc::Sender<RoomCommand>,
+    status: watch::Receiver<RoomStatus>,
+}
+
+impl LiveRoom {
+    pub fn status(&self) -> RoomStatus {
+        self.status.borrow().clone()
+    }
+
+    pub async fn tell(&self, command: RoomCommand) {
+        let mut commands = self.commands.clone();
+        if commands.send(command).await.is_err() {
+            debug!("room closed before it got to the command");
+        }
+    }
+
+    // None if the room closed before answering
+    pub async fn ask<T, F>(&self, command: F) -> Option<T>
+    where
+        F: FnOnce(oneshot::Sender<T>) -> RoomCommand,
+    {
+        let (reply, answer) = oneshot::channel();
+        self.tell(command(reply)).await;
+        answer.await.ok()
+    }
+
+    // resolves once the room's task is done with it and it's out of the live rooms
+    async fn closed(&self) {
+        let mut status = self.status.clone();
+        while status.recv().await.is_some() {}
+    }
+}
+
+/// Puts the host connection in charge of the room, which goes live unless it already is. Also
+/// says whether the host came back from a reconnect, the listeners stay then.
+pub async fn take_room(
+    pool: &PgPool,
+    conns: &Conns,
+    room_id: Uuid,
+    host: HostArrival,
+) -> (LiveRoom, bool) {
+    let live_rooms = &conns.live_rooms;
+    loop {
+        let live = {
+            let mut live = live_rooms.write().await;
+            match live.get(&room_id) {
+                Some(room) => room.clone(),
+                None => {
+                    let room = RoomTask::start(room_id, pool.clone(), conns.clone(), host);
+                    live.insert(room_id, room.clone());
+                    return (room, false);
+                }
+            }
+        };
+        let host = host.clone();
+        let resumed = live
+            .ask(|reply| RoomCommand::HostConnected { host, reply })
+            .await;
+        if let Some(resumed) = resumed {
+            return (live, resumed);
+        }
+        // it closed in the meantime, so it goes live again once it's out of the way
+        live.closed().await;
+    }
+}
+
+// records the listeners' session in the background, the room doesn't wait for the database
+fn start_session(pool: &PgPool, room_id: Uuid, listeners: &mut RoomListeners) {
+    let session = RoomSession {
+        id: listeners.session_id,
+        room_id,
+        started_at: Utc::now(),
+        ended_at: None,
+        peak_listeners: 0,
+    };
+    let pool = pool.clone();
+    let started = task::spawn(async move {
+        let result = db_txn(pool, false, move |db| {
+            diesel::insert_into(room_sessions::table)
+                .values(&session)
+                .execute(db)?;
+            Ok(())
+        })
+        .await;
+        if let Err(e) = result {
+            error!("unable to record room session: {:#?}", e);
+        }
+    });
+    listeners.session_started = Some(started);
+}
+
+// also in the background, once the session is recorded as started
+fn end_session(pool: &PgPool, listeners: &mut RoomListeners) {
+    let started = listeners.session_started.take();
+    let stats = listeners.stats();
+    let pool = pool.clone();
+    task::spawn(async move {
+        if let Some(started) = started {
+            let _ = started.await;
+        }
+        let result = db_txn(pool, false, move |db| {
+            diesel::update(room_sessions::table.find(stats.session_id))
+                .set((
+                    room_sessions::ended_at.eq(Utc::now()),
+                    room_sessions::peak_listeners.eq(peak_of(&stats)),
+                ))
+                .execute(db)?;
+            Ok(())
+        })
+        .await;
+        if let Err(e) = result {
+            error!("unable to end room session: {:#?}", e);
+        }
+    });
+}
+
+fn close_listeners(listeners: &RoomListeners, reason: RoomClosedReason) {
+    for (_, mut listener) in listeners.connections() {
+        // the close code says why too, for a listener too far behind to take the message
+        let _ = try_send_json(&mut listener, &ToListenerMessage::RoomClosed { reason });
+        close_later(listener, reason.close_code());
+    }
+}
+
+// resolves once a reconnecting host ran out of time, never while the host is connected
+async fn reconnect_expired(deadline: Option<time::Instant>) {
+    match deadline {
+        None => future::pending().await,
+        Some(deadline) => time::delay_until(deadline).await,
+    }
+}
+
+// owns a live room's connections, from its host connecting until the room closes
+struct RoomTask {
+    room_id: Uuid,
+    pool: PgPool,
+    // to take the room out once it closes
+    live_rooms: LiveRooms,
+    room_events: RoomEvents,
+    cluster: Cluster,
+    // the last registry write, the next one goes out after it
+    registry_updated: Option<task::JoinHandle<()>>,
+    host: Host,
+    listeners: RoomListeners,
+    status: watch::Sender<RoomStatus>,
+    // Some while the host is reconnecting
+    host_back_by: Option<time::Instant>,
+    // listeners whose queue was full, sent off once the command is handled
+    lagging: HashSet<Uuid>,
+    // the host's queue was full, so it's let go once the command is handled. It's told what it
+    // missed when it reconnects
+    host_lagging: bool,
+}
+
+impl RoomTask {
+    fn start(room_id: Uuid, pool: PgPool, conns: Conns, arrival: HostArrival) -> LiveRoom {
+        let Conns {
+            live_rooms,
+            room_events,
+            cluster,
+            ..
+        } = conns;
+        let host = Host {
+            conn_id: arrival.conn_id,
+            sender: Some(arrival.sender),
+            now_playing: None,
+            owner_id: arrival.owner_id,
+            visibility: arrival.visibility,
+        };
+        let listeners = RoomListeners::new(arrival.capacity);
+        let (status, status_receiver) = watch::channel(status_of(&host, &listeners));
+        let (commands, command_receiver) = mpsc::channel(settings().buf_size);
+        let room = RoomTask {
+            room_id,
+            pool,
+            live_rooms,
+            room_events,
+            cluster,
+            registry_updated: None,
+            host,
+            listeners,
+            status,
+            host_back_by: None,
+            lagging: HashSet::new(),
+            host_lagging: false,
+        };
+        task::spawn(room.run(command_receiver));
+        LiveRoom {
+            commands,
+            status: status_receiver,
+        }
+    }
+
+    async fn run(mut self, mut commands: mpsc::Receiver<RoomCommand>) {
+        start_session(&self.pool, self.room_id, &mut self.listeners);
+        self.set_room_host(false);
+        self.announce(DirectoryChange::HostOnline);
+        loop {
+            let command = tokio::select! {
+                command = commands.recv() => match command {
+                    None => break,
+                    Some(command) => command,
+                },
+                _ = reconnect_expired(self.host_back_by) => {
+                    debug!("host didn't reconnect in time (room={})", self.room_id);
+                    self.host_gone();
+                    break;
+                }
+            };
+            if !self.handle(command) || !self.drop_lagging() {
+                break;
+            }
+            let _ = self
+                .status
+                .broadcast(status_of(&self.host, &self.listeners));
+        }
+        debug!("room closed: {}", self.room_id);
+        self.let_go();
+    }
+
+    // once the registry writes before are through, forgets the room's host unless it moved to
+    // another instance, and takes the room out so a host connecting from now on starts it over
+    fn let_go(self) {
+        let RoomTask {
+            room_id,
+            live_rooms,
+            cluster,
+            registry_updated,
+            status,
+            ..
+        } = self;
+        task::spawn(async move {
+            if let Some(updated) = registry_updated {
+                let _ = updated.await;
+            }
+            cluster.clear_room_host(room_id).await;
+            live_rooms.write().await.remove(&room_id);
+            // lets take_room know it's gone
+            drop(status);
+        });
+    }
+
+    // in the background, after the registry write before it
+    fn set_room_host(&mut self, reconnecting: bool) {
+        let previous = self.registry_updated.take();
+        let cluster = self.cluster.clone();
+        let room_id = self.room_id;
+        self.registry_updated = Some(task::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            cluster.set_room_host(room_id, reconnecting).await;
+        }));
+    }
+
+    // false once the room closed
+    fn handle(&mut self, command: RoomCommand) -> bool {
+        match command {
+            RoomCommand::HostConnected { host, reply } => {
+                let resumed = self.host_connected(host);
+                let _ = reply.send(resumed);
+            }
+            RoomCommand::HostDisconnected { conn_id } => {
+                return self.host_disconnected(conn_id);
+            }
+            RoomCommand::HostReady {
+                conn_id,
+                resumed,
+                reply,
+            } => {
+                let _ = reply.send(self.host_ready(conn_id, resumed));
+            }
+            RoomCommand::NowPlaying {
+                conn_id,
+                now_playing,
+            } => {
+                if conn_id == self.host.conn_id {
+                    self.host.now_playing = Some(now_playing.clone());
+                    let change = DirectoryChange::NowPlaying {
+                        now_playing: now_playing.clone(),
+                    };
+                    self.announce(change);
+                    self.notify_listeners(&ToListenerMessage::NowPlaying(now_playing));
+                }
+            }
+            RoomCommand::Place {
+                id,
+                display_name,
+                identity,
+                sender,
+                reply,
+            } => {
+                let _ = reply.send(self.place(id, display_name, identity, sender));
+            }
+            RoomCommand::Joined { id } => {
+                let joined = self.listeners.presence.get(&id).map(|listener| {
+                    ToHostMessage::ListenerJoined {
+                        id,
+                        display_name: listener.display_name.clone(),
+                        user_id: listener.user_id,
+                    }
+                });
+                // not if it's been kicked out already
+                if let Some(joined) = joined {
+                    let _ = self.notify_host(&joined);
+                }
+                self.announce_listener_count();
+            }
+            RoomCommand::Left { id } => {
+                // already gone if the host kicked it
+                let removed = self.listeners.remove(id);
+                debug!("number of listeners: {}", self.listeners.admitted.len());
+                if removed.is_some_and(|removed| removed.was_admitted) {
+                    let _ = self.notify_host(&ToHostMessage::ListenerLeft { id });
+                }
+                // either a spot opened up or the queue moved
+                self.admit_waiting();
+                // also where listeners the host kicked out get counted
+                self.announce_listener_count();
+            }
+            RoomCommand::Chat {
+                author,
+                text,
+                reply,
+            } => {
+                let _ = reply.send(self.chat(author, text));
+            }
+            RoomCommand::SignalHost {
+                from,
+                signal,
+                reply,
+            } => {
+                let relayed = match self.listeners.admitted.contains_key(&from) {
+                    false => None,
+                    true => {
+                        let to_send = ToHostMessage::Signal {
+                            from,
+                            user_id: self
+                                .listeners
+                                .identities
+                                .get(&from)
+                                .and_then(|identity| identity.user_id),
+                            signal,
+                        };
+                        Some(match &mut self.host.sender {
+                            None => Relayed::Gone,
+                            Some(host) => try_send_json(host, &to_send),
+                        })
+                    }
+                };
+                let _ = reply.send(relayed);
+            }
+            RoomCommand::SignalListener { to, signal, reply } => {
+                let relayed = match self.listeners.admitted.get_mut(&to) {
+                    None => Relayed::Gone,
+                    Some(listener) => {
+                        try_send_json(listener, &ToListenerMessage::Signal { signal })
+                    }
+                };
+                let _ = reply.send(relayed);
+            }
+            RoomCommand::Identify { listener, reply } => {
+                let _ = reply.send(self.listeners.identities.get(&listener).cloned());
+            }
+            RoomCommand::IsBanned { identity, reply } => {
+                let _ = reply.send(self.listeners.is_banned(&identity));
+            }
+            RoomCommand::DeleteChat { message_id, reply } => {
+                let _ = reply.send(self.delete_chat(message_id));
+            }
+            RoomCommand::Mute {
+                listener,
+                muted,
+                reply,
+            } => {
+                let _ = reply.send(self.set_muted(listener, muted));
+            }
+            RoomCommand::Kick { listener, reply } => {
+                let _ = reply.send(self.kick(listener));
+            }
+            RoomCommand::Ban { listener, reply } => {
+                let _ = reply.send(self.ban(listener));
+            }
+            RoomCommand::Update {
+                visibility,
+                capacity,
+                details,
+            } => {
+                self.host.visibility = visibility;
+                self.listeners.max_listeners = capacity;
+                let _ = self.notify_host(&ToHostMessage::RoomUpdated {
+                    room: details.clone(),
+                });
+                self.notify_listeners(&ToListenerMessage::RoomUpdated { room: details });
+                self.admit_waiting();
+                self.announce_listener_count();
+            }
+            RoomCommand::Presence { reply } => {
+                let mut connected: Vec<ConnectedListener> =
+                    self.listeners.presence.values().cloned().collect();
+                connected.sort_by_key(|listener| listener.joined_at);
+                let _ = reply.send(connected);
+            }
+            RoomCommand::Close { reason, reply } => {
+                self.close(reason);
+                let _ = reply.send(());
+                return false;
+            }
+        }
+        true
+    }
+
+    // true if the host is back from a reconnect
+    fn host_connected(&mut self, arrival: HostArrival) -> bool {
+        let resumed = self.host.sender.is_none();
+        if let Some(mut old_sender) = self.host.sender.take() {
+            // taken over, the listeners were connected to the old host so they start over too
+            debug!("replacing host connection (room={})", self.room_id);
+            let reason = RoomClosedReason::HostReplaced;
+            let _ = try_send_json(&mut old_sender, &ToHostMessage::RoomClosed { reason });
+            close_later(old_sender, reason.close_code());
+            let mut old = mem::replace(&mut self.listeners, RoomListeners::new(arrival.capacity));
+            close_listeners(&old, reason);
+            end_session(&self.pool, &mut old);
+            self.announce(DirectoryChange::ListenerCountChanged { listener_count: 0 });
+            start_session(&self.pool, self.room_id, &mut self.listeners);
+            // a new host will say what's playing for itself
+            self.host.now_playing = None;
+        } else {
+            self.listeners.max_listeners = arrival.capacity;
+        }
+        self.host.conn_id = arrival.conn_id;
+        self.host.sender = Some(arrival.sender);
+        self.host.owner_id = arrival.owner_id;
+        self.host.visibility = arrival.visibility;
+        self.host_back_by = None;
+        self.set_room_host(false);
+        resumed
+    }
+
+    // keeps the room around for a while in case the host comes back, false if it closed
+    fn host_disconnected(&mut self, conn_id: Uuid) -> bool {
+        if conn_id != self.host.conn_id || self.host.sender.is_none() {
+            // taken over by another connection, or already let go for falling behind
+            return true;
+        }
+        self.host.sender = None;
+        let grace = settings().host_reconnect_grace;
+        if grace == 0 {
+            self.host_gone();
+            return false;
+        }
+        let reconnecting = ToListenerMessage::HostReconnecting {
+            grace_seconds: grace,
+        };
+        self.notify_listeners(&reconnecting);
+        self.set_room_host(true);
+        self.host_back_by = Some(time::Instant::now() + Duration::from_secs(grace));
+        true
+    }
+
+    fn host_gone(&mut self) {
+        self.close(RoomClosedReason::HostLeft);
+        self.announce(DirectoryChange::HostOffline);
+    }
+
+    fn host_ready(&mut self, conn_id: Uuid, resumed: bool) -> Result<(), ()> {
+        if self.host.sender.is_none() || conn_id != self.host.conn_id {
+            return Err(());
+        }
+        if resumed {
+            let listeners = self.listeners.admitted.keys().copied().collect();
+            self.notify_host(&ToHostMessage::Resume { listeners })?;
+            self.notify_listeners(&ToListenerMessage::HostResumed);
+        }
+        let messages = self.listeners.chat.scrollback();
+        self.notify_host(&ToHostMessage::ChatScrollback { messages })
+    }
+
+    // ends every connection to the room, telling everyone still connected why
+    fn close(&mut self, reason: RoomClosedReason) {
+        close_listeners(&self.listeners, reason);
+        end_session(&self.pool, &mut self.listeners);
+        // the host is already gone when it's the one that left
+        if let Some(mut host) = self.host.sender.take() {
+            let _ = try_send_json(&mut host, &ToHostMessage::RoomClosed { reason });
+            close_later(host, reason.close_code());
+        }
+    }
+
+    fn place(
+        &mut self,
+        id: Uuid,
+        display_name: Option<String>,
+        identity: ListenerIdentity,
+        sender: WSSender,
+    ) -> Placement {
+        let listeners = &mut self.listeners;
+        if listeners.is_banned(&identity) {
+            // banned since it connected
+            return Placement::Banned;
+        }
+        let placement = if !listeners.is_full() {
+            listeners.admit(id, display_name, identity.user_id, sender);
+            Placement::Admitted {
+                scrollback: listeners.chat.scrollback(),
+                muted: listeners.chat.is_muted(&MuteKey::of(id, &identity)),
+            }
+        } else if listeners.waiting.len() < settings().listener_queue_len {
+            listeners.waiting.push_back(WaitingListener {
+                id,
+                display_name,
+                user_id: identity.user_id,
+                sender,
+            });
+            Placement::Queued(listeners.waiting.len())
+        } else {
+            return Placement::Full;
+        };
+        listeners.identities.insert(id, identity);
+        placement
+    }
+
+    fn chat(&mut self, author: ChatAuthor, text: String) -> Result<(), (ErrorCode, &'static str)> {
+        if let ChatAuthor::Listener { id, .. } = &author {
+            if !self.listeners.admitted.contains_key(id) {
+                return Err((
+                    ErrorCode::NotAdmitted,
+                    "still waiting for a spot in the room",
+                ));
+            }
+            if self.listeners.chat.is_muted(&self.listeners.mute_key(*id)) {
+                return Err((ErrorCode::Muted, "the host muted you"));
+            }
+        }
+        let message = self.listeners.chat.post(author, text);
+        self.broadcast_chat(
+            &ToHostMessage::Chat {
+                message: message.clone(),
+            },
+            &ToListenerMessage::Chat { message },
+        );
+        Ok(())
+    }
+
+    fn delete_chat(&mut self, message_id: u64) -> Result<(), ModerationError> {
+        if !self.listeners.chat.delete(message_id) {
+            return Err(ModerationError::Rejected(
+                ErrorCode::UnknownChatMessage,
+                format!("chat message not found: {}", message_id),
+            ));
+        }
+        self.broadcast_chat(
+            &ToHostMessage::ChatDeleted { id: message_id },
+            &ToListenerMessage::ChatDeleted { id: message_id },
+        );
+        Ok(())
+    }
+
+    fn set_muted(&mut self, listener: Uuid, muted: bool) -> Result<(), ModerationError> {
+        if !self.listeners.admitted.contains_key(&listener) {
+            return Err(unknown_listener(listener));
+        }
+        let key = self.listeners.mute_key(listener);
+        self.listeners.chat.set_muted(key.clone(), muted);
+        // the listener's other connections are muted along with this one
+        let connections: Vec<(Uuid, WSSender)> = self
+            .listeners
+            .admitted
+            .iter()
+            .filter(|(id, _)| self.listeners.mute_key(**id) == key)
+            .map(|(id, sender)| (*id, sender.clone()))
+            .collect();
+        for (id, sender) in connections {
+            self.notify_listener(id, sender, &ToListenerMessage::ChatMuted { muted });
+        }
+        Ok(())
+    }
+
+    // the listener can be in the room or still in line
+    fn kick(&mut self, listener: Uuid) -> Result<(), ModerationError> {
+        let removed = self
+            .listeners
+            .remove(listener)
+            .ok_or_else(|| unknown_listener(listener))?;
+        let kicked = ToListenerMessage::Kicked { banned: false };
+        self.send_off(vec![removed], Some(&kicked), CloseCode::Kicked);
+        Ok(())
+    }
+
+    // like kick, along with every other connection of whoever it is
+    fn ban(&mut self, listener: Uuid) -> Result<ListenerIdentity, ModerationError> {
+        let identity = self
+            .listeners
+            .identities
+            .get(&listener)
+            .cloned()
+            .ok_or_else(|| unknown_listener(listener))?;
+        if !identity.is_bannable() {
+            warn!("nothing to ban listener {} by, kicking it", listener);
+            self.kick(listener)?;
+            return Ok(identity);
+        }
+        self.listeners.ban(&identity);
+        let removed = self
+            .listeners
+            .connected_as(&identity)
+            .into_iter()
+            .filter_map(|listener_id| self.listeners.remove(listener_id))
+            .collect();
+        let kicked = ToListenerMessage::Kicked { banned: true };
+        self.send_off(removed, Some(&kicked), CloseCode::Banned);
+        Ok(identity)
+    }
+
+    // closes the listeners' connections, after telling them why if there's a message for it,
+    // then lets the next ones in
+    fn send_off(
+        &mut self,
+        removed: Vec<RemovedListener>,
+        why: Option<&ToListenerMessage>,
+        code: CloseCode,
+    ) {
+        for mut listener in removed {
+            debug!("removing listener {} (room={})", listener.id, self.room_id);
+            if let Some(why) = why {
+                let _ = try_send_json(&mut listener.sender, why);
+            }
+            close_later(listener.sender, code);
+            if listener.was_admitted {
+                let _ = self.notify_host(&ToHostMessage::ListenerLeft { id: listener.id });
+            }
+        }
+        self.admit_waiting();
+    }
+
+    // lets in as many waiting listeners as fit, and tells the rest where they are in line
+    fn admit_waiting(&mut self) {
+        let admitted = self.listeners.admit_waiting();
+        let scrollback = self.listeners.chat.scrollback();
+        for listener in admitted {
+            debug!("admitting listener {} (room={})", listener.id, self.room_id);
+            let (id, sender) = (listener.id, listener.sender);
+            self.notify_listener(id, sender.clone(), &ToListenerMessage::Admitted);
+            let messages = scrollback.clone();
+            let scrollback = ToListenerMessage::ChatScrollback { messages };
+            self.notify_listener(id, sender.clone(), &scrollback);
+            if self.listeners.chat.is_muted(&self.listeners.mute_key(id)) {
+                let muted = ToListenerMessage::ChatMuted { muted: true };
+                self.notify_listener(id, sender, &muted);
+            }
+            let joined = ToHostMessage::ListenerJoined {
+                id,
+                display_name: listener.display_name,
+                user_id: listener.user_id,
+            };
+            let _ = self.notify_host(&joined);
+        }
+        for (id, sender, position) in self.listeners.queue_positions() {
+            self.notify_listener(id, sender, &ToListenerMessage::Queued { position });
+        }
+    }
+
+    // lets go of whoever fell behind while the command was handled, false if the room closed
+    fn drop_lagging(&mut self) -> bool {
+        loop {
+            if mem::take(&mut self.host_lagging) {
+                warn!(
+                    "host isn't keeping up, disconnecting it (room={})",
+                    self.room_id
+                );
+                Metrics::incr(&metrics().backpressure_disconnected);
+                if let Some(host) = self.host.sender.clone() {
+                    close_later(host, CloseCode::Backpressure);
+                }
+                let conn_id = self.host.conn_id;
+                if !self.host_disconnected(conn_id) {
+                    return false;
+                }
+            }
+            if self.lagging.is_empty() {
+                return true;
+            }
+            // sending them off can leave others behind in turn, e.g. when it lets in the next
+            let removed: Vec<RemovedListener> = mem::take(&mut self.lagging)
+                .into_iter()
+                .filter_map(|listener| self.listeners.remove(listener))
+                .collect();
+            for listener in &removed {
+                debug!(
+                    "listener {} isn't keeping up (room={})",
+                    listener.id, self.room_id
+                );
+                Metrics::incr(&metrics().backpressure_disconnected);
+            }
+            self.send_off(removed, None, CloseCode::Backpressure);
+            self.announce_listener_count();
+        }
+    }
+
+    // tells GET /rooms/events about the room
+    fn announce(&self, change: DirectoryChange) {
+        self.room_events.send(
+            self.room_id,
+            self.host.owner_id,
+            self.host.visibility,
+            change,
+        );
+    }
+
+    // only if it changed since the last time, a listener leaving often lets the next one in
+    fn announce_listener_count(&mut self) {
+        let listener_count = self.listeners.admitted.len();
+        if listener_count != self.listeners.announced_listeners {
+            self.listeners.announced_listeners = listener_count;
+            self.announce(DirectoryChange::ListenerCountChanged { listener_count });
+        }
+    }
+
+    // Err if the room has no host to tell, or the host is too far behind to take it
+    fn notify_host(&mut self, msg: &ToHostMessage) -> Result<(), ()> {
+        let host = match &mut self.host.sender {
+            None => {
+                debug!("host not found: {}", self.room_id);
+                return Err(());
+            }
+            Some(host) => host,
+        };
+        match try_send_json(host, msg) {
+            Relayed::Sent => Ok(()),
+            Relayed::Full => {
+                self.host_lagging = true;
+                Err(())
+            }
+            Relayed::Gone => Err(()),
+        }
+    }
+
+    // nothing waits for a listener that's behind, it's sent off instead
+    fn notify_listener(&mut self, id: Uuid, mut sender: WSSender, msg: &ToListenerMessage) {
+        if let Relayed::Full = try_send_json(&mut sender, msg) {
+            self.lagging.insert(id);
+        }
+    }
+
+    fn notify_listeners(&mut self, msg: &ToListenerMessage) {
+        for (id, sender) in self.listeners.connections() {
+            self.notify_listener(id, sender, msg);
+        }
+    }
+
+    // chat goes to the host and everyone admitted, without waiting on anyone slow. Whoever is
+    // too far behind misses it, or is sent off if that's the policy
+    fn broadcast_chat(&mut self, to_host: &ToHostMessage, to_listeners: &ToListenerMessage) {
+        let disconnect = settings().backpressure == BackpressurePolicy::Disconnect;
+        if let Some(host) = &mut self.host.sender {
+            if let Relayed::Full = try_send_json(host, to_host) {
+                if disconnect {
+                    self.host_lagging = true;
+                } else {
+                    Metrics::incr(&metrics().backpressure_dropped);
+                }
+            }
+        }
+        for (id, listener) in self.listeners.admitted.iter_mut() {
+            if let Relayed::Full = try_send_json(listener, to_listeners) {
+                if disconnect {
+                    self.lagging.insert(*id);
+                } else {
+                    Metrics::incr(&metrics().backpressure_dropped);
+                }
+            }
+        }
+    }
+}
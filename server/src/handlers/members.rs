@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use diesel::{delete, insert_into, pg::upsert::excluded, prelude::*};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{
+    hyper::StatusCode,
+    reply::{json, with_status},
+};
+
+use crate::schema::{room_members, rooms, users};
+use crate::{
+    db::{Permission, PgPool, Role, Room, RoomMember},
+    errors::MyError,
+};
+
+use super::util::db_txn;
+
+#[derive(Debug, Deserialize)]
+pub struct MemberAddReq {
+    pub display_name: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Serialize, Queryable)]
+pub struct MemberResponse {
+    pub user_id: Uuid,
+    pub display_name: String,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The user's role in the room, None if they aren't a member.
+pub fn room_role(db: &PgConnection, room_id: Uuid, user: Uuid) -> Result<Option<Role>, MyError> {
+    let found = room_members::table
+        .find((room_id, user))
+        .select(room_members::role)
+        .first(db)
+        .optional()?;
+    Ok(found)
+}
+
+/// The user's roles in whichever of the rooms they're a member of.
+pub fn room_roles(
+    db: &PgConnection,
+    user: Uuid,
+    room_ids: &[Uuid],
+) -> Result<HashMap<Uuid, Role>, MyError> {
+    let found: Vec<(Uuid, Role)> = room_members::table
+        .filter(room_members::user_id.eq(user))
+        .filter(room_members::room_id.eq_any(room_ids))
+        .select((room_members::room_id, room_members::role))
+        .load(db)?;
+    Ok(found.into_iter().collect())
+}
+
+/// Finds the room, if the user is allowed to do that there. `action` goes in the error message.
+pub fn check_permission(
+    db: &PgConnection,
+    room_id: Uuid,
+    user: Uuid,
+    permission: Permission,
+    action: &str,
+) -> Result<Room, MyError> {
+    let room: Room = rooms::table.find(room_id).first(db)?;
+    match room_role(db, room_id, user)? {
+        Some(role) if role.can(permission) => Ok(room),
+        _ => {
+            let reason = match permission {
+                Permission::Manage => "room is not owned by user",
+                Permission::Host => "user is not an owner or co-host of the room",
+                Permission::Moderate => "user is not a member of the room",
+            };
+            Err(MyError::AuthError(format!(
+                "Unable to {}: {}",
+                action, reason
+            )))
+        }
+    }
+}
+
+pub async fn has_permission(
+    pool: PgPool,
+    room_id: Uuid,
+    user: Uuid,
+    permission: Permission,
+) -> Result<bool, MyError> {
+    db_txn(pool, true, move |db| {
+        Ok(room_role(db, room_id, user)?.is_some_and(|role| role.can(permission)))
+    })
+    .await
+}
+
+pub async fn list_members(
+    room_id: Uuid,
+    pool: PgPool,
+    req_user_id: Uuid,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let members = db_txn(pool, true, move |db| {
+        check_permission(
+            db,
+            room_id,
+            req_user_id,
+            Permission::Moderate,
+            "list members",
+        )?;
+        let members: Vec<MemberResponse> = room_members::table
+            .inner_join(users::table)
+            .filter(room_members::room_id.eq(room_id))
+            .select((
+                room_members::user_id,
+                users::display_name,
+                room_members::role,
+                room_members::created_at,
+            ))
+            .order(room_members::created_at)
+            .load(db)?;
+        Ok(members)
+    })
+    .await?;
+    Ok(json(&members))
+}
+
+// also changes the role of someone who's already a member
+pub async fn add_member(
+    room_id: Uuid,
+    pool: PgPool,
+    req_user_id: Uuid,
+    req: MemberAddReq,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if req.role == Role::Owner {
+        return Err(warp::reject::custom(MyError::BadRequest(
+            "rooms can't have another owner".to_owned(),
+        )));
+    }
+    let (member, added) = db_txn(pool, false, move |db| {
+        check_permission(db, room_id, req_user_id, Permission::Manage, "add member")?;
+        let (member_id, member_name): (Uuid, String) = users::table
+            .filter(users::display_name.eq(&req.display_name))
+            .select((users::id, users::display_name))
+            .first(db)?;
+        let previous = room_role(db, room_id, member_id)?;
+        if previous == Some(Role::Owner) {
+            return Err(MyError::BadRequest(
+                "the owner's role can't be changed".to_owned(),
+            ));
+        }
+        let member = RoomMember {
+            room_id,
+            user_id: member_id,
+            role: req.role,
+            created_at: Utc::now(),
+        };
+        insert_into(room_members::table)
+            .values(&member)
+            .on_conflict((room_members::room_id, room_members::user_id))
+            .do_update()
+            .set(room_members::role.eq(excluded(room_members::role)))
+            .execute(db)?;
+        let created_at = room_members::table
+            .find((room_id, member_id))
+            .select(room_members::created_at)
+            .first(db)?;
+        let response = MemberResponse {
+            user_id: member_id,
+            display_name: member_name,
+            role: member.role,
+            created_at,
+        };
+        Ok((response, previous.is_none()))
+    })
+    .await?;
+    let status = if added {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok(with_status(json(&member), status))
+}
+
+// members can also leave on their own, except for the owner
+pub async fn remove_member(
+    room_id: Uuid,
+    member_id: Uuid,
+    pool: PgPool,
+    req_user_id: Uuid,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    db_txn(pool, false, move |db| {
+        if member_id != req_user_id {
+            check_permission(
+                db,
+                room_id,
+                req_user_id,
+                Permission::Manage,
+                "remove member",
+            )?;
+        }
+        match room_role(db, room_id, member_id)? {
+            None => Err(MyError::DBError(diesel::result::Error::NotFound)),
+            Some(Role::Owner) => Err(MyError::BadRequest(
+                "the owner can't be removed from their room".to_owned(),
+            )),
+            Some(_) => {
+                delete(room_members::table.find((room_id, member_id))).execute(db)?;
+                Ok(())
+            }
+        }
+    })
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
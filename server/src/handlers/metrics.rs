@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Counters for signaling messages the server refused to relay.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub rate_limited: AtomicU64,
+    pub oversized: AtomicU64,
+    pub backpressure_dropped: AtomicU64,
+    pub backpressure_disconnected: AtomicU64,
+}
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Prometheus text format
+    fn render(&self) -> String {
+        let counters = [
+            (
+                "radiowo_ws_rate_limited_total",
+                "Messages rejected for going over the rate limit",
+                &self.rate_limited,
+            ),
+            (
+                "radiowo_ws_oversized_total",
+                "Messages rejected for being too large",
+                &self.oversized,
+            ),
+            (
+                "radiowo_ws_backpressure_dropped_total",
+                "Messages dropped because the receiving side's queue was full",
+                &self.backpressure_dropped,
+            ),
+            (
+                "radiowo_ws_backpressure_disconnected_total",
+                "Connections closed because the receiving side's queue was full",
+                &self.backpressure_disconnected,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, counter) in counters.iter() {
+            out.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+                name,
+                help,
+                name,
+                name,
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
+pub async fn get_metrics() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::with_header(
+        metrics().render(),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
@@ -1,10 +1,31 @@
+mod bans;
+mod chat;
+mod cursor;
+mod directory;
+mod limits;
+mod live_room;
+mod members;
+mod metrics;
+mod protocol;
+mod registry;
 mod room_conns;
+mod room_sessions;
 mod rooms;
+mod schedule;
+mod sessions;
 mod turn;
 mod users;
-mod util;
+pub mod util;
 
+pub use bans::*;
+pub use directory::*;
+pub use limits::*;
+pub use members::*;
+pub use metrics::*;
+pub use registry::*;
 pub use room_conns::*;
+pub use room_sessions::*;
 pub use rooms::*;
+pub use sessions::*;
 pub use turn::*;
 pub use users::*;
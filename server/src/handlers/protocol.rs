@@ -0,0 +1,594 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::db::{Room, Visibility};
+
+use super::schedule::{Occurrence, Schedule};
+
+/// Protocol versions this server can speak. Clients say which one they speak in their `hello`.
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+const MAX_SDP_LEN: usize = 64 * 1024;
+const MAX_CANDIDATE_LEN: usize = 1024;
+const MAX_SDP_MID_LEN: usize = 64;
+const MAX_REASON_LEN: usize = 256;
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+const MAX_TRACK_TEXT_LEN: usize = 256;
+const MAX_ARTWORK_URL_LEN: usize = 2048;
+const MAX_CHAT_LEN: usize = 500;
+
+/// WebRTC signaling, relayed between the host and a listener.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Signal {
+    Offer {
+        sdp: String,
+    },
+    Answer {
+        sdp: String,
+    },
+    IceCandidate {
+        // null once the peer is done gathering candidates
+        candidate: Option<IceCandidate>,
+    },
+    /// Asks the other side to (re)start negotiation, e.g. for a listener that just joined.
+    Renegotiate,
+    /// The peer connection is being closed on purpose.
+    Bye {
+        reason: Option<String>,
+    },
+    /// Something went wrong on the sender's side, e.g. applying a remote description.
+    Error {
+        message: String,
+    },
+}
+
+/// Same shape as the browser's RTCIceCandidateInit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidate {
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex")]
+    pub sdp_m_line_index: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username_fragment: Option<String>,
+}
+
+impl Signal {
+    /// Checks the parts the server can check, so bad signaling fails here rather than in the
+    /// other peer's browser.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Signal::Offer { sdp } | Signal::Answer { sdp } => {
+                if sdp.len() > MAX_SDP_LEN {
+                    Err(format!("sdp is longer than {} bytes", MAX_SDP_LEN))
+                } else if !sdp.starts_with("v=0") {
+                    Err("sdp must start with v=0".to_owned())
+                } else {
+                    Ok(())
+                }
+            }
+            Signal::IceCandidate { candidate: None } => Ok(()),
+            Signal::IceCandidate {
+                candidate: Some(candidate),
+            } => {
+                // an empty candidate also marks the end of candidates
+                if candidate.candidate.is_empty() {
+                    Ok(())
+                } else if candidate.candidate.len() > MAX_CANDIDATE_LEN {
+                    Err(format!(
+                        "candidate is longer than {} bytes",
+                        MAX_CANDIDATE_LEN
+                    ))
+                } else if !candidate.candidate.starts_with("candidate:") {
+                    Err("candidate must start with candidate:".to_owned())
+                } else if candidate.sdp_mid.is_none() && candidate.sdp_m_line_index.is_none() {
+                    Err("candidate needs an sdpMid or sdpMLineIndex".to_owned())
+                } else if candidate
+                    .sdp_mid
+                    .as_ref()
+                    .is_some_and(|mid| mid.len() > MAX_SDP_MID_LEN)
+                {
+                    Err(format!("sdpMid is longer than {} bytes", MAX_SDP_MID_LEN))
+                } else {
+                    Ok(())
+                }
+            }
+            Signal::Renegotiate => Ok(()),
+            Signal::Bye { reason } => match reason {
+                Some(reason) if reason.len() > MAX_REASON_LEN => {
+                    Err(format!("reason is longer than {} bytes", MAX_REASON_LEN))
+                }
+                _ => Ok(()),
+            },
+            Signal::Error { message } => {
+                if message.len() > MAX_REASON_LEN {
+                    Err(format!("message is longer than {} bytes", MAX_REASON_LEN))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// The frame wasn't a JSON text frame the server understands.
+    MalformedMessage,
+    /// The signal was understood but failed validation.
+    InvalidSignal,
+    UnsupportedVersion,
+    /// The `hello` was understood but something in it other than the version was rejected.
+    InvalidHello,
+    /// Something other than `hello` was sent before the handshake finished.
+    HandshakeRequired,
+    UnknownListener,
+    HostUnavailable,
+    RateLimited,
+    MessageTooLarge,
+    /// The other side isn't keeping up, so the message was dropped.
+    PeerBusy,
+    /// The room and its waiting queue are full.
+    RoomFull,
+    /// The listener is still waiting for a spot in the room.
+    NotAdmitted,
+    /// A `now-playing` message didn't pass validation.
+    InvalidNowPlaying,
+    InvalidChat,
+    /// The host muted this listener in the chat.
+    Muted,
+    /// The chat message to delete isn't in the scrollback (anymore).
+    UnknownChatMessage,
+    /// Something went wrong on the server's side, e.g. it couldn't reach the database.
+    ServerError,
+    /// Only the room's members can do that, and only the host can moderate other members.
+    NotAllowed,
+    /// The room isn't live yet, so there's nothing to do that in.
+    NotLive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayStatus {
+    Playing,
+    Paused,
+}
+
+/// What the host is playing, as far as it can tell. Everything but the status is optional
+/// since not every page says.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NowPlaying {
+    pub status: PlayStatus,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub artwork_url: Option<String>,
+    // seconds
+    pub duration: Option<f64>,
+    // seconds into the track as of updated_at
+    pub position: Option<f64>,
+    // set by the server when the host reports it, over whatever the host sent
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl NowPlaying {
+    pub fn validate(&self) -> Result<(), String> {
+        for (field, text) in [("title", &self.title), ("artist", &self.artist)] {
+            if let Some(text) = text {
+                if text.chars().count() > MAX_TRACK_TEXT_LEN {
+                    return Err(format!(
+                        "{} is longer than {} characters",
+                        field, MAX_TRACK_TEXT_LEN
+                    ));
+                }
+            }
+        }
+        if let Some(url) = &self.artwork_url {
+            if url.len() > MAX_ARTWORK_URL_LEN {
+                return Err(format!(
+                    "artwork_url is longer than {} bytes",
+                    MAX_ARTWORK_URL_LEN
+                ));
+            }
+            // listeners load it, so nothing that only makes sense on the host's machine
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err("artwork_url must be an http(s) URL".to_owned());
+            }
+        }
+        for (field, seconds) in [("duration", self.duration), ("position", self.position)] {
+            if let Some(seconds) = seconds {
+                if !seconds.is_finite() || seconds < 0.0 {
+                    return Err(format!(
+                        "{} must be a non-negative number of seconds",
+                        field
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Who wrote a chat message.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "role", rename_all = "kebab-case")]
+pub enum ChatAuthor {
+    Host,
+    Listener {
+        id: Uuid,
+        display_name: Option<String>,
+        // None for anonymous listeners
+        user_id: Option<Uuid>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    // counts up per room, hosts delete messages by it
+    pub id: u64,
+    pub author: ChatAuthor,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Why a room stopped being live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoomClosedReason {
+    HostLeft,
+    RoomDeleted,
+    // the owner started hosting from somewhere else
+    HostReplaced,
+}
+
+impl RoomClosedReason {
+    pub fn close_code(self) -> CloseCode {
+        match self {
+            RoomClosedReason::HostLeft => CloseCode::HostLeft,
+            RoomClosedReason::RoomDeleted => CloseCode::RoomDeleted,
+            RoomClosedReason::HostReplaced => CloseCode::HostReplaced,
+        }
+    }
+}
+
+/// What hosts and listeners see of a room's settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomDetails {
+    pub name: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub visibility: Visibility,
+    pub max_listeners: Option<i32>,
+    pub require_login: bool,
+    pub schedule: Option<Schedule>,
+}
+
+impl From<&Room> for RoomDetails {
+    fn from(room: &Room) -> Self {
+        Self {
+            name: room.room_name.clone(),
+            description: room.description.clone(),
+            tags: room.tags.clone(),
+            visibility: room.visibility,
+            max_listeners: room.max_listeners,
+            require_login: room.require_login,
+            schedule: Schedule::of(room),
+        }
+    }
+}
+
+/// WebSocket close codes the server closes connections with. Everything but `Normal` is in
+/// the 4000-4999 range reserved for applications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,
+    HostLeft,
+    RoomDeleted,
+    HostReplaced,
+    UnsupportedVersion,
+    HostUnavailable,
+    PingTimeout,
+    // sending faster than the other side can keep up with
+    Backpressure,
+    RoomFull,
+    Kicked,
+    Banned,
+}
+
+impl CloseCode {
+    const ALL: [CloseCode; 11] = [
+        CloseCode::Normal,
+        CloseCode::HostLeft,
+        CloseCode::RoomDeleted,
+        CloseCode::HostReplaced,
+        CloseCode::UnsupportedVersion,
+        CloseCode::HostUnavailable,
+        CloseCode::PingTimeout,
+        CloseCode::Backpressure,
+        CloseCode::RoomFull,
+        CloseCode::Kicked,
+        CloseCode::Banned,
+    ];
+
+    /// warp doesn't hand out a close frame's code, but the server always closes with the code's
+    /// own reason, so that tells them apart.
+    pub fn from_reason(reason: &str) -> CloseCode {
+        CloseCode::ALL
+            .iter()
+            .copied()
+            .find(|code| code.reason() == reason)
+            .unwrap_or(CloseCode::Normal)
+    }
+
+    pub fn code(self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::HostLeft => 4000,
+            CloseCode::RoomDeleted => 4001,
+            CloseCode::HostReplaced => 4002,
+            CloseCode::UnsupportedVersion => 4003,
+            CloseCode::HostUnavailable => 4004,
+            CloseCode::PingTimeout => 4005,
+            CloseCode::Backpressure => 4006,
+            CloseCode::RoomFull => 4007,
+            CloseCode::Kicked => 4008,
+            CloseCode::Banned => 4009,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseCode::Normal => "",
+            CloseCode::HostLeft => "host left",
+            CloseCode::RoomDeleted => "room deleted",
+            CloseCode::HostReplaced => "host replaced",
+            CloseCode::UnsupportedVersion => "unsupported protocol version",
+            CloseCode::HostUnavailable => "host unavailable",
+            CloseCode::PingTimeout => "ping timeout",
+            CloseCode::Backpressure => "too far behind",
+            CloseCode::RoomFull => "room full",
+            CloseCode::Kicked => "kicked",
+            CloseCode::Banned => "banned",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum FromHostMessage {
+    Hello {
+        version: u32,
+    },
+    Signal {
+        to: Uuid,
+        signal: Signal,
+    },
+    // older extensions still send this, server pings took its place
+    KeepAlive,
+    NowPlaying(NowPlaying),
+    Chat {
+        text: String,
+    },
+    /// Removes a message from everyone's chat and the scrollback.
+    DeleteChat {
+        id: u64,
+    },
+    /// Mutes a listener in the chat until the room closes, by account or else by address, so
+    /// reconnecting doesn't lift it.
+    Mute {
+        listener: Uuid,
+    },
+    Unmute {
+        listener: Uuid,
+    },
+    /// Disconnects a listener, which is free to come back.
+    Kick {
+        listener: Uuid,
+    },
+    /// Disconnects a listener and everyone else connecting from the same address or account, and
+    /// keeps them out until the room closes, or for good if persistent.
+    Ban {
+        listener: Uuid,
+        #[serde(default)]
+        persistent: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum FromListenerMessage {
+    Hello {
+        version: u32,
+        // shown to the host, ignored for listeners who are logged in since they go by their
+        // account's name
+        #[serde(default)]
+        display_name: Option<String>,
+    },
+    Signal {
+        signal: Signal,
+    },
+    Chat {
+        text: String,
+    },
+    // moderation, for listeners who are members of the room, same as the host's
+    DeleteChat {
+        id: u64,
+    },
+    Mute {
+        listener: Uuid,
+    },
+    Unmute {
+        listener: Uuid,
+    },
+    Kick {
+        listener: Uuid,
+    },
+    Ban {
+        listener: Uuid,
+        #[serde(default)]
+        persistent: bool,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ToHostMessage {
+    Welcome {
+        version: u32,
+    },
+    Signal {
+        from: Uuid,
+        // the listener's account, None if it's anonymous
+        user_id: Option<Uuid>,
+        signal: Signal,
+    },
+    ListenerJoined {
+        id: Uuid,
+        display_name: Option<String>,
+        user_id: Option<Uuid>,
+    },
+    ListenerLeft {
+        id: Uuid,
+    },
+    /// Sent after the welcome when the host took its room back within the reconnect grace
+    /// period, with the listeners that are still there.
+    Resume {
+        listeners: Vec<Uuid>,
+    },
+    /// The owner changed the room's settings.
+    RoomUpdated {
+        room: RoomDetails,
+    },
+    Chat {
+        message: ChatMessage,
+    },
+    ChatDeleted {
+        id: u64,
+    },
+    /// The recent chat, oldest first. Sent after the welcome.
+    ChatScrollback {
+        messages: Vec<ChatMessage>,
+    },
+    RoomClosed {
+        reason: RoomClosedReason,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ToListenerMessage {
+    Welcome {
+        version: u32,
+        // the id the host knows this listener by
+        id: Uuid,
+    },
+    /// The listener is in the room and can start signaling. Follows the welcome, right away
+    /// unless the room is full.
+    Admitted,
+    /// The host isn't live yet but has a broadcast scheduled, so the listener waits for it
+    /// instead of being turned away. Followed by `admitted` or `queued` once the host is
+    /// live, and sent again if the room is rescheduled.
+    WaitingForHost {
+        broadcast: Occurrence,
+    },
+    /// The room is full, the listener is admitted once everyone ahead of it is. Sent again
+    /// whenever the position changes.
+    Queued {
+        // 1 is next in line
+        position: usize,
+    },
+    Signal {
+        signal: Signal,
+    },
+    /// The host dropped, the room closes unless it's back within the grace period.
+    HostReconnecting {
+        grace_seconds: u64,
+    },
+    HostResumed,
+    Chat {
+        message: ChatMessage,
+    },
+    ChatDeleted {
+        id: u64,
+    },
+    /// The recent chat, oldest first. Sent once the listener is admitted.
+    ChatScrollback {
+        messages: Vec<ChatMessage>,
+    },
+    /// The host muted or unmuted this listener. Also sent on joining a room it's still muted in.
+    ChatMuted {
+        muted: bool,
+    },
+    /// The host removed this listener from the room, the connection closes right after.
+    Kicked {
+        banned: bool,
+    },
+    /// Sent whenever the host reports a change, and after the welcome if the host already has.
+    NowPlaying(NowPlaying),
+    RoomUpdated {
+        room: RoomDetails,
+    },
+    RoomClosed {
+        reason: RoomClosedReason,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+pub fn check_version(version: u32) -> Result<(), String> {
+    if SUPPORTED_VERSIONS.contains(&version) {
+        Ok(())
+    } else {
+        Err(format!(
+            "unsupported protocol version {}, supported: {:?}",
+            version, SUPPORTED_VERSIONS
+        ))
+    }
+}
+
+/// Trims the message, which can't be blank.
+pub fn check_chat_text(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        Err("chat messages can't be empty".to_owned())
+    } else if text.chars().count() > MAX_CHAT_LEN {
+        Err(format!(
+            "chat messages can't be longer than {} characters",
+            MAX_CHAT_LEN
+        ))
+    } else if text.chars().any(|c| c.is_control() && c != '\n') {
+        Err("chat messages can't contain control characters".to_owned())
+    } else {
+        Ok(text.to_owned())
+    }
+}
+
+/// Trims the name, treating a blank one as no name at all.
+pub fn check_display_name(name: Option<String>) -> Result<Option<String>, String> {
+    let name = match name {
+        None => return Ok(None),
+        Some(name) => name.trim().to_owned(),
+    };
+    if name.is_empty() {
+        Ok(None)
+    } else if name.chars().count() > MAX_DISPLAY_NAME_LEN {
+        Err(format!(
+            "display name is longer than {} characters",
+            MAX_DISPLAY_NAME_LEN
+        ))
+    } else if name.chars().any(char::is_control) {
+        Err("display name can't contain control characters".to_owned())
+    } else {
+        Ok(Some(name))
+    }
+}
@@ -0,0 +1,559 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use diesel::{prelude::*, sql_types::Text};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, RwLock},
+    task, time,
+};
+use tokio_postgres::{AsyncMessage, NoTls};
+use uuid::Uuid;
+use warp::ws::Message;
+
+use crate::{
+    db::{HostedRoom, PgPool, Visibility},
+    errors::MyError,
+    schema::{relay_payloads, room_hosts},
+    settings::settings,
+};
+
+use super::{directory::DirectoryChange, protocol::CloseCode, schedule::Occurrence, util::db_txn};
+
+/// Which ConnectionRegistry the server keeps track of rooms' hosts with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistryKind {
+    /// In memory, for running a single instance.
+    Memory,
+    /// In the database, with messages between instances going over LISTEN/NOTIFY.
+    Postgres,
+}
+
+impl FromStr for RegistryKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(RegistryKind::Memory),
+            "postgres" => Ok(RegistryKind::Postgres),
+            _ => Err(format!("unknown registry: {}", s)),
+        }
+    }
+}
+
+/// A websocket message passed along between instances.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Frame {
+    Text { text: String },
+    // listeners only ever get an error back for these, so no need to be compact
+    Binary { data: Vec<u8> },
+    Ping,
+    Pong,
+    Close { reason: String },
+}
+
+impl From<Message> for Frame {
+    fn from(msg: Message) -> Self {
+        if msg.is_ping() {
+            Frame::Ping
+        } else if msg.is_pong() {
+            Frame::Pong
+        } else if msg.is_close() {
+            let reason = String::from_utf8_lossy(&msg.into_bytes()).into_owned();
+            Frame::Close { reason }
+        } else if let Ok(text) = msg.to_str() {
+            Frame::Text {
+                text: text.to_owned(),
+            }
+        } else {
+            Frame::Binary {
+                data: msg.into_bytes(),
+            }
+        }
+    }
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Text { text } => Message::text(text),
+            Frame::Binary { data } => Message::binary(data),
+            // warp can't make pongs, and a ping shows the other side is there just as well
+            Frame::Ping | Frame::Pong => Message::ping(Vec::new()),
+            Frame::Close { reason } => {
+                let code = CloseCode::from_reason(&reason);
+                Message::close_with(code.code(), code.reason())
+            }
+        }
+    }
+}
+
+/// A message for another instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Relay {
+    /// A listener connected to the sending instance, for a room whose host is on the receiving
+    /// one. Its messages follow as frames with the same connection UUID.
+    ListenerOpened {
+        conn_id: Uuid,
+        // the instance to send the listener's frames back to
+        from: Uuid,
+        room_id: Uuid,
+        listener_id: Uuid,
+        fingerprint: Option<String>,
+        user_id: Option<Uuid>,
+        account_name: Option<String>,
+        scheduled: Option<Occurrence>,
+    },
+    /// A message on a relayed listener connection, either way.
+    Frame {
+        conn_id: Uuid,
+        frame: Frame,
+    },
+    /// One end of a relayed listener connection went away.
+    Closed {
+        conn_id: Uuid,
+    },
+    /// The room's host connected to another instance, so the receiving one has to let it go.
+    HostReplaced {
+        room_id: Uuid,
+    },
+    /// The room's host is ready, for the listeners waiting in its lobby.
+    HostLive {
+        room_id: Uuid,
+    },
+    RoomUpdated {
+        room_id: Uuid,
+    },
+    RoomDeleted {
+        room_id: Uuid,
+    },
+    /// Something about the room changed, for everyone on GET /rooms/events.
+    DirectoryChanged {
+        room_id: Uuid,
+        owner_id: Uuid,
+        visibility: Visibility,
+        change: DirectoryChange,
+    },
+}
+
+/// How a room live on this instance is doing, for the other instances' room listings.
+#[derive(Debug, Clone)]
+pub struct HostActivity {
+    pub room_id: Uuid,
+    pub listener_count: i32,
+    // as JSON
+    pub now_playing: Option<String>,
+}
+
+/// Keeps track of the instance each live room's host is connected to, and carries messages
+/// between instances so listeners can connect to any of them.
+#[async_trait]
+pub trait ConnectionRegistry: Send + Sync {
+    /// This instance, new every time the server starts.
+    fn instance_id(&self) -> Uuid;
+
+    /// Where the room's host is connected, None if it's not connected anywhere.
+    async fn room_host(&self, room_id: Uuid) -> Result<Option<HostedRoom>, MyError>;
+
+    /// Every room whose host is connected, to this instance or any other.
+    async fn room_hosts(&self) -> Result<Vec<HostedRoom>, MyError>;
+
+    /// Records the room's host as connected to this instance, or reconnecting to it.
+    async fn set_room_host(&self, room_id: Uuid, reconnecting: bool) -> Result<(), MyError>;
+
+    /// Forgets the room's host, unless it's connected to another instance by now.
+    async fn clear_room_host(&self, room_id: Uuid) -> Result<(), MyError>;
+
+    /// Records how the rooms live on this instance are doing.
+    async fn update_room_hosts(&self, activity: Vec<HostActivity>) -> Result<(), MyError>;
+
+    /// Sends to one instance, or to every other one if `to` is None. Relays from one task arrive
+    /// in the order they were sent.
+    async fn send(&self, to: Option<Uuid>, relay: Relay) -> Result<(), MyError>;
+}
+
+pub type Registry = Arc<dyn ConnectionRegistry>;
+
+/// Where relays from other instances come in.
+pub type RelayReceiver = mpsc::UnboundedReceiver<Relay>;
+
+/// Sets up the registry the settings ask for.
+pub fn start_registry(kind: RegistryKind, pool: PgPool) -> (Registry, RelayReceiver) {
+    match kind {
+        RegistryKind::Memory => {
+            // nobody else to hear from
+            let (_, relays) = mpsc::unbounded_channel();
+            (Arc::new(LocalRegistry::default()), relays)
+        }
+        RegistryKind::Postgres => {
+            let (registry, relays) = PostgresRegistry::start(pool);
+            (Arc::new(registry), relays)
+        }
+    }
+}
+
+/// Every room's host is on this instance, so there's never anyone to relay to.
+pub struct LocalRegistry {
+    instance_id: Uuid,
+    hosts: RwLock<HashMap<Uuid, HostedRoom>>,
+}
+
+impl Default for LocalRegistry {
+    fn default() -> Self {
+        Self {
+            instance_id: Uuid::new_v4(),
+            hosts: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl ConnectionRegistry for LocalRegistry {
+    fn instance_id(&self) -> Uuid {
+        self.instance_id
+    }
+
+    async fn room_host(&self, room_id: Uuid) -> Result<Option<HostedRoom>, MyError> {
+        Ok(self.hosts.read().await.get(&room_id).cloned())
+    }
+
+    async fn room_hosts(&self) -> Result<Vec<HostedRoom>, MyError> {
+        Ok(self.hosts.read().await.values().cloned().collect())
+    }
+
+    async fn set_room_host(&self, room_id: Uuid, reconnecting: bool) -> Result<(), MyError> {
+        let mut hosts = self.hosts.write().await;
+        let host = hosts.entry(room_id).or_insert_with(|| HostedRoom {
+            room_id,
+            instance_id: self.instance_id,
+            reconnecting,
+            updated_at: Utc::now(),
+            listener_count: 0,
+            now_playing: None,
+        });
+        host.reconnecting = reconnecting;
+        host.updated_at = Utc::now();
+        Ok(())
+    }
+
+    async fn clear_room_host(&self, room_id: Uuid) -> Result<(), MyError> {
+        self.hosts.write().await.remove(&room_id);
+        Ok(())
+    }
+
+    async fn update_room_hosts(&self, activity: Vec<HostActivity>) -> Result<(), MyError> {
+        let mut hosts = self.hosts.write().await;
+        for room in activity {
+            if let Some(host) = hosts.get_mut(&room.room_id) {
+                host.listener_count = room.listener_count;
+                host.now_playing = room.now_playing;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&self, _to: Option<Uuid>, relay: Relay) -> Result<(), MyError> {
+        debug!("no other instances to relay to: {:?}", relay);
+        Ok(())
+    }
+}
+
+// every instance listens on this one and its own
+const SHARED_CHANNEL: &str = "radiowo";
+
+// NOTIFY payloads have to be shorter than 8000 bytes, so larger relays go in relay_payloads.
+// Leaves room for the rest of the notification
+const MAX_INLINE_RELAY: usize = 7900;
+
+const RELISTEN_DELAY: Duration = Duration::from_secs(1);
+
+fn channel_of(instance_id: Uuid) -> String {
+    format!("radiowo_{}", instance_id.to_simple())
+}
+
+// what goes out as a NOTIFY payload
+#[derive(Debug, Serialize, Deserialize)]
+struct Notification {
+    from: Uuid,
+    notice: Notice,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Notice {
+    Relay(Relay),
+    // too large to send inline, the relay_payloads row to take it from. Only ever sent to a
+    // single instance, since the first one to take it deletes it
+    Stored(Uuid),
+}
+
+/// Rooms' hosts in the room_hosts table and relays over Postgres LISTEN/NOTIFY, for running
+/// several instances against the same database. Every relay is a round trip to the database, which
+/// is plenty for signaling. Relays sent while an instance is reconnecting to listen are lost, it
+/// logs how long it wasn't listening once it is again. Room listings on other instances see the
+/// listener counts and what's playing as of the last refresh, every ping interval.
+pub struct PostgresRegistry {
+    instance_id: Uuid,
+    pool: PgPool,
+}
+
+impl PostgresRegistry {
+    pub fn start(pool: PgPool) -> (Self, RelayReceiver) {
+        let instance_id = Uuid::new_v4();
+        let (relay_sender, relays) = mpsc::unbounded_channel();
+        task::spawn(listen(instance_id, pool.clone(), relay_sender));
+        spawn_refresh(instance_id, pool.clone());
+        info!("registered as instance {}", instance_id);
+        (Self { instance_id, pool }, relays)
+    }
+}
+
+#[async_trait]
+impl ConnectionRegistry for PostgresRegistry {
+    fn instance_id(&self) -> Uuid {
+        self.instance_id
+    }
+
+    async fn room_host(&self, room: Uuid) -> Result<Option<HostedRoom>, MyError> {
+        db_txn(self.pool.clone(), true, move |db| {
+            Ok(room_hosts::table
+                .find(room)
+                .filter(room_hosts::updated_at.gt(stale_before()))
+                .first(db)
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn room_hosts(&self) -> Result<Vec<HostedRoom>, MyError> {
+        db_txn(self.pool.clone(), true, move |db| {
+            Ok(room_hosts::table
+                .filter(room_hosts::updated_at.gt(stale_before()))
+                .load(db)?)
+        })
+        .await
+    }
+
+    async fn set_room_host(&self, room: Uuid, reconnecting: bool) -> Result<(), MyError> {
+        let host = HostedRoom {
+            room_id: room,
+            instance_id: self.instance_id,
+            reconnecting,
+            updated_at: Utc::now(),
+            listener_count: 0,
+            now_playing: None,
+        };
+        db_txn(self.pool.clone(), false, move |db| {
+            diesel::insert_into(room_hosts::table)
+                .values(&host)
+                .on_conflict(room_hosts::room_id)
+                .do_update()
+                .set((
+                    room_hosts::instance_id.eq(host.instance_id),
+                    room_hosts::reconnecting.eq(host.reconnecting),
+                    room_hosts::updated_at.eq(host.updated_at),
+                ))
+                .execute(db)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn clear_room_host(&self, room: Uuid) -> Result<(), MyError> {
+        let instance = self.instance_id;
+        db_txn(self.pool.clone(), false, move |db| {
+            diesel::delete(
+                room_hosts::table
+                    .find(room)
+                    .filter(room_hosts::instance_id.eq(instance)),
+            )
+            .execute(db)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_room_hosts(&self, activity: Vec<HostActivity>) -> Result<(), MyError> {
+        let instance = self.instance_id;
+        db_txn(self.pool.clone(), false, move |db| {
+            for room in activity {
+                // not if the room moved to another instance in the meantime
+                diesel::update(
+                    room_hosts::table
+                        .find(room.room_id)
+                        .filter(room_hosts::instance_id.eq(instance)),
+                )
+                .set((
+                    room_hosts::listener_count.eq(room.listener_count),
+                    room_hosts::now_playing.eq(room.now_playing),
+                ))
+                .execute(db)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn send(&self, to: Option<Uuid>, relay: Relay) -> Result<(), MyError> {
+        let channel = to.map_or_else(|| SHARED_CHANNEL.to_owned(), channel_of);
+        let relay_json = to_json(&relay)?;
+        let (notice, stored) = if relay_json.len() <= MAX_INLINE_RELAY {
+            (Notice::Relay(relay), None)
+        } else {
+            let stored_id = Uuid::new_v4();
+            (Notice::Stored(stored_id), Some((stored_id, relay_json)))
+        };
+        let payload = to_json(&Notification {
+            from: self.instance_id,
+            notice,
+        })?;
+        db_txn(self.pool.clone(), false, move |db| {
+            if let Some((stored_id, relay_json)) = stored {
+                diesel::insert_into(relay_payloads::table)
+                    .values((
+                        relay_payloads::id.eq(stored_id),
+                        relay_payloads::payload.eq(relay_json),
+                        relay_payloads::created_at.eq(Utc::now()),
+                    ))
+                    .execute(db)?;
+            }
+            // delivered once the transaction commits, so after the payload is there
+            diesel::sql_query("SELECT pg_notify($1, $2)")
+                .bind::<Text, _>(channel)
+                .bind::<Text, _>(payload)
+                .execute(db)?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, MyError> {
+    serde_json::to_string(value).map_err(|e| {
+        error!("unable to serialize relay: {}", e);
+        MyError::UnexpectedError
+    })
+}
+
+// rows an instance stopped bumping for this long are left from a crash
+fn stale_before() -> DateTime<Utc> {
+    let missed = i64::from(settings().max_missed_pongs) + 1;
+    Utc::now() - chrono::Duration::seconds(settings().ping_interval as i64 * missed)
+}
+
+// keeps this instance's rooms from looking stale
+fn spawn_refresh(instance_id: Uuid, pool: PgPool) {
+    task::spawn(async move {
+        let mut ticks = time::interval(Duration::from_secs(settings().ping_interval));
+        loop {
+            ticks.tick().await;
+            let result = db_txn(pool.clone(), false, move |db| {
+                diesel::update(room_hosts::table.filter(room_hosts::instance_id.eq(instance_id)))
+                    .set(room_hosts::updated_at.eq(Utc::now()))
+                    .execute(db)?;
+                // nobody took them, e.g. because the instance they were for crashed
+                diesel::delete(
+                    relay_payloads::table.filter(relay_payloads::created_at.lt(stale_before())),
+                )
+                .execute(db)?;
+                Ok(())
+            })
+            .await;
+            if let Err(e) = result {
+                error!("unable to refresh room hosts: {:#?}", e);
+            }
+        }
+    });
+}
+
+// passes on relays for this instance, listening again whenever the connection drops
+async fn listen(instance_id: Uuid, pool: PgPool, relays: mpsc::UnboundedSender<Relay>) {
+    let channels = format!(
+        "LISTEN {}; LISTEN {}",
+        SHARED_CHANNEL,
+        channel_of(instance_id)
+    );
+    // since when it isn't listening, NOTIFY doesn't keep anything for later
+    let mut deaf_since: Option<DateTime<Utc>> = None;
+    loop {
+        match tokio_postgres::connect(&settings().database_url, NoTls).await {
+            Err(e) => error!("unable to connect to listen for relays: {}", e),
+            Ok((client, mut connection)) => {
+                let (payload_sender, mut payloads) = mpsc::unbounded_channel();
+                // the connection only gets anywhere while its messages are read
+                let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+                task::spawn(async move {
+                    while let Some(message) = messages.next().await {
+                        match message {
+                            Ok(AsyncMessage::Notification(notification)) => {
+                                let _ = payload_sender.send(notification.payload().to_owned());
+                            }
+                            Ok(_) => {}
+                            // it keeps failing once it's closed, so this is where listening ends
+                            Err(e) => {
+                                error!("relay connection error: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                });
+                match client.batch_execute(&channels).await {
+                    Err(e) => error!("unable to listen for relays: {}", e),
+                    Ok(()) => {
+                        if let Some(since) = deaf_since.take() {
+                            error!(
+                                "listening for relays again, any sent since {} were lost",
+                                since
+                            );
+                        }
+                        while let Some(payload) = payloads.recv().await {
+                            if let Some(relay) = receive(instance_id, &pool, &payload).await {
+                                if relays.send(relay).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                        error!("stopped listening for relays, listening again");
+                    }
+                }
+            }
+        }
+        deaf_since.get_or_insert_with(Utc::now);
+        time::delay_for(RELISTEN_DELAY).await;
+    }
+}
+
+// None if the notification isn't for this instance after all
+async fn receive(instance_id: Uuid, pool: &PgPool, payload: &str) -> Option<Relay> {
+    let notification: Notification = serde_json::from_str(payload)
+        .map_err(|e| error!("unable to parse relay: {}", e))
+        .ok()?;
+    // relays to everyone come back to their sender too
+    if notification.from == instance_id {
+        return None;
+    }
+    let stored_id = match notification.notice {
+        Notice::Relay(relay) => return Some(relay),
+        Notice::Stored(stored_id) => stored_id,
+    };
+    let stored = db_txn(pool.clone(), false, move |db| {
+        Ok(diesel::delete(relay_payloads::table.find(stored_id))
+            .returning(relay_payloads::payload)
+            .get_result::<String>(db)?)
+    })
+    .await;
+    match stored {
+        Err(e) => {
+            error!("unable to take stored relay: {:#?}", e);
+            None
+        }
+        Ok(relay_json) => serde_json::from_str(&relay_json)
+            .map_err(|e| error!("unable to parse relay: {}", e))
+            .ok(),
+    }
+}
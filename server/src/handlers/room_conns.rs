@@ -1,12 +1,16 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, convert::TryFrom, net::IpAddr, sync::Arc, time::Duration};
 
 use chrono::Utc;
-use diesel::prelude::*;
-use futures::{FutureExt, StreamExt};
-use serde::{Deserialize, Serialize};
+use diesel::{dsl::any, prelude::*};
+use futures::{future, FutureExt, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
 use tokio::{
-    sync::{mpsc, RwLock},
-    task,
+    sync::{
+        broadcast::{self, RecvError},
+        mpsc, RwLock,
+    },
+    task, time,
 };
 use uuid::Uuid;
 use warp::{
@@ -14,76 +18,611 @@ use warp::{
     Rejection,
 };
 
-use crate::{db::PgPool, errors::MyError, settings::BUF_SIZE};
-use crate::{db::Room, schema::rooms::dsl::*};
+use crate::{db::PgPool, errors::MyError, settings::settings};
+use crate::{
+    db::{HostedRoom, Permission, Room},
+    schema::{room_sessions, rooms::dsl::*, users},
+};
+
+use super::{
+    bans::{ban_fingerprint, is_banned, save_ban},
+    directory::{DirectoryEvent, DirectoryOutbox, RoomEvents},
+    limits::{BackpressurePolicy, TokenBucket},
+    live_room::{
+        peak_of, send_close, send_json, take_room, ConnectedListener, HostArrival,
+        ListenerIdentity, LiveRoom, ModerationError, Placement, Relayed, RoomCommand, RoomStatus,
+        SessionStats, WSSender,
+    },
+    members::{check_permission, has_permission},
+    metrics::{metrics, Metrics},
+    protocol::{
+        check_chat_text, check_display_name, check_version, ChatAuthor, CloseCode, ErrorCode,
+        FromHostMessage, FromListenerMessage, NowPlaying, RoomClosedReason, RoomDetails,
+        ToHostMessage, ToListenerMessage,
+    },
+    registry::{HostActivity, Registry, Relay, RelayReceiver},
+    rooms::authorize_listener,
+    schedule::{Occurrence, Schedule},
+    util::db_txn,
+};
+
+// Room UUID -> the room's task, from its host connecting until it closes
+pub type LiveRooms = Arc<RwLock<HashMap<Uuid, LiveRoom>>>;
+
+/// What happens to a scheduled room while listeners wait for its host.
+#[derive(Debug, Clone, Copy)]
+pub enum LobbyEvent {
+    HostLive,
+    // the broadcast that's up next now, None if there's none anymore
+    Rescheduled(Option<Occurrence>),
+    Closed(RoomClosedReason),
+}
+
+// Room UUID -> listeners waiting for a scheduled room's host to go live
+pub type Lobbies = Arc<RwLock<HashMap<Uuid, broadcast::Sender<LobbyEvent>>>>;
+
+// only the latest few matter, the first one usually ends the wait
+const LOBBY_EVENTS: usize = 8;
+
+/// Everything about the rooms that are live, handed to whatever needs to know.
+#[derive(Clone)]
+pub struct Conns {
+    pub live_rooms: LiveRooms,
+    pub lobbies: Lobbies,
+    pub room_events: RoomEvents,
+    pub cluster: Cluster,
+}
+
+/// The registry, and the listener connections relayed to or from other instances.
+#[derive(Clone)]
+pub struct Cluster {
+    registry: Registry,
+    // relayed connection UUID -> where its messages from the other instance go
+    bridges: Arc<RwLock<HashMap<Uuid, WSSender>>>,
+}
+
+impl Cluster {
+    pub fn new(registry: Registry) -> Self {
+        Self {
+            registry,
+            bridges: Arc::default(),
+        }
+    }
+
+    // the room's host, if it's connected to another instance
+    async fn remote_host(&self, room_id: Uuid) -> Option<HostedRoom> {
+        match self.registry.room_host(room_id).await {
+            Ok(host) => host.filter(|host| host.instance_id != self.registry.instance_id()),
+            Err(e) => {
+                error!("unable to look up room host: {:#?}", e);
+                None
+            }
+        }
+    }
+
+    // the rooms live on other instances
+    async fn remote_rooms(&self) -> Vec<HostedRoom> {
+        match self.registry.room_hosts().await {
+            Ok(hosts) => hosts
+                .into_iter()
+                .filter(|host| host.instance_id != self.registry.instance_id())
+                .collect(),
+            Err(e) => {
+                error!("unable to look up room hosts: {:#?}", e);
+                vec![]
+            }
+        }
+    }
+
+    // Err if it didn't go out
+    async fn relay(&self, to: Option<Uuid>, relay: Relay) -> Result<(), ()> {
+        self.registry.send(to, relay).await.map_err(|e| {
+            error!("unable to relay: {:#?}", e);
+        })
+    }
+
+    /// Tells every other instance, e.g. about a room that changed.
+    pub async fn relay_to_all(&self, relay: Relay) {
+        let _ = self.relay(None, relay).await;
+    }
+
+    pub async fn set_room_host(&self, room_id: Uuid, reconnecting: bool) {
+        if let Err(e) = self.registry.set_room_host(room_id, reconnecting).await {
+            error!("unable to register room host: {:#?}", e);
+        }
+    }
+
+    pub async fn clear_room_host(&self, room_id: Uuid) {
+        if let Err(e) = self.registry.clear_room_host(room_id).await {
+            error!("unable to unregister room host: {:#?}", e);
+        }
+    }
 
-use super::util::db_txn;
+    // for the other instances' room listings
+    async fn update_room_hosts(&self, statuses: &[(Uuid, RoomStatus)]) {
+        let activity = statuses
+            .iter()
+            .map(|(room_id, status)| HostActivity {
+                room_id: *room_id,
+                listener_count: i32::try_from(status.listener_count).unwrap_or(i32::MAX),
+                now_playing: status
+                    .now_playing
+                    .as_ref()
+                    .and_then(|now_playing| serde_json::to_string(now_playing).ok()),
+            })
+            .collect();
+        if let Err(e) = self.registry.update_room_hosts(activity).await {
+            error!("unable to update room hosts: {:#?}", e);
+        }
+    }
+}
 
-// Room UUID -> Sender to host
-pub type HostConnections = Arc<RwLock<HashMap<Uuid, mpsc::Sender<Result<Message, warp::Error>>>>>;
+// a listener waiting for a scheduled room's host
+struct LobbyWait {
+    events: broadcast::Receiver<LobbyEvent>,
+    broadcast: Occurrence,
+}
 
-// Room UUID -> Random connection UUID -> (Sender to listener)
-pub type ListenConnections =
-    Arc<RwLock<HashMap<Uuid, HashMap<Uuid, mpsc::Sender<Result<Message, warp::Error>>>>>>;
+fn max_listeners_of(room: &Room) -> Option<usize> {
+    room.max_listeners.and_then(|max| usize::try_from(max).ok())
+}
+
+// state of a single host websocket
+struct HostSession {
+    room_id: Uuid,
+    conn_id: Uuid,
+    // took the room back from an earlier connection, so the listeners are already there
+    resumed: bool,
+    room: LiveRoom,
+    // back to the host, for replies
+    sender: WSSender,
+    // negotiated in the handshake
+    version: Option<u32>,
+    limiter: TokenBucket,
+    // for persistent bans
+    pool: PgPool,
+    // to let in whoever came early for a scheduled broadcast
+    lobbies: Lobbies,
+    cluster: Cluster,
+}
+
+// state of a single listener websocket
+struct ListenerSession {
+    room_id: Uuid,
+    id: Uuid,
+    // back to the listener, for replies
+    sender: WSSender,
+    // negotiated in the handshake, which is also when the listener is registered
+    version: Option<u32>,
+    // from the hello or the listener's account, shown next to the listener's chat messages
+    display_name: Option<String>,
+    identity: ListenerIdentity,
+    pool: PgPool,
+    limiter: TokenBucket,
+    // the room's broadcast that's on or up next as of connecting, if it has a schedule
+    scheduled: Option<Occurrence>,
+    // Some while waiting for the host to go live
+    lobby: Option<LobbyWait>,
+    // Some once the listener is in the room or in line for it
+    room: Option<LiveRoom>,
+    cluster: Cluster,
+    // the instance to hand the listener over to, once its room's host turned up there
+    relocate_to: Option<Uuid>,
+}
+
+// a listener let in to the room, before it says hello
+struct ListenerArrival {
+    room_id: Uuid,
+    id: Uuid,
+    identity: ListenerIdentity,
+    account_name: Option<String>,
+    scheduled: Option<Occurrence>,
+}
 
 #[derive(Debug, Deserialize)]
-#[serde(tag = "type")]
-enum FromHostMessage {
-    ToListener(ToListenerMessage),
-    KeepAlive,
+pub struct HostOptions {
+    // kick out the room's current host connection, e.g. one left behind by a crashed tab
+    #[serde(default)]
+    pub takeover: bool,
 }
 
 #[derive(Debug, Deserialize)]
-struct ToListenerMessage {
-    to: Uuid,
-    msg: String,
+pub struct ListenOptions {
+    // from POST /rooms/<ID>/invites
+    pub invite: Option<String>,
+    pub password: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-struct ToHostMessage {
-    from: Uuid,
-    msg: String,
+// a tab that died without closing its socket still looks connected, so ping it
+struct Heartbeat {
+    pings: time::Interval,
+    // pings sent since the other side was last heard from
+    missed: u32,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        let period = Duration::from_secs(settings().ping_interval);
+        Self {
+            pings: time::interval_at(time::Instant::now() + period, period),
+            missed: 0,
+        }
+    }
+
+    // anything coming in shows the other side is still there
+    fn alive(&mut self) {
+        self.missed = 0;
+    }
+
+    // Err if the connection should be closed with that code
+    async fn ping(&mut self, sender: &mut WSSender) -> Result<(), CloseCode> {
+        if self.missed >= settings().max_missed_pongs {
+            return Err(CloseCode::PingTimeout);
+        }
+        self.missed += 1;
+        sender
+            .send(Ok(Message::ping(Vec::new())))
+            .await
+            .map_err(|_| CloseCode::Normal)
+    }
+}
+
+// Err if the message should be rejected rather than handled
+fn check_limits(limiter: &mut TokenBucket, msg: &Message) -> Result<(), (ErrorCode, String)> {
+    if !msg.is_text() && !msg.is_binary() {
+        return Ok(());
+    }
+    let max_size = settings().max_message_size;
+    if msg.as_bytes().len() > max_size {
+        Metrics::incr(&metrics().oversized);
+        return Err((
+            ErrorCode::MessageTooLarge,
+            format!("messages can't be longer than {} bytes", max_size),
+        ));
+    }
+    if !limiter.take() {
+        Metrics::incr(&metrics().rate_limited);
+        return Err((
+            ErrorCode::RateLimited,
+            "too many messages, slow down".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+// messages over max_message_size are rejected with an error, this limit is only a backstop
+// that drops the connection
+fn limit_ws(ws: Ws) -> Ws {
+    let backstop = settings().max_message_size.saturating_mul(2);
+    ws.max_message_size(backstop).max_frame_size(backstop)
+}
+
+// the room's task, if it's live here
+async fn find_room(live_rooms: &LiveRooms, room_id: Uuid) -> Option<LiveRoom> {
+    live_rooms.read().await.get(&room_id).cloned()
+}
+
+/// Ends every live connection to the room, telling everyone still connected why.
+pub async fn close_room(live_rooms: &LiveRooms, room_id: Uuid, reason: RoomClosedReason) {
+    if let Some(room) = find_room(live_rooms, room_id).await {
+        room.ask(|reply| RoomCommand::Close { reason, reply }).await;
+    }
+}
+
+/// How a live room is doing as far as room listings go, whichever instance its host is on.
+#[derive(Debug, Clone)]
+pub struct LiveSummary {
+    pub reconnecting: bool,
+    pub now_playing: Option<NowPlaying>,
+    // admitted listeners, not counting those waiting in line
+    pub listener_count: usize,
+}
+
+/// Every live room. The ones live on other instances are as of their last refresh, see
+/// spawn_live_room_updates.
+pub async fn live_summaries(conns: &Conns) -> HashMap<Uuid, LiveSummary> {
+    let mut summaries: HashMap<Uuid, LiveSummary> = conns
+        .cluster
+        .remote_rooms()
+        .await
+        .into_iter()
+        .map(|host| {
+            let summary = LiveSummary {
+                reconnecting: host.reconnecting,
+                now_playing: host
+                    .now_playing
+                    .and_then(|now_playing| serde_json::from_str(&now_playing).ok()),
+                listener_count: usize::try_from(host.listener_count).unwrap_or(0),
+            };
+            (host.room_id, summary)
+        })
+        .collect();
+    for (room_id, room) in conns.live_rooms.read().await.iter() {
+        let status = room.status();
+        let summary = LiveSummary {
+            reconnecting: status.reconnecting,
+            now_playing: status.now_playing,
+            listener_count: status.listener_count,
+        };
+        summaries.insert(*room_id, summary);
+    }
+    summaries
+}
+
+/// Sends away the listeners waiting for the room's host.
+pub async fn close_lobby(lobbies: &Lobbies, room_id: Uuid, reason: RoomClosedReason) {
+    if let Some(lobby) = lobbies.write().await.remove(&room_id) {
+        let _ = lobby.send(LobbyEvent::Closed(reason));
+    }
+}
+
+// lets in the listeners waiting for the room's host
+async fn wake_lobby(lobbies: &Lobbies, room_id: Uuid) {
+    let lobby = lobbies.write().await.remove(&room_id);
+    if let Some(lobby) = lobby {
+        let _ = lobby.send(LobbyEvent::HostLive);
+    }
+}
+
+// drops the room's lobby once the last listener stopped waiting
+async fn leave_lobby(lobbies: &Lobbies, room_id: Uuid, lobby: LobbyWait) {
+    drop(lobby);
+    let mut lobbies = lobbies.write().await;
+    if lobbies
+        .get(&room_id)
+        .is_some_and(|lobby| lobby.receiver_count() == 0)
+    {
+        lobbies.remove(&room_id);
+    }
+}
+
+/// Tells a live room's host and listeners about its new settings, letting waiting listeners in
+/// if the listener limit went up. Listeners waiting for the host hear about a new schedule.
+pub async fn room_updated(live_rooms: &LiveRooms, lobbies: &Lobbies, room: &Room) {
+    if let Some(lobby) = lobbies.read().await.get(&room.id) {
+        let next = Schedule::of(room).and_then(|schedule| schedule.next_occurrence(Utc::now()));
+        let _ = lobby.send(LobbyEvent::Rescheduled(next));
+    }
+    if let Some(live) = find_room(live_rooms, room.id).await {
+        let update = RoomCommand::Update {
+            visibility: room.visibility,
+            capacity: max_listeners_of(room),
+            details: RoomDetails::from(room),
+        };
+        live.tell(update).await;
+    }
+}
+
+/// Bumps `last_connected` of every room with a connected host, saves the peak listener count of
+/// live sessions and tells the registry how the rooms are doing, once per ping interval rather
+/// than on every change.
+pub fn spawn_live_room_updates(pool: PgPool, live_rooms: LiveRooms, cluster: Cluster) {
+    task::spawn(async move {
+        let mut ticks = time::interval(Duration::from_secs(settings().ping_interval));
+        loop {
+            ticks.tick().await;
+            let statuses: Vec<(Uuid, RoomStatus)> = live_rooms
+                .read()
+                .await
+                .iter()
+                .map(|(room_id, room)| (*room_id, room.status()))
+                .collect();
+            let live: Vec<Uuid> = statuses
+                .iter()
+                .filter(|(_, status)| !status.reconnecting)
+                .map(|(room_id, _)| *room_id)
+                .collect();
+            let sessions: Vec<SessionStats> =
+                statuses.iter().map(|(_, status)| status.session).collect();
+            if live.is_empty() && sessions.is_empty() {
+                continue;
+            }
+            cluster.update_room_hosts(&statuses).await;
+            let update_result = db_txn(pool.clone(), false, move |db| {
+                let updated = diesel::update(rooms.filter(id.eq(any(live))))
+                    .set(last_connected.eq(Utc::now()))
+                    .execute(db)?;
+                for stats in &sessions {
+                    diesel::update(room_sessions::table.find(stats.session_id))
+                        .set(room_sessions::peak_listeners.eq(peak_of(stats)))
+                        .execute(db)?;
+                }
+                Ok(updated)
+            })
+            .await;
+            match update_result {
+                Err(e) => error!("{:#?}", e),
+                Ok(updated) => debug!("updated last_connected of {} rooms", updated),
+            }
+        }
+    });
+}
+
+/// Passes this instance's room directory changes on to the other instances, in order.
+pub fn spawn_directory_relays(cluster: Cluster, mut outbox: DirectoryOutbox) {
+    task::spawn(async move {
+        while let Some(event) = outbox.recv().await {
+            let relay = Relay::DirectoryChanged {
+                room_id: event.room_id,
+                owner_id: event.owner_id,
+                visibility: event.visibility,
+                change: event.change,
+            };
+            cluster.relay_to_all(relay).await;
+        }
+    });
+}
+
+/// Handles what other instances relay to this one, see ConnectionRegistry.
+pub fn spawn_relays(pool: PgPool, conns: Conns, mut relays: RelayReceiver) {
+    task::spawn(async move {
+        while let Some(relay) = relays.recv().await {
+            handle_relay(&pool, &conns, relay).await;
+        }
+    });
+}
+
+async fn handle_relay(pool: &PgPool, conns: &Conns, relay: Relay) {
+    let Conns {
+        live_rooms,
+        lobbies,
+        room_events,
+        cluster,
+    } = conns;
+    match relay {
+        Relay::ListenerOpened {
+            conn_id,
+            from,
+            room_id,
+            listener_id,
+            fingerprint,
+            user_id: account,
+            account_name,
+            scheduled,
+        } => {
+            // registered right away, so the frames that follow have somewhere to go
+            let (inbound, reader) = mpsc::channel(settings().buf_size);
+            cluster.bridges.write().await.insert(conn_id, inbound);
+            let arrival = ListenerArrival {
+                room_id,
+                id: listener_id,
+                identity: ListenerIdentity {
+                    fingerprint,
+                    user_id: account,
+                },
+                account_name,
+                scheduled,
+            };
+            task::spawn(relayed_listener(
+                reader,
+                conn_id,
+                from,
+                pool.clone(),
+                conns.clone(),
+                arrival,
+            ));
+        }
+        Relay::Frame { conn_id, frame } => {
+            let bridge = cluster.bridges.read().await.get(&conn_id).cloned();
+            let sent = match bridge {
+                None => Err("unknown connection"),
+                Some(mut bridge) => bridge
+                    .try_send(Ok(frame.into()))
+                    .map_err(|_| "connection not keeping up"),
+            };
+            if let Err(why) = sent {
+                debug!("dropping relayed frame (conn={}): {}", conn_id, why);
+            }
+        }
+        Relay::Closed { conn_id } => {
+            cluster.bridges.write().await.remove(&conn_id);
+        }
+        Relay::HostReplaced { room_id } => {
+            // GET /rooms/events hears the room is still live from the instance it moved to
+            if let Some(room) = find_room(live_rooms, room_id).await {
+                debug!("host moved to another instance (room={})", room_id);
+                let reason = RoomClosedReason::HostReplaced;
+                room.ask(|reply| RoomCommand::Close { reason, reply }).await;
+            }
+        }
+        Relay::HostLive { room_id } => wake_lobby(lobbies, room_id).await,
+        Relay::RoomUpdated { room_id } => {
+            let room = db_txn(pool.clone(), true, move |db| {
+                Ok(rooms.find(room_id).first::<Room>(db)?)
+            })
+            .await;
+            match room {
+                Ok(room) => room_updated(live_rooms, lobbies, &room).await,
+                Err(e) => error!("unable to load updated room: {:#?}", e),
+            }
+        }
+        Relay::RoomDeleted { room_id } => {
+            let reason = RoomClosedReason::RoomDeleted;
+            close_room(live_rooms, room_id, reason).await;
+            close_lobby(lobbies, room_id, reason).await;
+        }
+        Relay::DirectoryChanged {
+            room_id,
+            owner_id,
+            visibility: room_visibility,
+            change,
+        } => room_events.publish(DirectoryEvent {
+            room_id,
+            owner_id,
+            visibility: room_visibility,
+            change,
+        }),
+    }
+}
+
+/// The listeners the room has let in, longest there first. Only known for rooms live on this
+/// instance.
+pub async fn connected_listeners(live_rooms: &LiveRooms, room_id: Uuid) -> Vec<ConnectedListener> {
+    match find_room(live_rooms, room_id).await {
+        None => vec![],
+        Some(room) => room
+            .ask(|reply| RoomCommand::Presence { reply })
+            .await
+            .unwrap_or_default(),
+    }
+}
+
+/// The peak listener count so far of the room's live session, if it has one.
+pub async fn live_session_peak(live_rooms: &LiveRooms, room_id: Uuid) -> Option<(Uuid, i32)> {
+    find_room(live_rooms, room_id).await.map(|room| {
+        let stats = room.status().session;
+        (stats.session_id, peak_of(&stats))
+    })
 }
 
 pub async fn host_room(
     room_id: Uuid,
     host_id: Uuid,
+    opts: HostOptions,
     ws: Ws,
     pool: PgPool,
-    conns: (HostConnections, ListenConnections),
+    conns: Conns,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    // validate room is owned by host
-    let res = db_txn(pool.clone(), true, |db| {
-        let room_result: Room = rooms.find(room_id).first(db)?;
-        Ok(room_result.user_id)
+    // validate the room's owner or a co-host is hosting it
+    let room: Room = db_txn(pool.clone(), true, move |db| {
+        check_permission(db, room_id, host_id, Permission::Host, "host")
     })
     .await?;
 
-    let (host_conns, listen_conns) = conns;
-    if res != host_id {
-        Err(Rejection::from(MyError::AuthError(
-            "You are not the owner of the selected room".to_owned(),
-        )))
-    } else if host_conns.read().await.contains_key(&room_id) {
+    let live_here = find_room(&conns.live_rooms, room_id)
+        .await
+        .is_some_and(|room| !room.status().reconnecting);
+    // a host coming back to another instance starts the room over here
+    let remote = conns.cluster.remote_host(room_id).await;
+    let live_elsewhere = remote.as_ref().is_some_and(|host| !host.reconnecting);
+    if !opts.takeover && (live_here || live_elsewhere) {
         // So a ton of connection requests doesn't constantly reset connections
         debug!("Old connection exists");
         Err(Rejection::from(MyError::WSConnectionAlreadyExists))
     } else {
-        Ok(ws.on_upgrade(move |socket| {
-            host_connected(socket, host_conns, listen_conns, pool, room_id)
+        Ok(limit_ws(ws).on_upgrade(move |socket| {
+            host_connected(
+                socket,
+                pool,
+                conns,
+                room,
+                remote.map(|host| host.instance_id),
+            )
         }))
     }
 }
 
+// replaces is the instance the room's host was connected to before, if it was another one
 async fn host_connected(
     ws: WebSocket,
-    host_conns: HostConnections,
-    listen_conns: ListenConnections,
     pool: PgPool,
-    room_id: Uuid,
+    conns: Conns,
+    room: Room,
+    replaces: Option<Uuid>,
 ) {
+    let room_id = room.id;
     let (ws_writer, mut ws_reader) = ws.split();
-    let (buf_write, buf_read) = mpsc::channel(BUF_SIZE);
+    let (buf_write, buf_read) = mpsc::channel(settings().buf_size);
     task::spawn(buf_read.forward(ws_writer).map(|result| {
         if let Err(e) = result {
             error!("websocket send error: {}", e);
@@ -92,14 +631,53 @@ async fn host_connected(
         }
     }));
 
-    host_conns.write().await.insert(room_id, buf_write);
-    listen_conns
-        .write()
-        .await
-        .insert(room_id, HashMap::default());
+    let cluster = conns.cluster.clone();
+    if let Some(instance) = replaces {
+        debug!("taking room {} over from instance {}", room_id, instance);
+        let _ = cluster
+            .relay(Some(instance), Relay::HostReplaced { room_id })
+            .await;
+    }
+    let conn_id = Uuid::new_v4();
+    let arrival = HostArrival {
+        conn_id,
+        sender: buf_write.clone(),
+        owner_id: room.user_id,
+        visibility: room.visibility,
+        capacity: max_listeners_of(&room),
+    };
+    let (live, resumed) = take_room(&pool, &conns, room_id, arrival).await;
+    let mut session = HostSession {
+        room_id,
+        conn_id,
+        resumed,
+        room: live,
+        sender: buf_write,
+        version: None,
+        limiter: TokenBucket::new(settings().host_message_rate, settings().host_message_burst),
+        pool,
+        lobbies: conns.lobbies,
+        cluster,
+    };
 
     // when host sends message, we need to direct it to the correct listener
-    while let Some(result) = ws_reader.next().await {
+    let mut heartbeat = Heartbeat::new();
+    loop {
+        let result = tokio::select! {
+            result = ws_reader.next() => match result {
+                None => break,
+                Some(result) => result,
+            },
+            _ = heartbeat.pings.tick() => {
+                if let Err(code) = heartbeat.ping(&mut session.sender).await {
+                    debug!("host stopped answering pings (room={})", room_id);
+                    send_close(&mut session.sender, code).await;
+                    break;
+                }
+                continue;
+            }
+        };
+        heartbeat.alive();
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
@@ -107,99 +685,394 @@ async fn host_connected(
                 break;
             }
         };
-        handle_host_message(&listen_conns, pool.clone(), room_id, msg).await;
+        if msg.is_close() {
+            break;
+        }
+        if let Err(code) = handle_host_message(&mut session, msg).await {
+            send_close(&mut session.sender, code).await;
+            break;
+        }
     }
 
-    // host disconnected
-    listen_conns.write().await.remove(&room_id);
-    host_conns.write().await.remove(&room_id);
+    // the room waits a while in case the host comes back
+    session
+        .room
+        .tell(RoomCommand::HostDisconnected { conn_id })
+        .await;
 }
 
-async fn handle_host_message(
-    listen_conns: &ListenConnections,
-    pool: PgPool,
+async fn host_error(session: &mut HostSession, code: ErrorCode, message: String) {
+    debug!(
+        "rejecting host message (room={}): {}",
+        session.room_id, message
+    );
+    let _ = send_json(&mut session.sender, &ToHostMessage::Error { code, message }).await;
+}
+
+// false once the room's been taken over, the listeners aren't this connection's anymore
+fn is_current_host(session: &HostSession) -> bool {
+    session.room.status().host_conn == session.conn_id
+}
+
+/// A moderation command, from the host or a moderator listening in.
+enum Moderation {
+    DeleteChat(u64),
+    Mute { listener: Uuid, muted: bool },
+    Kick(Uuid),
+    Ban { listener: Uuid, persistent: bool },
+}
+
+impl Moderation {
+    // the listener it's aimed at, if any
+    fn target(&self) -> Option<Uuid> {
+        match self {
+            Moderation::DeleteChat(_) => None,
+            Moderation::Mute { listener, .. }
+            | Moderation::Kick(listener)
+            | Moderation::Ban { listener, .. } => Some(*listener),
+        }
+    }
+}
+
+async fn moderate(
+    pool: &PgPool,
+    room: &LiveRoom,
     room_id: Uuid,
-    msg: Message,
-) {
-    let raw_msg = {
-        if let Ok(s) = msg.to_str() {
-            s
-        } else {
-            return;
+    action: Moderation,
+) -> Result<(), ModerationError> {
+    let result = match action {
+        Moderation::DeleteChat(message_id) => {
+            room.ask(|reply| RoomCommand::DeleteChat { message_id, reply })
+                .await
         }
+        Moderation::Mute { listener, muted } => {
+            room.ask(|reply| RoomCommand::Mute {
+                listener,
+                muted,
+                reply,
+            })
+            .await
+        }
+        Moderation::Kick(listener) => {
+            room.ask(|reply| RoomCommand::Kick { listener, reply })
+                .await
+        }
+        Moderation::Ban {
+            listener,
+            persistent,
+        } => return ban(pool, room, room_id, listener, persistent).await,
     };
+    result.unwrap_or(Err(ModerationError::RoomClosed))
+}
 
-    match serde_json::from_str::<FromHostMessage>(raw_msg) {
+async fn ban(
+    pool: &PgPool,
+    room: &LiveRoom,
+    room_id: Uuid,
+    listener: Uuid,
+    persistent: bool,
+) -> Result<(), ModerationError> {
+    let identity = room
+        .ask(|reply| RoomCommand::Ban { listener, reply })
+        .await
+        .unwrap_or(Err(ModerationError::RoomClosed))?;
+    if !persistent || !identity.is_bannable() {
+        return Ok(());
+    }
+    save_ban(
+        pool.clone(),
+        room_id,
+        identity.fingerprint,
+        identity.user_id,
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| {
+        error!("unable to save ban: {:#?}", e);
+        ModerationError::Rejected(
+            ErrorCode::ServerError,
+            "unable to save the ban, it only lasts until the room closes".to_owned(),
+        )
+    })
+}
+
+// Err means the host's connection should be closed with that code
+async fn host_moderate(session: &mut HostSession, action: Moderation) -> Result<(), CloseCode> {
+    if !is_current_host(session) {
+        return Err(CloseCode::HostReplaced);
+    }
+    let result = moderate(&session.pool, &session.room, session.room_id, action).await;
+    match result {
+        Ok(()) => Ok(()),
+        Err(ModerationError::Rejected(code, message)) => {
+            host_error(session, code, message).await;
+            Ok(())
+        }
+        Err(ModerationError::RoomClosed) => Err(CloseCode::Normal),
+    }
+}
+
+// Err means the host's connection should be closed with that code
+async fn handle_host_message(session: &mut HostSession, msg: Message) -> Result<(), CloseCode> {
+    if let Err((code, message)) = check_limits(&mut session.limiter, &msg) {
+        host_error(session, code, message).await;
+        return Ok(());
+    }
+    let raw_msg = match msg.to_str() {
+        Ok(s) => s,
+        Err(_) => {
+            if msg.is_binary() {
+                host_error(
+                    session,
+                    ErrorCode::MalformedMessage,
+                    "binary messages are not supported".to_owned(),
+                )
+                .await;
+            }
+            return Ok(());
+        }
+    };
+
+    let parsed = match serde_json::from_str::<FromHostMessage>(raw_msg) {
         Err(e) => {
             error!("couldn't deserialize msg: {} due to {}", raw_msg, e);
+            host_error(session, ErrorCode::MalformedMessage, e.to_string()).await;
+            return Ok(());
         }
-        Ok(msg) => match msg {
-            FromHostMessage::KeepAlive => {
-                let update_result = db_txn(pool, false, |db| {
-                    let updated = diesel::update(rooms.find(room_id))
-                        .set(last_connected.eq(Utc::now()))
-                        .execute(db)?;
-                    Ok(updated == 1)
+        Ok(parsed) => parsed,
+    };
+
+    match (session.version, parsed) {
+        (_, FromHostMessage::Hello { version }) => {
+            if let Err(message) = check_version(version) {
+                host_error(session, ErrorCode::UnsupportedVersion, message).await;
+                return Err(CloseCode::UnsupportedVersion);
+            }
+            let first_hello = session.version.is_none();
+            session.version = Some(version);
+            send_json(&mut session.sender, &ToHostMessage::Welcome { version })
+                .await
+                .map_err(|_| CloseCode::Normal)?;
+            if first_hello {
+                // the listeners it missed if it's back from a reconnect, and the chat
+                let (conn_id, resumed) = (session.conn_id, session.resumed);
+                session
+                    .room
+                    .ask(|reply| RoomCommand::HostReady {
+                        conn_id,
+                        resumed,
+                        reply,
+                    })
+                    .await
+                    .unwrap_or(Err(()))
+                    .map_err(|_| CloseCode::Normal)?;
+                // the host is ready, so whoever came early for a scheduled broadcast gets in
+                wake_lobby(&session.lobbies, session.room_id).await;
+                let live = Relay::HostLive {
+                    room_id: session.room_id,
+                };
+                session.cluster.relay_to_all(live).await;
+            }
+            Ok(())
+        }
+        (None, _) => {
+            host_error(
+                session,
+                ErrorCode::HandshakeRequired,
+                "send hello first".to_owned(),
+            )
+            .await;
+            Ok(())
+        }
+        (Some(_), FromHostMessage::KeepAlive) => Ok(()),
+        (Some(_), FromHostMessage::Chat { text }) => {
+            if !is_current_host(session) {
+                return Err(CloseCode::HostReplaced);
+            }
+            let text = match check_chat_text(&text) {
+                Ok(text) => text,
+                Err(message) => {
+                    host_error(session, ErrorCode::InvalidChat, message).await;
+                    return Ok(());
+                }
+            };
+            let posted = session
+                .room
+                .ask(|reply| RoomCommand::Chat {
+                    author: ChatAuthor::Host,
+                    text,
+                    reply,
                 })
                 .await;
-                match update_result {
-                    Err(e) => {
-                        error!("{:#?}", e);
-                    }
-                    Ok(updated) if !updated => {
-                        error!("couldn't update room {}, likely not present", room_id);
-                    }
-                    _ => {}
-                }
+            match posted {
+                None => Err(CloseCode::Normal),
+                Some(_) => Ok(()),
             }
-            FromHostMessage::ToListener(to_listener) => {
-                let mut listeners = listen_conns.write().await;
-                match listeners.get_mut(&room_id) {
-                    None => {
-                        debug!("room closed: {}", room_id);
-                    }
-                    Some(room_listeners) => match room_listeners.get_mut(&to_listener.to) {
-                        None => {
-                            debug!("listener not found: {}, {:#?}", to_listener.to, to_listener);
-                        }
-                        Some(listener) => {
-                            let res = listener.send(Ok(Message::text(to_listener.msg))).await;
-                            if let Err(e) = res {
-                                error!("unable to send to listener, likely disconnected {}", e);
-                            }
-                        }
-                    },
+        }
+        (Some(_), FromHostMessage::DeleteChat { id: message_id }) => {
+            let action = Moderation::DeleteChat(message_id);
+            host_moderate(session, action).await
+        }
+        (Some(_), FromHostMessage::Mute { listener }) => {
+            let action = Moderation::Mute {
+                listener,
+                muted: true,
+            };
+            host_moderate(session, action).await
+        }
+        (Some(_), FromHostMessage::Unmute { listener }) => {
+            let action = Moderation::Mute {
+                listener,
+                muted: false,
+            };
+            host_moderate(session, action).await
+        }
+        (Some(_), FromHostMessage::Kick { listener }) => {
+            let action = Moderation::Kick(listener);
+            host_moderate(session, action).await
+        }
+        (
+            Some(_),
+            FromHostMessage::Ban {
+                listener,
+                persistent,
+            },
+        ) => {
+            let action = Moderation::Ban {
+                listener,
+                persistent,
+            };
+            host_moderate(session, action).await
+        }
+        (Some(_), FromHostMessage::NowPlaying(mut now_playing)) => {
+            if let Err(message) = now_playing.validate() {
+                host_error(session, ErrorCode::InvalidNowPlaying, message).await;
+                return Ok(());
+            }
+            now_playing.updated_at = Some(Utc::now());
+            if !is_current_host(session) {
+                return Err(CloseCode::HostReplaced);
+            }
+            let conn_id = session.conn_id;
+            session
+                .room
+                .tell(RoomCommand::NowPlaying {
+                    conn_id,
+                    now_playing,
+                })
+                .await;
+            Ok(())
+        }
+        (Some(_), FromHostMessage::Signal { to, signal }) => {
+            if !is_current_host(session) {
+                return Err(CloseCode::HostReplaced);
+            }
+            if let Err(message) = signal.validate() {
+                host_error(session, ErrorCode::InvalidSignal, message).await;
+                return Ok(());
+            }
+            let relayed = session
+                .room
+                .ask(|reply| RoomCommand::SignalListener { to, signal, reply })
+                .await;
+            let relayed = match relayed {
+                None => {
+                    debug!("room closed: {}", session.room_id);
+                    return Err(CloseCode::Normal);
+                }
+                Some(relayed) => relayed,
+            };
+            match relayed {
+                Relayed::Sent => {}
+                Relayed::Full => {
+                    // the listener isn't reading, it's the one at fault rather than the host
+                    Metrics::incr(&metrics().backpressure_dropped);
+                    host_error(
+                        session,
+                        ErrorCode::PeerBusy,
+                        format!("listener {} isn't keeping up, message dropped", to),
+                    )
+                    .await;
+                }
+                Relayed::Gone => {
+                    host_error(
+                        session,
+                        ErrorCode::UnknownListener,
+                        format!("listener not found: {}", to),
+                    )
+                    .await;
                 }
             }
-        },
+            Ok(())
+        }
     }
 }
 
 pub async fn listen_room(
     room_id: Uuid,
+    opts: ListenOptions,
+    listener_user_id: Option<Uuid>,
+    ip: Option<IpAddr>,
     ws: Ws,
     pool: PgPool,
-    conns: (HostConnections, ListenConnections),
+    conns: Conns,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    // validate room exists
-    db_txn(pool, true, |db| {
-        let _: Room = rooms.find(room_id).first(db)?;
-        Ok(())
+    // validate room exists and the listener is let in
+    let (room, account_name) = db_txn(pool.clone(), true, move |db| {
+        let room: Room = rooms.find(room_id).first(db)?;
+        let account_name = match listener_user_id {
+            None => None,
+            Some(user) => Some(
+                users::table
+                    .find(user)
+                    .select(users::display_name)
+                    .first::<String>(db)?,
+            ),
+        };
+        Ok((room, account_name))
     })
     .await?;
+    let identity = ListenerIdentity {
+        fingerprint: ip.map(ban_fingerprint),
+        user_id: listener_user_id,
+    };
+    let banned_for_now = match find_room(&conns.live_rooms, room_id).await {
+        None => false,
+        Some(room) => {
+            let identity = identity.clone();
+            room.ask(|reply| RoomCommand::IsBanned { identity, reply })
+                .await
+                .unwrap_or_default()
+        }
+    };
+    let banned = banned_for_now
+        || is_banned(
+            pool.clone(),
+            room_id,
+            identity.fingerprint.clone(),
+            identity.user_id,
+        )
+        .await?;
+    if banned {
+        return Err(Rejection::from(MyError::AuthError(
+            "You are banned from this room".to_owned(),
+        )));
+    }
+    authorize_listener(pool.clone(), &room, &opts, listener_user_id).await?;
+    let arrival = ListenerArrival {
+        room_id,
+        id: Uuid::new_v4(),
+        identity,
+        account_name,
+        scheduled: Schedule::of(&room).and_then(|schedule| schedule.next_occurrence(Utc::now())),
+    };
 
-    let (host_conns, listen_conns) = conns;
-    Ok(ws.on_upgrade(move |socket| listen_connected(socket, host_conns, listen_conns, room_id)))
+    Ok(limit_ws(ws).on_upgrade(move |socket| listen_connected(socket, pool, conns, arrival)))
 }
 
-async fn listen_connected(
-    ws: WebSocket,
-    host_conns: HostConnections,
-    listen_conns: ListenConnections,
-    room_id: Uuid,
-) {
-    let (ws_writer, mut ws_reader) = ws.split();
-    let (buf_write, buf_read) = mpsc::channel(BUF_SIZE);
+async fn listen_connected(ws: WebSocket, pool: PgPool, conns: Conns, arrival: ListenerArrival) {
+    let (ws_writer, ws_reader) = ws.split();
+    let (buf_write, buf_read) = mpsc::channel(settings().buf_size);
     task::spawn(buf_read.forward(ws_writer).map(|result| {
         if let Err(e) = result {
             error!("websocket read error: {}", e);
@@ -207,17 +1080,197 @@ async fn listen_connected(
             debug!("finished flushing listener sender");
         }
     }));
+    listener_session(ws_reader, buf_write, pool, conns, arrival).await;
+}
 
-    let my_uuid = Uuid::new_v4();
-    match listen_conns.write().await.get_mut(&room_id) {
-        Some(listeners) => listeners.insert(my_uuid, buf_write),
-        None => {
-            error!("host probably disconnected");
-            return;
+// a listener connected to another instance, for a room whose host is on this one. Its messages
+// come in on the reader
+async fn relayed_listener(
+    reader: mpsc::Receiver<Result<Message, warp::Error>>,
+    conn_id: Uuid,
+    from: Uuid,
+    pool: PgPool,
+    conns: Conns,
+    arrival: ListenerArrival,
+) {
+    let cluster = conns.cluster.clone();
+    let (sender, mut outgoing) = mpsc::channel::<Result<Message, warp::Error>>(settings().buf_size);
+    let relaying = cluster.clone();
+    task::spawn(async move {
+        while let Some(Ok(msg)) = outgoing.next().await {
+            let frame = Relay::Frame {
+                conn_id,
+                frame: msg.into(),
+            };
+            let _ = relaying.relay(Some(from), frame).await;
         }
+        let _ = relaying.relay(Some(from), Relay::Closed { conn_id }).await;
+    });
+    listener_session(reader, sender, pool, conns, arrival).await;
+    cluster.bridges.write().await.remove(&conn_id);
+}
+
+// hands the listener over to the instance its room's host is on, passing messages back and
+// forth until either side is done. The hello goes first if the listener already said it here
+async fn relay_listener<S>(
+    cluster: &Cluster,
+    owner: Uuid,
+    mut reader: S,
+    mut sender: WSSender,
+    arrival: ListenerArrival,
+    hello: Option<Message>,
+) where
+    S: Stream<Item = Result<Message, warp::Error>> + Unpin,
+{
+    let conn_id = Uuid::new_v4();
+    let listener_id = arrival.id;
+    let (inbound, mut from_owner) = mpsc::channel(settings().buf_size);
+    cluster.bridges.write().await.insert(conn_id, inbound);
+    let opened = Relay::ListenerOpened {
+        conn_id,
+        from: cluster.registry.instance_id(),
+        room_id: arrival.room_id,
+        listener_id,
+        fingerprint: arrival.identity.fingerprint,
+        user_id: arrival.identity.user_id,
+        account_name: arrival.account_name,
+        scheduled: arrival.scheduled,
     };
+    let mut relayed = cluster.relay(Some(owner), opened).await;
+    if let (Ok(()), Some(hello)) = (relayed, hello) {
+        let frame = Relay::Frame {
+            conn_id,
+            frame: hello.into(),
+        };
+        relayed = cluster.relay(Some(owner), frame).await;
+    }
+    if relayed.is_err() {
+        cluster.bridges.write().await.remove(&conn_id);
+        send_close(&mut sender, CloseCode::HostUnavailable).await;
+        return;
+    }
+    debug!("relaying listener {} to instance {}", listener_id, owner);
+
+    // the owner pings the listener, so it's gone if that stops
+    let period = Duration::from_secs(settings().ping_interval);
+    let mut checks = time::interval_at(time::Instant::now() + period, period);
+    let mut silent = 0;
+    let mut owner_done = false;
+    loop {
+        tokio::select! {
+            result = reader.next() => match result {
+                Some(Ok(msg)) => {
+                    let closing = msg.is_close();
+                    let frame = Relay::Frame {
+                        conn_id,
+                        frame: msg.into(),
+                    };
+                    if cluster.relay(Some(owner), frame).await.is_err() || closing {
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    error!("websocket receive error (relayed listener {}): {}", listener_id, e);
+                    break;
+                }
+                None => break,
+            },
+            msg = from_owner.next() => match msg {
+                None => {
+                    owner_done = true;
+                    break;
+                }
+                Some(msg) => {
+                    silent = 0;
+                    if sender.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            },
+            _ = checks.tick() => {
+                silent += 1;
+                if silent > settings().max_missed_pongs + 1 {
+                    debug!("instance {} stopped relaying listener {}", owner, listener_id);
+                    send_close(&mut sender, CloseCode::HostUnavailable).await;
+                    break;
+                }
+            }
+        }
+    }
+    cluster.bridges.write().await.remove(&conn_id);
+    if !owner_done {
+        let _ = cluster.relay(Some(owner), Relay::Closed { conn_id }).await;
+    }
+}
 
-    while let Some(result) = ws_reader.next().await {
+async fn listener_session<S>(
+    mut reader: S,
+    sender: WSSender,
+    pool: PgPool,
+    conns: Conns,
+    arrival: ListenerArrival,
+) where
+    S: Stream<Item = Result<Message, warp::Error>> + Unpin,
+{
+    let Conns {
+        live_rooms,
+        lobbies,
+        cluster,
+        ..
+    } = conns;
+    let room_id = arrival.room_id;
+    if let Some(host) = cluster.remote_host(room_id).await {
+        relay_listener(&cluster, host.instance_id, reader, sender, arrival, None).await;
+        return;
+    }
+    let mut session = ListenerSession {
+        room_id,
+        id: arrival.id,
+        sender,
+        version: None,
+        display_name: arrival.account_name,
+        identity: arrival.identity,
+        pool,
+        limiter: TokenBucket::new(
+            settings().listener_message_rate,
+            settings().listener_message_burst,
+        ),
+        scheduled: arrival.scheduled,
+        lobby: None,
+        room: None,
+        cluster,
+        relocate_to: None,
+    };
+
+    let mut heartbeat = Heartbeat::new();
+    loop {
+        let result = tokio::select! {
+            result = reader.next() => match result {
+                None => break,
+                Some(result) => result,
+            },
+            _ = heartbeat.pings.tick() => {
+                if let Err(code) = heartbeat.ping(&mut session.sender).await {
+                    debug!("listener stopped answering pings ({})", session.id);
+                    send_close(&mut session.sender, code).await;
+                    break;
+                }
+                continue;
+            }
+            event = wait_in_lobby(&mut session.lobby) => {
+                let result = handle_lobby_event(&live_rooms, &lobbies, &mut session, event).await;
+                if let Err(code) = result {
+                    debug!("closing listener connection: {:?}", code);
+                    send_close(&mut session.sender, code).await;
+                    break;
+                }
+                if session.relocate_to.is_some() {
+                    break;
+                }
+                continue;
+            }
+        };
+        heartbeat.alive();
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
@@ -228,65 +1281,603 @@ async fn listen_connected(
                 break;
             }
         };
-        if let Err(_) = handle_listen_message(&host_conns, msg, &my_uuid, &room_id).await {
-            error!("error while handling listen message");
+        if msg.is_close() {
+            break;
+        }
+        if let Err(code) = handle_listen_message(&live_rooms, &lobbies, &mut session, msg).await {
+            debug!("closing listener connection: {:?}", code);
+            send_close(&mut session.sender, code).await;
+            break;
+        }
+        if session.relocate_to.is_some() {
             break;
         }
     }
 
+    if let (Some(owner), Some(version)) = (session.relocate_to, session.version) {
+        // the owner takes it from the hello, which it answers with a welcome as if it's repeated
+        let hello = json!({
+            "type": "hello",
+            "version": version,
+            "display_name": session.display_name,
+        });
+        let arrival = ListenerArrival {
+            room_id,
+            id: session.id,
+            identity: session.identity,
+            account_name: session.display_name,
+            scheduled: session.scheduled,
+        };
+        let hello = Some(Message::text(hello.to_string()));
+        relay_listener(
+            &session.cluster,
+            owner,
+            reader,
+            session.sender,
+            arrival,
+            hello,
+        )
+        .await;
+        return;
+    }
+
     // listener disconnected
-    let mut listeners = listen_conns.write().await;
-    match listeners.get_mut(&room_id) {
+    if let Some(lobby) = session.lobby.take() {
+        leave_lobby(&lobbies, room_id, lobby).await;
+        return;
+    }
+    match &session.room {
+        None => debug!("listener never got in ({})", session.id),
+        Some(room) => room.tell(RoomCommand::Left { id: session.id }).await,
+    }
+}
+
+// resolves with what happened to the room the listener waits for, None if the broadcast ended
+// without the host. Never resolves for listeners that aren't waiting
+async fn wait_in_lobby(lobby: &mut Option<LobbyWait>) -> Option<LobbyEvent> {
+    let lobby = match lobby {
+        None => return future::pending().await,
+        Some(lobby) => lobby,
+    };
+    loop {
+        let over_in = (lobby.broadcast.end - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        tokio::select! {
+            event = lobby.events.recv() => match event {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(_)) => continue,
+                // the lobby only goes away once the host is live or the room closed
+                Err(RecvError::Closed) => return Some(LobbyEvent::HostLive),
+            },
+            _ = time::delay_for(over_in) => return None,
+        }
+    }
+}
+
+// Err means the listener's connection should be closed with that code
+async fn handle_lobby_event(
+    live_rooms: &LiveRooms,
+    lobbies: &Lobbies,
+    session: &mut ListenerSession,
+    event: Option<LobbyEvent>,
+) -> Result<(), CloseCode> {
+    match event {
+        None => {
+            listener_error(
+                session,
+                ErrorCode::HostUnavailable,
+                "the host didn't show up for the scheduled broadcast".to_owned(),
+            )
+            .await;
+            Err(CloseCode::HostUnavailable)
+        }
+        Some(LobbyEvent::HostLive) => {
+            if let Some(lobby) = session.lobby.take() {
+                leave_lobby(lobbies, session.room_id, lobby).await;
+            }
+            let placement = place_listener(live_rooms, session).await;
+            if let Placement::NoHost = placement {
+                // the host went live on another instance
+                if let Some(host) = session.cluster.remote_host(session.room_id).await {
+                    session.relocate_to = Some(host.instance_id);
+                    return Ok(());
+                }
+            }
+            finish_placement(session, placement).await
+        }
+        Some(LobbyEvent::Rescheduled(Some(broadcast))) if broadcast.end > Utc::now() => {
+            match &mut session.lobby {
+                Some(lobby) if lobby.broadcast != broadcast => lobby.broadcast = broadcast,
+                _ => return Ok(()),
+            }
+            send_json(
+                &mut session.sender,
+                &ToListenerMessage::WaitingForHost { broadcast },
+            )
+            .await
+            .map_err(|_| CloseCode::Normal)
+        }
+        Some(LobbyEvent::Rescheduled(_)) => {
+            listener_error(
+                session,
+                ErrorCode::HostUnavailable,
+                "the room has no broadcast scheduled anymore".to_owned(),
+            )
+            .await;
+            Err(CloseCode::HostUnavailable)
+        }
+        Some(LobbyEvent::Closed(reason)) => {
+            let _ = send_json(
+                &mut session.sender,
+                &ToListenerMessage::RoomClosed { reason },
+            )
+            .await;
+            Err(reason.close_code())
+        }
+    }
+}
+
+// puts the listener in the room, or in line for it
+async fn place_listener(live_rooms: &LiveRooms, session: &mut ListenerSession) -> Placement {
+    let room = match find_room(live_rooms, session.room_id).await {
+        None => {
+            debug!("host probably disconnected");
+            return Placement::NoHost;
+        }
+        Some(room) => room,
+    };
+    let placed = room
+        .ask(|reply| RoomCommand::Place {
+            id: session.id,
+            display_name: session.display_name.clone(),
+            identity: session.identity.clone(),
+            sender: session.sender.clone(),
+            reply,
+        })
+        .await;
+    match placed {
+        // closed in the meantime
+        None => Placement::NoHost,
+        Some(placement) => {
+            if let Placement::Admitted { .. } | Placement::Queued(_) = placement {
+                session.room = Some(room);
+            }
+            placement
+        }
+    }
+}
+
+// tells the listener where it ended up, Err if it was turned away. Welcomed listeners that are
+// in now get what's playing and the chat, and the host hears about them
+async fn finish_placement(
+    session: &mut ListenerSession,
+    placement: Placement,
+) -> Result<(), CloseCode> {
+    let (queue_position, messages, muted) = match placement {
+        Placement::NoHost => {
+            listener_error(
+                session,
+                ErrorCode::HostUnavailable,
+                "the host is not connected".to_owned(),
+            )
+            .await;
+            return Err(CloseCode::HostUnavailable);
+        }
+        Placement::Full => {
+            listener_error(session, ErrorCode::RoomFull, "the room is full".to_owned()).await;
+            return Err(CloseCode::RoomFull);
+        }
+        Placement::Banned => return Err(CloseCode::Banned),
+        Placement::Queued(position) => (Some(position), vec![], false),
+        Placement::Admitted { scrollback, muted } => (None, scrollback, muted),
+    };
+    let now_playing = session
+        .room
+        .as_ref()
+        .and_then(|room| room.status().now_playing);
+    if let Some(now_playing) = now_playing {
+        send_json(
+            &mut session.sender,
+            &ToListenerMessage::NowPlaying(now_playing),
+        )
+        .await
+        .map_err(|_| CloseCode::Normal)?;
+    }
+    if let Some(position) = queue_position {
+        return send_json(&mut session.sender, &ToListenerMessage::Queued { position })
+            .await
+            .map_err(|_| CloseCode::Normal);
+    }
+    send_json(&mut session.sender, &ToListenerMessage::Admitted)
+        .await
+        .map_err(|_| CloseCode::Normal)?;
+    send_json(
+        &mut session.sender,
+        &ToListenerMessage::ChatScrollback { messages },
+    )
+    .await
+    .map_err(|_| CloseCode::Normal)?;
+    if muted {
+        send_json(
+            &mut session.sender,
+            &ToListenerMessage::ChatMuted { muted: true },
+        )
+        .await
+        .map_err(|_| CloseCode::Normal)?;
+    }
+    if let Some(room) = &session.room {
+        room.tell(RoomCommand::Joined { id: session.id }).await;
+    }
+    Ok(())
+}
+
+async fn listener_error(session: &mut ListenerSession, code: ErrorCode, message: String) {
+    debug!("rejecting listener message ({}): {}", session.id, message);
+    let _ = send_json(
+        &mut session.sender,
+        &ToListenerMessage::Error { code, message },
+    )
+    .await;
+}
+
+// moderators can't act on each other or on the room's owner and co-hosts, that's the host's call
+async fn check_moderator(
+    room: &LiveRoom,
+    session: &ListenerSession,
+    action: &Moderation,
+) -> Result<(), ModerationError> {
+    let not_allowed =
+        |message: &str| ModerationError::Rejected(ErrorCode::NotAllowed, message.to_owned());
+    let server_error = |e: MyError| {
+        error!("unable to check room permissions: {:#?}", e);
+        ModerationError::Rejected(
+            ErrorCode::ServerError,
+            "unable to check room permissions".to_owned(),
+        )
+    };
+    let user = session
+        .identity
+        .user_id
+        .ok_or_else(|| not_allowed("only the room's moderators can do that"))?;
+    let allowed = has_permission(
+        session.pool.clone(),
+        session.room_id,
+        user,
+        Permission::Moderate,
+    )
+    .await
+    .map_err(server_error)?;
+    if !allowed {
+        return Err(not_allowed("only the room's moderators can do that"));
+    }
+    let target_user = match action.target() {
+        None => return Ok(()),
+        Some(listener) => room
+            .ask(|reply| RoomCommand::Identify { listener, reply })
+            .await
+            .flatten()
+            .and_then(|identity| identity.user_id),
+    };
+    if let Some(target_user) = target_user {
+        let is_member = has_permission(
+            session.pool.clone(),
+            session.room_id,
+            target_user,
+            Permission::Moderate,
+        )
+        .await
+        .map_err(server_error)?;
+        if is_member {
+            return Err(not_allowed("only the host can moderate the room's members"));
+        }
+    }
+    Ok(())
+}
+
+// Err means the listener's connection should be closed with that code
+async fn listener_moderate(
+    session: &mut ListenerSession,
+    action: Moderation,
+) -> Result<(), CloseCode> {
+    // moderators waiting for the host have no room to moderate yet
+    let room = match session.room.clone() {
         None => {
-            debug!("room closed: {}", room_id);
+            listener_error(
+                session,
+                ErrorCode::NotLive,
+                "the room isn't live yet".to_owned(),
+            )
+            .await;
+            return Ok(());
         }
-        Some(room_listeners) => {
-            room_listeners.remove(&my_uuid);
-            debug!("number of listeners: {}", room_listeners.len());
+        Some(room) => room,
+    };
+    let result = match check_moderator(&room, session, &action).await {
+        Ok(()) => moderate(&session.pool, &room, session.room_id, action).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(ModerationError::Rejected(code, message)) => {
+            listener_error(session, code, message).await;
+            Ok(())
         }
+        Err(ModerationError::RoomClosed) => Err(CloseCode::Normal),
     }
 }
 
+// Err means the listener's connection should be closed with that code
 async fn handle_listen_message(
-    host_conns: &HostConnections,
+    live_rooms: &LiveRooms,
+    lobbies: &Lobbies,
+    session: &mut ListenerSession,
     msg: Message,
-    from_id: &Uuid,
-    room_id: &Uuid,
-) -> Result<(), ()> {
-    let msg_str = {
-        if let Ok(s) = msg.to_str() {
-            s
-        } else {
-            return Err(());
+) -> Result<(), CloseCode> {
+    if let Err((code, message)) = check_limits(&mut session.limiter, &msg) {
+        listener_error(session, code, message).await;
+        return Ok(());
+    }
+    let msg_str = match msg.to_str() {
+        Ok(s) => s,
+        Err(_) => {
+            if msg.is_binary() {
+                listener_error(
+                    session,
+                    ErrorCode::MalformedMessage,
+                    "binary messages are not supported".to_owned(),
+                )
+                .await;
+            }
+            return Ok(());
         }
     };
 
-    debug!("received listener message: {} from {}", msg_str, from_id);
-    if msg_str.to_string().is_empty() {
+    debug!("received listener message: {} from {}", msg_str, session.id);
+    if msg_str.is_empty() {
         return Ok(());
     }
 
-    let to_send = ToHostMessage {
-        from: from_id.clone(),
-        msg: msg_str.to_string(),
+    let parsed = match serde_json::from_str::<FromListenerMessage>(msg_str) {
+        Err(e) => {
+            listener_error(session, ErrorCode::MalformedMessage, e.to_string()).await;
+            return Ok(());
+        }
+        Ok(parsed) => parsed,
     };
-    let json_to_send = serde_json::to_string(&to_send).map_err(|_| ())?;
 
-    let mut hosts = host_conns.write().await;
-    let dest_result = hosts.get_mut(room_id);
-    match dest_result {
-        None => {
-            debug!("host not found: {}", room_id);
-            Err(())
-        }
-        Some(dest) => {
-            let res = dest.send(Ok(Message::text(json_to_send))).await;
-            if let Err(e) = res {
-                error!("unable to send to host, likely disconnected {}", e);
-                Err(())
-            } else {
-                debug!("successfully sent mesage to host");
-                Ok(())
+    match (session.version, parsed) {
+        (Some(version), FromListenerMessage::Hello { .. }) => {
+            // already registered, so just repeat the welcome
+            let welcome = ToListenerMessage::Welcome {
+                version,
+                id: session.id,
+            };
+            send_json(&mut session.sender, &welcome)
+                .await
+                .map_err(|_| CloseCode::Normal)
+        }
+        (
+            None,
+            FromListenerMessage::Hello {
+                version,
+                display_name,
+            },
+        ) => {
+            if let Err(message) = check_version(version) {
+                listener_error(session, ErrorCode::UnsupportedVersion, message).await;
+                return Err(CloseCode::UnsupportedVersion);
+            }
+            // logged in listeners go by their account's name
+            let display_name = match check_display_name(display_name) {
+                _ if session.identity.user_id.is_some() => session.display_name.clone(),
+                Ok(display_name) => display_name,
+                Err(message) => {
+                    listener_error(session, ErrorCode::InvalidHello, message).await;
+                    return Ok(());
+                }
+            };
+            session.display_name = display_name;
+            let mut placement = place_listener(live_rooms, session).await;
+            if let Placement::NoHost = placement {
+                // the host connected to another instance since the listener connected here
+                if let Some(host) = session.cluster.remote_host(session.room_id).await {
+                    session.version = Some(version);
+                    session.relocate_to = Some(host.instance_id);
+                    return Ok(());
+                }
+            }
+            let waits_for = session
+                .scheduled
+                .filter(|broadcast| broadcast.end > Utc::now());
+            if let (Placement::NoHost, Some(broadcast)) = (&placement, waits_for) {
+                let events = lobbies
+                    .write()
+                    .await
+                    .entry(session.room_id)
+                    .or_insert_with(|| broadcast::channel(LOBBY_EVENTS).0)
+                    .subscribe();
+                let lobby = LobbyWait { events, broadcast };
+                // the host may have gone live in the meantime
+                placement = place_listener(live_rooms, session).await;
+                match placement {
+                    Placement::NoHost => session.lobby = Some(lobby),
+                    _ => leave_lobby(lobbies, session.room_id, lobby).await,
+                }
+            }
+            if session.lobby.is_none() {
+                if let Placement::NoHost | Placement::Full | Placement::Banned = placement {
+                    // turned away before the welcome
+                    return finish_placement(session, placement).await;
+                }
+            }
+
+            session.version = Some(version);
+            let welcome = ToListenerMessage::Welcome {
+                version,
+                id: session.id,
+            };
+            send_json(&mut session.sender, &welcome)
+                .await
+                .map_err(|_| CloseCode::Normal)?;
+            if let Some(lobby) = &session.lobby {
+                let broadcast = lobby.broadcast;
+                return send_json(
+                    &mut session.sender,
+                    &ToListenerMessage::WaitingForHost { broadcast },
+                )
+                .await
+                .map_err(|_| CloseCode::Normal);
+            }
+            finish_placement(session, placement).await
+        }
+        (None, _) => {
+            listener_error(
+                session,
+                ErrorCode::HandshakeRequired,
+                "send hello first".to_owned(),
+            )
+            .await;
+            Ok(())
+        }
+        (Some(_), FromListenerMessage::Chat { text }) => {
+            let text = match check_chat_text(&text) {
+                Ok(text) => text,
+                Err(message) => {
+                    listener_error(session, ErrorCode::InvalidChat, message).await;
+                    return Ok(());
+                }
+            };
+            let author = ChatAuthor::Listener {
+                id: session.id,
+                display_name: session.display_name.clone(),
+                user_id: session.identity.user_id,
+            };
+            let posted = match &session.room {
+                None => None,
+                Some(room) => {
+                    room.ask(|reply| RoomCommand::Chat {
+                        author,
+                        text,
+                        reply,
+                    })
+                    .await
+                }
+            };
+            let not_admitted = (
+                ErrorCode::NotAdmitted,
+                "still waiting for a spot in the room",
+            );
+            if let Err((code, message)) = posted.unwrap_or(Err(not_admitted)) {
+                listener_error(session, code, message.to_owned()).await;
+            }
+            Ok(())
+        }
+        (Some(_), FromListenerMessage::DeleteChat { id: message_id }) => {
+            let action = Moderation::DeleteChat(message_id);
+            listener_moderate(session, action).await
+        }
+        (Some(_), FromListenerMessage::Mute { listener }) => {
+            let action = Moderation::Mute {
+                listener,
+                muted: true,
+            };
+            listener_moderate(session, action).await
+        }
+        (Some(_), FromListenerMessage::Unmute { listener }) => {
+            let action = Moderation::Mute {
+                listener,
+                muted: false,
+            };
+            listener_moderate(session, action).await
+        }
+        (Some(_), FromListenerMessage::Kick { listener }) => {
+            let action = Moderation::Kick(listener);
+            listener_moderate(session, action).await
+        }
+        (
+            Some(_),
+            FromListenerMessage::Ban {
+                listener,
+                persistent,
+            },
+        ) => {
+            let action = Moderation::Ban {
+                listener,
+                persistent,
+            };
+            listener_moderate(session, action).await
+        }
+        (Some(_), FromListenerMessage::Signal { signal }) => {
+            if let Err(message) = signal.validate() {
+                listener_error(session, ErrorCode::InvalidSignal, message).await;
+                return Ok(());
+            }
+            let from = session.id;
+            let relayed = match &session.room {
+                // not even in line
+                None => Some(None),
+                Some(room) => {
+                    room.ask(|reply| RoomCommand::SignalHost {
+                        from,
+                        signal,
+                        reply,
+                    })
+                    .await
+                }
+            };
+            let relayed = match relayed {
+                None => {
+                    listener_error(
+                        session,
+                        ErrorCode::HostUnavailable,
+                        "the host is not connected".to_owned(),
+                    )
+                    .await;
+                    return Err(CloseCode::HostUnavailable);
+                }
+                Some(None) => {
+                    listener_error(
+                        session,
+                        ErrorCode::NotAdmitted,
+                        "still waiting for a spot in the room".to_owned(),
+                    )
+                    .await;
+                    return Ok(());
+                }
+                Some(Some(relayed)) => relayed,
+            };
+            match relayed {
+                Relayed::Sent => {
+                    debug!("successfully sent mesage to host");
+                    Ok(())
+                }
+                Relayed::Full => match settings().backpressure {
+                    BackpressurePolicy::Drop => {
+                        Metrics::incr(&metrics().backpressure_dropped);
+                        listener_error(
+                            session,
+                            ErrorCode::PeerBusy,
+                            "the host isn't keeping up, message dropped".to_owned(),
+                        )
+                        .await;
+                        Ok(())
+                    }
+                    BackpressurePolicy::Disconnect => {
+                        Metrics::incr(&metrics().backpressure_disconnected);
+                        Err(CloseCode::Backpressure)
+                    }
+                },
+                Relayed::Gone => {
+                    // the room is still there, so the listener can wait for the host
+                    listener_error(
+                        session,
+                        ErrorCode::HostUnavailable,
+                        "the host is reconnecting".to_owned(),
+                    )
+                    .await;
+                    Ok(())
+                }
             }
         }
     }
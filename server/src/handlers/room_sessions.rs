@@ -0,0 +1,59 @@
+use std::cmp::min;
+
+use diesel::prelude::*;
+use uuid::Uuid;
+use warp::reply::json;
+
+use crate::db::{Permission, PgPool, RoomSession};
+use crate::schema::room_sessions;
+
+use super::{
+    members::check_permission,
+    room_conns::{live_session_peak, LiveRooms},
+    util::db_txn,
+    ListOptions,
+};
+
+const SESSION_LIMIT_MAX: u8 = 100;
+
+// the times the room was live, latest first
+pub async fn list_room_sessions(
+    room_id: Uuid,
+    opts: ListOptions,
+    pool: PgPool,
+    req_user_id: Uuid,
+    live_rooms: LiveRooms,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let offset = i64::from(opts.offset.unwrap_or(0));
+    let limit = i64::from(min(
+        opts.limit.unwrap_or(SESSION_LIMIT_MAX),
+        SESSION_LIMIT_MAX,
+    ));
+    let mut sessions = db_txn(pool, true, move |db| {
+        check_permission(
+            db,
+            room_id,
+            req_user_id,
+            Permission::Moderate,
+            "list sessions",
+        )?;
+        let sessions: Vec<RoomSession> = room_sessions::table
+            .filter(room_sessions::room_id.eq(room_id))
+            .order((room_sessions::started_at.desc(), room_sessions::id))
+            .offset(offset)
+            .limit(limit)
+            .load(db)?;
+        Ok(sessions)
+    })
+    .await?;
+    // the saved peak of a live session lags behind by up to a ping interval
+    if let Some((session_id, peak)) = live_session_peak(&live_rooms, room_id).await {
+        for session in sessions
+            .iter_mut()
+            .filter(|session| session.id == session_id)
+        {
+            session.peak_listeners = peak;
+        }
+    }
+    Ok(json(&sessions))
+}
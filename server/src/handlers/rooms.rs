@@ -1,7 +1,15 @@
 use chrono::{DateTime, Utc};
-use diesel::{delete, dsl::any, insert_into, prelude::*};
+use diesel::{
+    delete, dsl,
+    dsl::any,
+    insert_into,
+    pg::Pg,
+    prelude::*,
+    sql_types::{Bool, Timestamptz},
+    update,
+};
 use futures::stream::{self, StreamExt};
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 use warp::{
     hyper::StatusCode,
@@ -9,21 +17,86 @@ use warp::{
     reply::{json, with_status},
 };
 
-use std::{cmp::min, collections::HashMap};
+use std::{cmp::Reverse, collections::HashMap};
 
 use crate::schema;
 use crate::schema::{rooms::dsl::*, users::dsl::*};
 use crate::{
-    db::{PgPool, Room},
+    auth::{check_invite_token, get_invite_token, hash_password, verify_password, Verified},
+    db::{Permission, PgPool, Role, Room, RoomChanges, RoomMember, Visibility},
     errors::MyError,
+    settings::settings,
 };
 
-use super::{util::db_txn, HostConnections};
+use super::{
+    cursor::RoomCursor,
+    directory::{DirectoryChange, RoomEvents},
+    live_room::ConnectedListener,
+    members::{check_permission, room_roles},
+    protocol::{NowPlaying, PlayStatus, RoomClosedReason, RoomDetails},
+    registry::Relay,
+    room_conns::{
+        close_lobby, close_room, connected_listeners, live_summaries, room_updated, Conns,
+        ListenOptions, LiveRooms, LiveSummary,
+    },
+    schedule::{Occurrence, Schedule},
+    util::db_txn,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct ListOptions {
-    pub offset: Option<u8>,
+    pub offset: Option<u32>,
+    pub limit: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageOptions {
+    // next_cursor of the page before, None for the first page
+    pub cursor: Option<String>,
+    pub limit: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchOptions {
+    pub cursor: Option<String>,
     pub limit: Option<u8>,
+    // found anywhere in the room's name, ignoring case
+    pub q: Option<String>,
+    // true for rooms with a host, false for the others
+    pub live: Option<bool>,
+    // the owner's display name
+    pub owner: Option<String>,
+    // comma separated, rooms have to have all of them
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub sort: RoomSort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomSort {
+    /// Newest first
+    #[default]
+    CreatedAt,
+    /// Most recently hosted first, never hosted last
+    LastConnected,
+    /// Most listeners first, then the rest by last_connected
+    Listeners,
+}
+
+impl RoomSort {
+    // whether the cursor came from a listing sorted this way
+    fn continues(self, cursor: &RoomCursor) -> bool {
+        matches!(
+            (self, cursor),
+            (RoomSort::CreatedAt, RoomCursor::Created { .. })
+                | (RoomSort::LastConnected, RoomCursor::Connected { .. })
+                | (
+                    RoomSort::Listeners,
+                    RoomCursor::Listened { .. } | RoomCursor::Connected { .. }
+                )
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -32,6 +105,9 @@ pub enum HostStatus {
     Unknown = 0,
     Stopped = 1,
     Playing = 2,
+    // the host dropped and the room is waiting for it to come back
+    Reconnecting = 3,
+    Paused = 4,
 }
 
 impl Serialize for HostStatus {
@@ -43,6 +119,8 @@ impl Serialize for HostStatus {
             HostStatus::Unknown => "unknown",
             HostStatus::Playing => "playing",
             HostStatus::Stopped => "stopped",
+            HostStatus::Reconnecting => "reconnecting",
+            HostStatus::Paused => "paused",
         })
     }
 }
@@ -53,6 +131,8 @@ impl From<i16> for HostStatus {
             x if x == HostStatus::Unknown as i16 => HostStatus::Unknown,
             x if x == HostStatus::Playing as i16 => HostStatus::Playing,
             x if x == HostStatus::Stopped as i16 => HostStatus::Stopped,
+            x if x == HostStatus::Reconnecting as i16 => HostStatus::Reconnecting,
+            x if x == HostStatus::Paused as i16 => HostStatus::Paused,
             x => {
                 error!("attempting to convert invalid i16 to HostStatus: {}", x);
                 HostStatus::Unknown
@@ -64,6 +144,196 @@ impl From<i16> for HostStatus {
 #[derive(Debug, Deserialize)]
 pub struct RoomCreateReq {
     pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub max_listeners: Option<i32>,
+    // public if left out
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub require_login: bool,
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+// fields left out are left alone, a null description, max_listeners, password or schedule
+// removes it
+#[derive(Debug, Deserialize)]
+pub struct RoomUpdateReq {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_listeners: Option<Option<i32>>,
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub password: Option<Option<String>>,
+    #[serde(default)]
+    pub require_login: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub schedule: Option<Option<Schedule>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InviteCreateReq {
+    // seconds, max_invite_lifetime if left out
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteResponse {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+// tells an explicit null (Some(None)) apart from a missing field (None)
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+const MAX_ROOM_NAME_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 1000;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LEN: usize = 32;
+
+fn check_room_name(name: &str) -> Result<String, MyError> {
+    let name = name.trim();
+    if name.is_empty() {
+        Err(MyError::BadRequest("name must not be empty".to_owned()))
+    } else if name.chars().count() > MAX_ROOM_NAME_LEN {
+        Err(MyError::BadRequest(format!(
+            "name is longer than {} characters",
+            MAX_ROOM_NAME_LEN
+        )))
+    } else if name.chars().any(char::is_control) {
+        Err(MyError::BadRequest(
+            "name can't contain control characters".to_owned(),
+        ))
+    } else {
+        Ok(name.to_owned())
+    }
+}
+
+// a blank description is no description
+fn check_description(text: Option<&str>) -> Result<Option<String>, MyError> {
+    match text.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(text) if text.chars().count() > MAX_DESCRIPTION_LEN => {
+            Err(MyError::BadRequest(format!(
+                "description is longer than {} characters",
+                MAX_DESCRIPTION_LEN
+            )))
+        }
+        Some(text) => Ok(Some(text.to_owned())),
+    }
+}
+
+// tags are trimmed, lowercased and deduplicated, keeping the owner's order
+fn check_tags(requested: &[String]) -> Result<Vec<String>, MyError> {
+    let mut checked: Vec<String> = vec![];
+    for tag in requested {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || checked.contains(&tag) {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LEN {
+            return Err(MyError::BadRequest(format!(
+                "tags can't be longer than {} characters",
+                MAX_TAG_LEN
+            )));
+        }
+        // commas separate tags in search queries
+        if tag.chars().any(|c| c == ',' || c.is_control()) {
+            return Err(MyError::BadRequest(
+                "tags can't contain commas or control characters".to_owned(),
+            ));
+        }
+        checked.push(tag);
+    }
+    if checked.len() > MAX_TAGS {
+        return Err(MyError::BadRequest(format!(
+            "rooms can't have more than {} tags",
+            MAX_TAGS
+        )));
+    }
+    Ok(checked)
+}
+
+fn validate_max_listeners(max: Option<i32>) -> Result<(), MyError> {
+    match max {
+        Some(max) if max < 1 => Err(MyError::BadRequest(
+            "max_listeners must be positive".to_owned(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn hash_join_password(password: &str) -> Result<String, MyError> {
+    if password.is_empty() {
+        return Err(MyError::BadRequest("password must not be empty".to_owned()));
+    }
+    hash_password(password)
+}
+
+/// Checks that a listener is allowed into the room. An invite gets them in no matter the
+/// visibility or password, but not without logging in if the room requires it.
+pub async fn authorize_listener(
+    pool: PgPool,
+    room: &Room,
+    opts: &ListenOptions,
+    listener_user_id: Option<Uuid>,
+) -> Result<(), MyError> {
+    if room.require_login && listener_user_id.is_none() {
+        return Err(MyError::AuthError(
+            "room is only open to listeners who are logged in".to_owned(),
+        ));
+    }
+    if let Some(invite) = &opts.invite {
+        return check_invite_token(invite, &room.id);
+    }
+    if room.visibility == Visibility::Private {
+        return Err(MyError::AuthError(
+            "room is private, joining takes an invite".to_owned(),
+        ));
+    }
+    let stored = match &room.join_password_hash {
+        None => return Ok(()),
+        Some(stored) => stored,
+    };
+    let password = match &opts.password {
+        None => return Err(MyError::AuthError("room needs a password".to_owned())),
+        Some(password) => password,
+    };
+    match verify_password(password, stored)? {
+        Verified::Invalid => Err(MyError::AuthError("wrong room password".to_owned())),
+        Verified::Valid { rehashed: None } => Ok(()),
+        Verified::Valid {
+            rehashed: Some(new_hash),
+        } => {
+            let room_id = room.id;
+            db_txn(pool, false, move |db| {
+                update(rooms.find(room_id))
+                    .set(join_password_hash.eq(new_hash))
+                    .execute(db)?;
+                Ok(())
+            })
+            .await
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -72,8 +342,47 @@ pub struct RoomResponse {
     pub name: String,
     pub host_name: Option<String>,
     pub host_status: HostStatus,
+    pub now_playing: Option<NowPlaying>,
     pub created_at: DateTime<Utc>,
     pub last_connected: Option<DateTime<Utc>>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub max_listeners: Option<i32>,
+    pub visibility: Visibility,
+    pub has_password: bool,
+    pub require_login: bool,
+    pub schedule: Option<Schedule>,
+    // the broadcast that's on or up next, None without a schedule or once it's over
+    pub next_broadcast: Option<Occurrence>,
+    // the user's role, only in their own listing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    // let in right now, not counting those waiting in line
+    pub listener_count: usize,
+    // who they are, only for the owner
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listeners: Option<Vec<ConnectedListener>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomPage {
+    pub rooms: Vec<RoomResponse>,
+    // pass as cursor to get the page after this one, None on the last page
+    pub next_cursor: Option<String>,
+    pub has_more: bool,
+    // how many rooms there are across all pages
+    pub total: i64,
+}
+
+impl RoomPage {
+    fn new(page: Vec<RoomResponse>, next_cursor: Option<String>, total: i64) -> Self {
+        Self {
+            rooms: page,
+            has_more: next_cursor.is_some(),
+            next_cursor,
+            total,
+        }
+    }
 }
 
 #[derive(Debug, Queryable)]
@@ -84,62 +393,502 @@ pub struct UserDisplayName {
 
 const ROOM_LIMIT_MAX: u8 = 100;
 
-pub async fn get_host_status(host_conns: &HostConnections, room: &Uuid) -> HostStatus {
-    if host_conns.read().await.contains_key(room) {
-        HostStatus::Playing
-    } else {
-        HostStatus::Stopped
+pub fn get_host_status(live: Option<&LiveSummary>) -> (HostStatus, Option<NowPlaying>) {
+    match live.cloned() {
+        None => (HostStatus::Stopped, None),
+        Some(live) => {
+            let status = match &live.now_playing {
+                _ if live.reconnecting => HostStatus::Reconnecting,
+                // connected, but hasn't said what it's playing
+                None => HostStatus::Unknown,
+                Some(now_playing) => match now_playing.status {
+                    PlayStatus::Playing => HostStatus::Playing,
+                    PlayStatus::Paused => HostStatus::Paused,
+                },
+            };
+            (status, live.now_playing)
+        }
     }
 }
 
+// what a listing shows of a room, with the host's status and audience as of now, see
+// live_summaries. The role is only shown in the user's own listing, viewer_role is the role of
+// whoever's asking
+async fn room_response(
+    live_rooms: &LiveRooms,
+    live: &HashMap<Uuid, LiveSummary>,
+    room: Room,
+    host_name: Option<String>,
+    role: Option<Role>,
+    viewer_role: Option<Role>,
+) -> RoomResponse {
+    let summary = live.get(&room.id);
+    let (host_status, now_playing) = get_host_status(summary);
+    let listeners = match viewer_role {
+        Some(viewer_role) if viewer_role.can(Permission::Manage) => {
+            Some(connected_listeners(live_rooms, room.id).await)
+        }
+        _ => None,
+    };
+    let schedule = Schedule::of(&room);
+    RoomResponse {
+        id: room.id,
+        host_name,
+        name: room.room_name,
+        host_status,
+        now_playing,
+        created_at: room.created_at,
+        last_connected: room.last_connected,
+        description: room.description,
+        tags: room.tags,
+        max_listeners: room.max_listeners,
+        visibility: room.visibility,
+        has_password: room.join_password_hash.is_some(),
+        require_login: room.require_login,
+        next_broadcast: schedule.and_then(|schedule| schedule.next_occurrence(Utc::now())),
+        schedule,
+        role,
+        listener_count: summary.map_or(0, |summary| summary.listener_count),
+        listeners,
+    }
+}
+
+// the parts of a room search that end up in the query
+struct RoomFilters {
+    // already escaped for LIKE
+    name: Option<String>,
+    owner: Option<String>,
+    tags: Vec<String>,
+    // and whether to look among them or everywhere else, None to not care
+    live: Option<(Vec<Uuid>, bool)>,
+}
+
+impl RoomFilters {
+    fn query(&self) -> schema::rooms::BoxedQuery<'static, Pg> {
+        let mut query = rooms.filter(visibility.eq(Visibility::Public)).into_boxed();
+        if let Some(name) = &self.name {
+            query = query.filter(room_name.ilike(format!("%{}%", name)));
+        }
+        if let Some(owner) = &self.owner {
+            let owner_ids = users
+                .select(schema::users::dsl::id)
+                .filter(display_name.eq(owner.clone()));
+            query = query.filter(user_id.eq_any(owner_ids));
+        }
+        if !self.tags.is_empty() {
+            query = query.filter(tags.contains(self.tags.clone()));
+        }
+        match &self.live {
+            None => query,
+            Some((live_ids, true)) => query.filter(schema::rooms::dsl::id.eq_any(live_ids.clone())),
+            Some((live_ids, false)) => {
+                query.filter(schema::rooms::dsl::id.ne_all(live_ids.clone()))
+            }
+        }
+    }
+}
+
+type RoomCondition = Box<dyn BoxableExpression<schema::rooms::table, Pg, SqlType = Bool>>;
+
+// rooms past the cursor when newest come first
+fn after_created_desc(after_created: DateTime<Utc>, after_id: Uuid) -> RoomCondition {
+    Box::new(
+        schema::rooms::dsl::created_at
+            .lt(after_created)
+            .or(schema::rooms::dsl::created_at
+                .eq(after_created)
+                .and(schema::rooms::dsl::id.gt(after_id))),
+    )
+}
+
+// rooms past the cursor when oldest come first, typed out so it works on joins too
+type AfterCreatedAsc = dsl::Or<
+    dsl::Gt<schema::rooms::created_at, DateTime<Utc>>,
+    dsl::And<dsl::Eq<schema::rooms::created_at, DateTime<Utc>>, dsl::Gt<schema::rooms::id, Uuid>>,
+>;
+
+fn after_created_asc(after_created: DateTime<Utc>, after_id: Uuid) -> AfterCreatedAsc {
+    schema::rooms::created_at
+        .gt(after_created)
+        .or(schema::rooms::created_at
+            .eq(after_created)
+            .and(schema::rooms::id.gt(after_id)))
+}
+
+// rooms past the cursor when most recently hosted come first, never hosted last
+fn after_connected(
+    after: Option<DateTime<Utc>>,
+    after_created: DateTime<Utc>,
+    after_id: Uuid,
+) -> RoomCondition {
+    let ties = after_created_desc(after_created, after_id);
+    match after {
+        None => Box::new(last_connected.is_null().and(ties)),
+        Some(after) => Box::new(
+            last_connected
+                .lt(after)
+                .or(last_connected.eq(after).and(ties))
+                .or(last_connected.is_null()),
+        ),
+    }
+}
+
+fn by_last_connected(
+    query: schema::rooms::BoxedQuery<'static, Pg>,
+    fetch: i64,
+) -> schema::rooms::BoxedQuery<'static, Pg> {
+    query
+        .order((
+            last_connected.desc().nulls_last(),
+            schema::rooms::dsl::created_at.desc(),
+            schema::rooms::dsl::id,
+        ))
+        .limit(fetch)
+}
+
+fn connected_cursor(room: &Room) -> RoomCursor {
+    RoomCursor::Connected {
+        last_connected: room.last_connected,
+        created_at: room.created_at,
+        id: room.id,
+    }
+}
+
+// the page without the extra row fetched to see if there's more, and the cursor after it
+fn split_page<T>(mut found: Vec<(T, RoomCursor)>, limit: usize) -> (Vec<T>, Option<String>) {
+    let has_more = found.len() > limit;
+    found.truncate(limit);
+    let next_cursor = found
+        .last()
+        .filter(|_| has_more)
+        .map(|(_, cursor)| cursor.encode());
+    (
+        found.into_iter().map(|(room, _)| room).collect(),
+        next_cursor,
+    )
+}
+
+// so % and _ in a search match themselves
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
 pub async fn list_rooms(
-    opts: ListOptions,
+    opts: SearchOptions,
+    viewer: Option<Uuid>,
     pool: PgPool,
-    host_conns: HostConnections,
+    conns: Conns,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let offset_or_zero = opts.offset.unwrap_or(0);
-    let limit = min(opts.limit.unwrap_or(ROOM_LIMIT_MAX), ROOM_LIMIT_MAX);
-    let offset = offset_or_zero;
-    let rooms_to_ret = db_txn(pool, true, |db| {
-        let found_rooms = rooms
-            .offset(i64::from(offset))
-            .limit(i64::from(limit))
-            .load::<Room>(db)?;
-        let ids: Vec<Uuid> = found_rooms
+    let live = live_summaries(&conns).await;
+    let live_rooms = conns.live_rooms;
+    let limit = usize::from(
+        opts.limit
+            .unwrap_or(ROOM_LIMIT_MAX)
+            .clamp(1, ROOM_LIMIT_MAX),
+    );
+    let cursor = match opts.cursor.as_deref() {
+        None => None,
+        Some(cursor) => Some(RoomCursor::decode(cursor)?),
+    };
+    if cursor
+        .as_ref()
+        .is_some_and(|cursor| !opts.sort.continues(cursor))
+    {
+        return Err(reject::custom(MyError::BadRequest(
+            "the cursor is from a listing sorted another way".to_owned(),
+        )));
+    }
+    let live_ids: Vec<Uuid> = live.keys().copied().collect();
+    let filters = RoomFilters {
+        name: opts
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(escape_like),
+        owner: opts.owner,
+        // matched the way they're stored, see check_tags
+        tags: opts
+            .tags
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+        live: opts.live.map(|live| (live_ids, live)),
+    };
+    // only live rooms have listeners, and how many isn't in the rooms table
+    let counts: HashMap<Uuid, usize> = match opts.sort {
+        RoomSort::Listeners => live
             .iter()
-            .map(|room| room.user_id.clone())
-            .collect();
+            .map(|(room_id, summary)| (*room_id, summary.listener_count))
+            .collect(),
+        _ => HashMap::new(),
+    };
+    let sort = opts.sort;
+    let rooms_to_ret = db_txn(pool, true, move |db| {
+        let total: i64 = filters.query().count().get_result(db)?;
+        // one more than asked for, to tell if there's another page. Each room comes with the
+        // cursor for the page after it
+        let fetch = limit as i64 + 1;
+        let found_rooms: Vec<(Room, RoomCursor)> = match sort {
+            RoomSort::CreatedAt => {
+                let mut query = filters.query();
+                if let Some(RoomCursor::Created {
+                    created_at: after_created,
+                    id: after_id,
+                }) = cursor
+                {
+                    query = query.filter(after_created_desc(after_created, after_id));
+                }
+                query
+                    .order((
+                        schema::rooms::dsl::created_at.desc(),
+                        schema::rooms::dsl::id,
+                    ))
+                    .limit(fetch)
+                    .load::<Room>(db)?
+                    .into_iter()
+                    .map(|room| {
+                        let next = RoomCursor::Created {
+                            created_at: room.created_at,
+                            id: room.id,
+                        };
+                        (room, next)
+                    })
+                    .collect()
+            }
+            RoomSort::LastConnected => {
+                let mut query = filters.query();
+                if let Some(RoomCursor::Connected {
+                    last_connected: after,
+                    created_at: after_created,
+                    id: after_id,
+                }) = cursor
+                {
+                    query = query.filter(after_connected(after, after_created, after_id));
+                }
+                by_last_connected(query, fetch)
+                    .load::<Room>(db)?
+                    .into_iter()
+                    .map(|room| {
+                        let next = connected_cursor(&room);
+                        (room, next)
+                    })
+                    .collect()
+            }
+            RoomSort::Listeners => {
+                // the rooms with listeners come first, sorted here, then the rest from the db
+                let with_listeners: Vec<Uuid> = counts.keys().copied().collect();
+                let mut matching: Vec<Uuid> = filters
+                    .query()
+                    .filter(schema::rooms::dsl::id.eq_any(&with_listeners))
+                    .select(schema::rooms::dsl::id)
+                    .load(db)?;
+                let key = |room: &Uuid| (Reverse(counts[room]), *room);
+                matching.sort_by_key(key);
+                let page: Vec<Uuid> = match &cursor {
+                    Some(RoomCursor::Listened {
+                        listeners,
+                        id: after_id,
+                    }) => {
+                        let after = (Reverse(*listeners), *after_id);
+                        matching
+                            .iter()
+                            .filter(|room| key(room) > after)
+                            .take(limit + 1)
+                            .copied()
+                            .collect()
+                    }
+                    // already past them
+                    Some(_) => vec![],
+                    None => matching.iter().take(limit + 1).copied().collect(),
+                };
+                let mut found_rooms: Vec<(Room, RoomCursor)> = rooms
+                    .filter(schema::rooms::dsl::id.eq_any(&page))
+                    .load::<Room>(db)?
+                    .into_iter()
+                    .map(|room| {
+                        let next = RoomCursor::Listened {
+                            listeners: counts[&room.id],
+                            id: room.id,
+                        };
+                        (room, next)
+                    })
+                    .collect();
+                found_rooms.sort_by_key(|(room, _)| key(&room.id));
+                let rest_fetch = fetch - found_rooms.len() as i64;
+                if rest_fetch > 0 {
+                    let mut query = filters
+                        .query()
+                        .filter(schema::rooms::dsl::id.ne_all(with_listeners));
+                    if let Some(RoomCursor::Connected {
+                        last_connected: after,
+                        created_at: after_created,
+                        id: after_id,
+                    }) = cursor
+                    {
+                        query = query.filter(after_connected(after, after_created, after_id));
+                    }
+                    let rest = by_last_connected(query, rest_fetch)
+                        .load::<Room>(db)?
+                        .into_iter()
+                        .map(|room| {
+                            let next = connected_cursor(&room);
+                            (room, next)
+                        });
+                    found_rooms.extend(rest);
+                }
+                found_rooms
+            }
+        };
+        let ids: Vec<Uuid> = found_rooms.iter().map(|(room, _)| room.user_id).collect();
         let found_users = users
             .select((schema::users::dsl::id, display_name))
             .filter(schema::users::dsl::id.eq(any(&ids)))
             .load::<UserDisplayName>(db)?;
-        Ok((found_rooms, found_users))
+        let viewer_roles = match viewer {
+            None => HashMap::new(),
+            Some(viewer) => {
+                let room_ids: Vec<Uuid> = found_rooms.iter().map(|(room, _)| room.id).collect();
+                room_roles(db, viewer, &room_ids)?
+            }
+        };
+        Ok((found_rooms, found_users, viewer_roles, total))
     })
     .await;
     match rooms_to_ret {
         Err(e) => Err(reject::custom(e)),
-        Ok((found_rooms, found_users)) => {
+        Ok((found_rooms, found_users, viewer_roles, total)) => {
             let id_to_name: HashMap<Uuid, String> = found_users
                 .into_iter()
                 .map(|u| (u.id, u.display_name))
                 .collect();
-            let response: Vec<RoomResponse> = stream::iter(found_rooms.into_iter())
-                .then(|room| async {
-                    let host_status = get_host_status(&host_conns, &room.id).await;
-                    RoomResponse {
-                        id: room.id,
-                        host_name: id_to_name
-                            .get(&room.user_id)
-                            .map_or(None, |name| Some(name.clone())),
-                        name: room.room_name,
-                        host_status,
-                        created_at: room.created_at,
-                        last_connected: room.last_connected,
-                    }
+            let (found_rooms, next_cursor) = split_page(found_rooms, limit);
+            let response: Vec<RoomResponse> = stream::iter(found_rooms)
+                .then(|room| {
+                    let host_name = id_to_name.get(&room.user_id).cloned();
+                    let viewer_role = viewer_roles.get(&room.id).copied();
+                    room_response(&live_rooms, &live, room, host_name, None, viewer_role)
+                })
+                .collect()
+                .await;
+            Ok(json(&RoomPage::new(response, next_cursor, total)))
+        }
+    }
+}
+
+// how far apart a recurring room's broadcasts are, in seconds, see schedule::period_of
+const RECURRENCE_PERIOD_SQL: &str =
+    "(CASE rooms.recurrence WHEN 'daily' THEN 86400 ELSE 604800 END)";
+
+type NextStart = Box<dyn BoxableExpression<schema::rooms::table, Pg, SqlType = Timestamptz>>;
+
+// when a scheduled room's broadcast that's on or up next starts, worked out like
+// Schedule::next_occurrence. Only meaningful for rooms upcoming_filter lets through
+fn next_start(now: DateTime<Utc>) -> NextStart {
+    Box::new(
+        dsl::sql::<Timestamptz>("(CASE WHEN rooms.scheduled_end > ")
+            .bind::<Timestamptz, _>(now)
+            .sql(" THEN rooms.scheduled_start")
+            // the broadcast after however many periods have gone by since the first one ended
+            .sql(" ELSE rooms.scheduled_start + interval '1 second' * ((floor(extract(epoch FROM ")
+            .bind::<Timestamptz, _>(now)
+            .sql(&format!(
+                " - rooms.scheduled_end) / {period}) + 1) * {period})::double precision END)",
+                period = RECURRENCE_PERIOD_SQL
+            )),
+    )
+}
+
+// public rooms with a broadcast that's on or still to come
+fn upcoming_filter(now: DateTime<Utc>) -> RoomCondition {
+    Box::new(
+        visibility
+            .eq(Visibility::Public)
+            .and(scheduled_start.is_not_null())
+            .and(recurrence.is_not_null().or(scheduled_end.gt(now))),
+    )
+}
+
+// public rooms with a broadcast on or coming up, soonest first
+pub async fn list_upcoming_rooms(
+    opts: PageOptions,
+    pool: PgPool,
+    conns: Conns,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let live = live_summaries(&conns).await;
+    let live_rooms = conns.live_rooms;
+    let limit = usize::from(
+        opts.limit
+            .unwrap_or(ROOM_LIMIT_MAX)
+            .clamp(1, ROOM_LIMIT_MAX),
+    );
+    let after = match opts.cursor.as_deref().map(RoomCursor::decode) {
+        None => None,
+        Some(Ok(RoomCursor::Upcoming {
+            start: after_start,
+            id: after_id,
+        })) => Some((after_start, after_id)),
+        Some(Ok(_)) => {
+            return Err(reject::custom(MyError::BadRequest(
+                "the cursor is from a listing sorted another way".to_owned(),
+            )))
+        }
+        Some(Err(e)) => return Err(reject::custom(e)),
+    };
+    let now = Utc::now();
+    let rooms_to_ret = db_txn(pool, true, move |db| {
+        let total: i64 = rooms.filter(upcoming_filter(now)).count().get_result(db)?;
+        let mut query = rooms
+            .filter(upcoming_filter(now))
+            .select((schema::rooms::all_columns, next_start(now)))
+            .into_boxed();
+        if let Some((after_start, after_id)) = after {
+            query = query.filter(
+                next_start(now).gt(after_start).or(next_start(now)
+                    .eq(after_start)
+                    .and(schema::rooms::dsl::id.gt(after_id))),
+            );
+        }
+        let found_rooms: Vec<(Room, DateTime<Utc>)> = query
+            .order((next_start(now), schema::rooms::dsl::id))
+            .limit(limit as i64 + 1)
+            .load(db)?;
+        let ids: Vec<Uuid> = found_rooms.iter().map(|(room, _)| room.user_id).collect();
+        let found_users = users
+            .select((schema::users::dsl::id, display_name))
+            .filter(schema::users::dsl::id.eq(any(&ids)))
+            .load::<UserDisplayName>(db)?;
+        Ok((found_rooms, found_users, total))
+    })
+    .await;
+    match rooms_to_ret {
+        Err(e) => Err(reject::custom(e)),
+        Ok((found_rooms, found_users, total)) => {
+            let id_to_name: HashMap<Uuid, String> = found_users
+                .into_iter()
+                .map(|u| (u.id, u.display_name))
+                .collect();
+            let found_rooms = found_rooms
+                .into_iter()
+                .map(|(room, start)| {
+                    let next = RoomCursor::Upcoming { start, id: room.id };
+                    (room, next)
+                })
+                .collect();
+            let (found_rooms, next_cursor) = split_page(found_rooms, limit);
+            let response: Vec<RoomResponse> = stream::iter(found_rooms)
+                .then(|room| {
+                    let host_name = id_to_name.get(&room.user_id).cloned();
+                    room_response(&live_rooms, &live, room, host_name, None, None)
                 })
                 .collect()
                 .await;
-            Ok(json(&response))
+            Ok(json(&RoomPage::new(response, next_cursor, total)))
         }
     }
 }
@@ -148,13 +897,31 @@ pub async fn create_room(
     pool: PgPool,
     req_user_id: Uuid,
     create: RoomCreateReq,
+    room_events: RoomEvents,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    validate_max_listeners(create.max_listeners)?;
+    if let Some(schedule) = &create.schedule {
+        schedule.validate()?;
+    }
+    let join_hash = match &create.password {
+        None => None,
+        Some(password) => Some(hash_join_password(password)?),
+    };
     let to_create = Room {
         id: Uuid::new_v4(),
-        room_name: create.name,
+        room_name: check_room_name(&create.name)?,
         user_id: req_user_id,
         created_at: Utc::now(),
         last_connected: None,
+        max_listeners: create.max_listeners,
+        visibility: create.visibility.unwrap_or(Visibility::Public),
+        join_password_hash: join_hash,
+        description: check_description(create.description.as_deref())?,
+        tags: check_tags(&create.tags)?,
+        require_login: create.require_login,
+        scheduled_start: create.schedule.map(|schedule| schedule.start),
+        scheduled_end: create.schedule.map(|schedule| schedule.end),
+        recurrence: create.schedule.and_then(|schedule| schedule.recurrence),
     };
 
     let res = db_txn(pool, false, |db| {
@@ -162,6 +929,15 @@ pub async fn create_room(
         if insert_result == 0 {
             return Err(MyError::UnexpectedError);
         }
+        let owner = RoomMember {
+            room_id: to_create.id,
+            user_id: req_user_id,
+            role: Role::Owner,
+            created_at: to_create.created_at,
+        };
+        insert_into(schema::room_members::table)
+            .values(&owner)
+            .execute(db)?;
         let read_result: Room = rooms.find(to_create.id).first(db)?;
         Ok(read_result)
     })
@@ -169,65 +945,301 @@ pub async fn create_room(
 
     match res {
         Err(e) => Err(reject::custom(e)),
-        Ok(room) => Ok(with_status(json(&room), StatusCode::CREATED)),
+        Ok(room) => {
+            let created = DirectoryChange::RoomCreated {
+                room: RoomDetails::from(&room),
+            };
+            room_events.send(room.id, room.user_id, room.visibility, created);
+            Ok(with_status(json(&room), StatusCode::CREATED))
+        }
     }
 }
 
-pub async fn delete_room(
-    room_to_delete: Uuid,
+pub async fn update_room(
+    room_to_update: Uuid,
     pool: PgPool,
     req_user_id: Uuid,
+    changes: RoomUpdateReq,
+    conns: Conns,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(max) = changes.max_listeners {
+        validate_max_listeners(max)?;
+    }
+    if let Some(Some(schedule)) = &changes.schedule {
+        schedule.validate()?;
+    }
+    let room_changes = RoomChanges {
+        room_name: changes.name.as_deref().map(check_room_name).transpose()?,
+        description: changes
+            .description
+            .as_ref()
+            .map(|text| check_description(text.as_deref()))
+            .transpose()?,
+        tags: changes.tags.as_deref().map(check_tags).transpose()?,
+        max_listeners: changes.max_listeners,
+        visibility: changes.visibility,
+        join_password_hash: match &changes.password {
+            None => None,
+            Some(None) => Some(None),
+            Some(Some(password)) => Some(Some(hash_join_password(password)?)),
+        },
+        require_login: changes.require_login,
+        scheduled_start: changes
+            .schedule
+            .map(|schedule| schedule.map(|schedule| schedule.start)),
+        scheduled_end: changes
+            .schedule
+            .map(|schedule| schedule.map(|schedule| schedule.end)),
+        recurrence: changes
+            .schedule
+            .map(|schedule| schedule.and_then(|schedule| schedule.recurrence)),
+    };
     let res = db_txn(pool, false, |db| {
-        let room_result: Room = rooms.find(room_to_delete).first(db)?;
-        if room_result.user_id != req_user_id {
-            return Err(MyError::AuthError(
-                "Unable to delete: room is not owned by user".to_owned(),
-            ));
+        check_permission(
+            db,
+            room_to_update,
+            req_user_id,
+            Permission::Manage,
+            "update",
+        )?;
+        if !room_changes.is_empty() {
+            update(rooms.find(room_to_update))
+                .set(&room_changes)
+                .execute(db)?;
         }
-        delete(rooms.find(room_to_delete)).execute(db)?;
+        let read_result: Room = rooms.find(room_to_update).first(db)?;
+        Ok(read_result)
+    })
+    .await;
+
+    match res {
+        Err(e) => Err(reject::custom(e)),
+        Ok(room) => {
+            let Conns {
+                live_rooms,
+                lobbies,
+                cluster,
+                ..
+            } = conns;
+            room_updated(&live_rooms, &lobbies, &room).await;
+            cluster
+                .relay_to_all(Relay::RoomUpdated { room_id: room.id })
+                .await;
+            Ok(json(&room))
+        }
+    }
+}
+
+pub async fn create_invite(
+    room_to_share: Uuid,
+    pool: PgPool,
+    req_user_id: Uuid,
+    req: InviteCreateReq,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let lifetime = req.expires_in.unwrap_or(settings().max_invite_lifetime);
+    if lifetime == 0 || lifetime > settings().max_invite_lifetime {
+        return Err(reject::custom(MyError::BadRequest(format!(
+            "expires_in must be between 1 and {} seconds",
+            settings().max_invite_lifetime
+        ))));
+    }
+    db_txn(pool, true, |db| {
+        check_permission(db, room_to_share, req_user_id, Permission::Host, "invite")?;
         Ok(())
     })
+    .await?;
+
+    let (token, expires_at) = get_invite_token(&room_to_share, lifetime)?;
+    Ok(with_status(
+        json(&InviteResponse { token, expires_at }),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn delete_room(
+    room_to_delete: Uuid,
+    pool: PgPool,
+    req_user_id: Uuid,
+    conns: Conns,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let res = db_txn(pool.clone(), false, |db| {
+        let room = check_permission(
+            db,
+            room_to_delete,
+            req_user_id,
+            Permission::Manage,
+            "delete",
+        )?;
+        delete(rooms.find(room_to_delete)).execute(db)?;
+        Ok(room)
+    })
     .await;
 
     match res {
         Err(e) => Err(reject::custom(e)),
-        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Ok(room) => {
+            let Conns {
+                live_rooms,
+                lobbies,
+                room_events,
+                cluster,
+            } = conns;
+            close_room(&live_rooms, room_to_delete, RoomClosedReason::RoomDeleted).await;
+            close_lobby(&lobbies, room_to_delete, RoomClosedReason::RoomDeleted).await;
+            cluster
+                .relay_to_all(Relay::RoomDeleted {
+                    room_id: room_to_delete,
+                })
+                .await;
+            room_events.send(
+                room.id,
+                room.user_id,
+                room.visibility,
+                DirectoryChange::RoomDeleted,
+            );
+            Ok(StatusCode::NO_CONTENT)
+        }
     }
 }
 
 pub async fn list_rooms_for_user(
     for_user_id: Uuid,
+    opts: PageOptions,
+    viewer: Option<Uuid>,
     pool: PgPool,
-    host_conns: HostConnections,
+    conns: Conns,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let rooms_to_ret = db_txn(pool, true, |db| {
-        let found_rooms = rooms.filter(user_id.eq(for_user_id)).load::<Room>(db)?;
-        let user_name: UserDisplayName = users
-            .select((schema::users::dsl::id, display_name))
-            .filter(schema::users::dsl::id.eq(for_user_id))
-            .first(db)?;
-        Ok((found_rooms, user_name))
+    rooms_of_user(for_user_id, viewer, false, opts, pool, conns).await
+}
+
+// unlisted and private rooms are only listed to their owner, along with the rooms the user was
+// made a member of
+pub async fn list_my_rooms(
+    req_user_id: Uuid,
+    opts: PageOptions,
+    pool: PgPool,
+    conns: Conns,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    rooms_of_user(req_user_id, Some(req_user_id), true, opts, pool, conns).await
+}
+
+// oldest first
+async fn rooms_of_user(
+    for_user_id: Uuid,
+    viewer: Option<Uuid>,
+    mine: bool,
+    opts: PageOptions,
+    pool: PgPool,
+    conns: Conns,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let live = live_summaries(&conns).await;
+    let live_rooms = conns.live_rooms;
+    let limit = usize::from(
+        opts.limit
+            .unwrap_or(ROOM_LIMIT_MAX)
+            .clamp(1, ROOM_LIMIT_MAX),
+    );
+    let after = match opts.cursor.as_deref().map(RoomCursor::decode) {
+        None => None,
+        Some(Ok(RoomCursor::Created {
+            created_at: after_created,
+            id: after_id,
+        })) => Some((after_created, after_id)),
+        Some(Ok(_)) => {
+            return Err(reject::custom(MyError::BadRequest(
+                "the cursor is from a listing sorted another way".to_owned(),
+            )))
+        }
+        Some(Err(e)) => return Err(reject::custom(e)),
+    };
+    let rooms_to_ret = db_txn(pool, true, move |db| {
+        let fetch = limit as i64 + 1;
+        // with the owner's name, and the user's role in the room when listing their own
+        let (found_rooms, total): (Vec<(Room, String, Option<Role>)>, i64) = if mine {
+            let query = || {
+                schema::room_members::table
+                    .inner_join(rooms.inner_join(users))
+                    .filter(schema::room_members::user_id.eq(for_user_id))
+                    .into_boxed()
+            };
+            let total = query().count().get_result(db)?;
+            let mut page = query();
+            if let Some((after_created, after_id)) = after {
+                page = page.filter(after_created_asc(after_created, after_id));
+            }
+            let found_rooms = page
+                .select((
+                    schema::rooms::all_columns,
+                    display_name,
+                    schema::room_members::role.nullable(),
+                ))
+                .order((schema::rooms::created_at, schema::rooms::id))
+                .limit(fetch)
+                .load(db)?;
+            (found_rooms, total)
+        } else {
+            let query = || {
+                rooms
+                    .inner_join(users)
+                    .filter(user_id.eq(for_user_id))
+                    .filter(visibility.eq(Visibility::Public))
+                    .into_boxed()
+            };
+            let total = query().count().get_result(db)?;
+            let mut page = query();
+            if let Some((after_created, after_id)) = after {
+                page = page.filter(after_created_asc(after_created, after_id));
+            }
+            let found_rooms: Vec<(Room, String)> = page
+                .select((schema::rooms::all_columns, display_name))
+                .order((schema::rooms::created_at, schema::rooms::id))
+                .limit(fetch)
+                .load(db)?;
+            let found_rooms = found_rooms
+                .into_iter()
+                .map(|(room, owner_name)| (room, owner_name, None))
+                .collect();
+            (found_rooms, total)
+        };
+        let viewer_roles = match viewer {
+            None => HashMap::new(),
+            Some(viewer) => {
+                let room_ids: Vec<Uuid> = found_rooms.iter().map(|(room, _, _)| room.id).collect();
+                room_roles(db, viewer, &room_ids)?
+            }
+        };
+        Ok((found_rooms, viewer_roles, total))
     })
     .await;
     match rooms_to_ret {
         Err(e) => Err(reject::custom(e)),
-        Ok((found_rooms, user_name)) => {
-            let response: Vec<RoomResponse> = stream::iter(found_rooms)
-                .then(|room| async {
-                    let host_status = get_host_status(&host_conns, &room.id).await;
-                    RoomResponse {
-                        id: room.id,
-                        host_name: Some(user_name.display_name.clone()),
-                        name: room.room_name,
-                        host_status,
+        Ok((found_rooms, viewer_roles, total)) => {
+            let found_rooms = found_rooms
+                .into_iter()
+                .map(|(room, owner_name, role)| {
+                    let next = RoomCursor::Created {
                         created_at: room.created_at,
-                        last_connected: room.last_connected,
-                    }
+                        id: room.id,
+                    };
+                    ((room, owner_name, role), next)
+                })
+                .collect();
+            let (found_rooms, next_cursor) = split_page(found_rooms, limit);
+            let response: Vec<RoomResponse> = stream::iter(found_rooms)
+                .then(|(room, owner_name, role)| {
+                    let viewer_role = viewer_roles.get(&room.id).copied();
+                    room_response(
+                        &live_rooms,
+                        &live,
+                        room,
+                        Some(owner_name),
+                        role,
+                        viewer_role,
+                    )
                 })
                 .collect()
                 .await;
-            Ok(json(&response))
+            Ok(json(&RoomPage::new(response, next_cursor, total)))
         }
     }
 }
@@ -0,0 +1,79 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{Recurrence, Room},
+    errors::MyError,
+};
+
+/// When a room plans to broadcast, as set by its owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    // a one-off broadcast if None
+    #[serde(default)]
+    pub recurrence: Option<Recurrence>,
+}
+
+/// One of a schedule's broadcasts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Occurrence {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+fn period_of(recurrence: Recurrence) -> Duration {
+    match recurrence {
+        Recurrence::Daily => Duration::days(1),
+        Recurrence::Weekly => Duration::weeks(1),
+    }
+}
+
+impl Schedule {
+    pub fn of(room: &Room) -> Option<Schedule> {
+        match (room.scheduled_start, room.scheduled_end) {
+            (Some(start), Some(end)) => Some(Schedule {
+                start,
+                end,
+                recurrence: room.recurrence,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), MyError> {
+        if self.end <= self.start {
+            return Err(MyError::BadRequest(
+                "a schedule has to end after it starts".to_owned(),
+            ));
+        }
+        match self.recurrence {
+            Some(recurrence) if self.end - self.start >= period_of(recurrence) => {
+                Err(MyError::BadRequest(format!(
+                    "a {} broadcast has to be over before the next one starts",
+                    recurrence.as_str()
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The broadcast that's on or up next, None once a one-off broadcast is over. Repeats are a
+    /// fixed number of hours apart, so they move by an hour of local time when DST changes.
+    pub fn next_occurrence(&self, now: DateTime<Utc>) -> Option<Occurrence> {
+        let offset = match self.recurrence {
+            _ if now < self.end => Duration::zero(),
+            None => return None,
+            Some(recurrence) => {
+                let period = period_of(recurrence).num_seconds();
+                let skipped = (now - self.end).num_seconds() / period + 1;
+                Duration::seconds(period * skipped)
+            }
+        };
+        Some(Occurrence {
+            start: self.start + offset,
+            end: self.end + offset,
+        })
+    }
+}
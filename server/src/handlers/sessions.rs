@@ -0,0 +1,186 @@
+use std::convert::TryFrom;
+
+use chrono::{Duration, Utc};
+use diesel::{insert_into, prelude::*, update};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{hyper::StatusCode, reject, reply::json};
+
+use crate::{
+    auth::{gen_refresh_token, get_token, hash_refresh_token},
+    db::{PgPool, PooledPg, Session, SessionQueryResult},
+    errors::MyError,
+    schema::sessions::dsl::*,
+    settings::settings,
+};
+
+use super::util::db_txn;
+
+#[derive(Deserialize)]
+pub struct SessionRefreshReq {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionTokens {
+    pub session_id: Uuid,
+    pub token: String,
+    pub refresh_token: String,
+}
+
+fn refresh_expiry() -> Result<chrono::DateTime<Utc>, MyError> {
+    let lifetime = i64::try_from(settings().refresh_token_lifetime)?;
+    Utc::now()
+        .checked_add_signed(Duration::seconds(lifetime))
+        .ok_or(MyError::UnexpectedError)
+}
+
+/// Creates a new session for the user, to be called from within a transaction.
+pub fn start_session(db: &PooledPg, for_user_id: Uuid) -> Result<SessionTokens, MyError> {
+    let new_refresh_token = gen_refresh_token();
+    let now = Utc::now();
+    let to_create = Session {
+        id: Uuid::new_v4(),
+        user_id: for_user_id,
+        refresh_token_hash: hash_refresh_token(&new_refresh_token),
+        created_at: now,
+        refreshed_at: now,
+        expires_at: refresh_expiry()?,
+        revoked_at: None,
+    };
+    let insert_result = insert_into(sessions).values(&to_create).execute(db)?;
+    if insert_result == 0 {
+        return Err(MyError::UnexpectedError);
+    }
+
+    Ok(SessionTokens {
+        session_id: to_create.id,
+        token: get_token(&for_user_id, &to_create.id)?,
+        refresh_token: new_refresh_token,
+    })
+}
+
+pub async fn refresh_session(
+    refresh: SessionRefreshReq,
+    pool: PgPool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let old_hash = hash_refresh_token(&refresh.refresh_token);
+    let new_refresh_token = gen_refresh_token();
+    let new_hash = hash_refresh_token(&new_refresh_token);
+    let new_expiry = refresh_expiry()?;
+
+    let res = db_txn(pool, false, |db| {
+        let now = Utc::now();
+        let found: Option<Session> = sessions
+            .filter(refresh_token_hash.eq(&old_hash))
+            .first(db)
+            .optional()?;
+        let session = match found {
+            Some(session) if session.revoked_at.is_none() && session.expires_at > now => session,
+            _ => {
+                return Err(MyError::AuthError(
+                    "refresh token is invalid or expired".to_owned(),
+                ));
+            }
+        };
+        // refresh tokens are single-use, so only one of two concurrent refreshes can win
+        let updated = update(
+            sessions
+                .find(session.id)
+                .filter(refresh_token_hash.eq(&old_hash)),
+        )
+        .set((
+            refresh_token_hash.eq(&new_hash),
+            refreshed_at.eq(now),
+            expires_at.eq(new_expiry),
+        ))
+        .execute(db)?;
+        if updated != 1 {
+            return Err(MyError::AuthError(
+                "refresh token is invalid or expired".to_owned(),
+            ));
+        }
+        Ok(session)
+    })
+    .await;
+
+    match res {
+        Err(e) => Err(reject::custom(e)),
+        Ok(session) => Ok(json(&SessionTokens {
+            session_id: session.id,
+            token: get_token(&session.user_id, &session.id)?,
+            refresh_token: new_refresh_token,
+        })),
+    }
+}
+
+pub async fn list_sessions(
+    req_user_id: Uuid,
+    pool: PgPool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let res = db_txn(pool, true, |db| {
+        let found: Vec<SessionQueryResult> = sessions
+            .select((id, created_at, refreshed_at, expires_at))
+            .filter(user_id.eq(req_user_id))
+            .filter(revoked_at.is_null())
+            .filter(expires_at.gt(Utc::now()))
+            .order(refreshed_at.desc())
+            .load(db)?;
+        Ok(found)
+    })
+    .await;
+
+    match res {
+        Err(e) => Err(reject::custom(e)),
+        Ok(found) => Ok(json(&found)),
+    }
+}
+
+pub async fn revoke_session(
+    session_id: Uuid,
+    req_user_id: Uuid,
+    pool: PgPool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let res = db_txn(pool, false, |db| {
+        // other users' sessions are reported as missing rather than forbidden
+        let session: Session = sessions
+            .find(session_id)
+            .filter(user_id.eq(req_user_id))
+            .first(db)?;
+        if session.revoked_at.is_none() {
+            update(sessions.find(session.id))
+                .set(revoked_at.eq(Utc::now()))
+                .execute(db)?;
+        }
+        Ok(())
+    })
+    .await;
+
+    match res {
+        Err(e) => Err(reject::custom(e)),
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+    }
+}
+
+// log out everywhere
+pub async fn revoke_all_sessions(
+    req_user_id: Uuid,
+    pool: PgPool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let res = db_txn(pool, false, |db| {
+        update(
+            sessions
+                .filter(user_id.eq(req_user_id))
+                .filter(revoked_at.is_null()),
+        )
+        .set(revoked_at.eq(Utc::now()))
+        .execute(db)?;
+        Ok(())
+    })
+    .await;
+
+    match res {
+        Err(e) => Err(reject::custom(e)),
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+    }
+}
@@ -5,10 +5,7 @@ use serde::Serialize;
 use uuid::Uuid;
 use warp::{reject, reply::json};
 
-use crate::{
-    errors::MyError,
-    settings::{TURN_SECRET, TURN_TIMEOUT},
-};
+use crate::{errors::MyError, settings::settings};
 
 #[derive(Debug, Serialize)]
 struct TurnCreds {
@@ -18,14 +15,14 @@ struct TurnCreds {
 
 pub async fn get_turn_creds() -> Result<impl warp::Reply, warp::Rejection> {
     let expiration_ts: Duration = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => n + Duration::from_secs(TURN_TIMEOUT),
+        Ok(n) => n + Duration::from_secs(settings().turn_timeout),
         Err(_) => {
             return Err(reject::custom(MyError::UnexpectedError));
         }
     };
     // just use a random UUID as the username since we want non-logged-in users to be able to hit the TURN server
     let username = format!("{}:{}", expiration_ts.as_secs(), Uuid::new_v4());
-    let mut hmac = Hmac::new(Sha1::new(), TURN_SECRET.as_bytes());
+    let mut hmac = Hmac::new(Sha1::new(), settings().turn_secret.as_bytes());
     hmac.input(username.as_bytes());
 
     let password = base64::encode(hmac.result().code());
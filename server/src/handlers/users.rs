@@ -1,5 +1,4 @@
 use chrono::Utc;
-use crypto::bcrypt::bcrypt;
 use diesel::{insert_into, prelude::*};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -9,14 +8,16 @@ use warp::{
 };
 
 use crate::{
-    auth::{gen_salt, get_token},
+    auth::{dummy_verify, hash_password, verify_password, Verified},
     db::{PgPool, User, UserQueryResult},
     errors::MyError,
     schema::users::dsl::*,
-    settings::{BCRYPT_COST, OUTPUT_LEN},
 };
 
-use super::util::db_txn;
+use super::{
+    sessions::{start_session, SessionTokens},
+    util::db_txn,
+};
 
 #[derive(Deserialize)]
 pub struct UserCreateReq {
@@ -34,31 +35,23 @@ pub struct UserLoginReq {
 #[derive(Debug, Serialize)]
 pub struct UserLoginRes {
     pub result: UserQueryResult,
-    pub token: String,
+    #[serde(flatten)]
+    pub tokens: SessionTokens,
 }
 
 pub async fn create_user(
     create: UserCreateReq,
     pool: PgPool,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let gen_salt = gen_salt();
-    let mut pass_output = [0u8; OUTPUT_LEN];
-    bcrypt(
-        BCRYPT_COST,
-        &gen_salt,
-        create.password.as_bytes(),
-        &mut pass_output,
-    );
     let to_create = User {
         id: Uuid::new_v4(),
         display_name: create.display_name,
         email: create.email,
         created_at: Utc::now(),
-        pass_hash: Vec::from(pass_output),
-        salt: Vec::from(gen_salt),
+        password_hash: hash_password(&create.password)?,
     };
 
-    let user = db_txn(pool, false, |db| {
+    let (user, tokens) = db_txn(pool, false, |db| {
         let insert_result = insert_into(users).values(&to_create).execute(db)?;
         if insert_result == 0 {
             return Err(MyError::UnexpectedError);
@@ -67,14 +60,15 @@ pub async fn create_user(
             .select((id, display_name, email, created_at))
             .find(to_create.id)
             .first(db)?;
-        Ok(read_result)
+        let tokens = start_session(db, read_result.id)?;
+        Ok((read_result, tokens))
     })
     .await?;
 
     Ok(with_status(
         json(&UserLoginRes {
-            token: get_token(&user.id)?,
             result: user,
+            tokens,
         }),
         StatusCode::CREATED,
     ))
@@ -84,48 +78,45 @@ pub async fn login_user(
     login: UserLoginReq,
     pool: PgPool,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let res = db_txn(pool, true, |db| -> Result<User, MyError> {
+    let res = db_txn(pool.clone(), true, |db| -> Result<User, MyError> {
         let user_result: User = users.filter(email.eq(&login.email)).first(db)?;
         Ok(user_result)
     })
     .await;
 
-    let mut pass_output = [0u8; OUTPUT_LEN];
     // ensure (close to) constant time to prevent distinguishing btwn invalid email vs. password
-    match res {
+    let user = match res {
         Err(e) => {
-            let gen_salt = gen_salt();
-            bcrypt(
-                BCRYPT_COST,
-                &gen_salt,
-                login.password.as_bytes(),
-                &mut pass_output,
-            );
+            dummy_verify(&login.password);
             error!("{:#?}", e);
-            Err(warp::Rejection::from(MyError::AuthError(
+            return Err(warp::Rejection::from(MyError::AuthError(
                 "email or password does not match".to_owned(),
-            )))
+            )));
         }
-        Ok(user) => {
-            bcrypt(
-                BCRYPT_COST,
-                &user.salt,
-                login.password.as_bytes(),
-                &mut pass_output,
-            );
-            if user.pass_hash == pass_output {
-                Ok(with_status(
-                    json(&UserLoginRes {
-                        token: get_token(&user.id)?,
-                        result: UserQueryResult::from(user),
-                    }),
-                    StatusCode::OK,
-                ))
-            } else {
-                Err(warp::Rejection::from(MyError::AuthError(
-                    "email or password does not match".to_owned(),
-                )))
-            }
+        Ok(user) => user,
+    };
+
+    match verify_password(&login.password, &user.password_hash)? {
+        Verified::Invalid => Err(warp::Rejection::from(MyError::AuthError(
+            "email or password does not match".to_owned(),
+        ))),
+        Verified::Valid { rehashed } => {
+            let tokens = db_txn(pool, false, |db| {
+                if let Some(new_hash) = &rehashed {
+                    diesel::update(users.find(user.id))
+                        .set(password_hash.eq(new_hash))
+                        .execute(db)?;
+                }
+                start_session(db, user.id)
+            })
+            .await?;
+            Ok(with_status(
+                json(&UserLoginRes {
+                    result: UserQueryResult::from(user),
+                    tokens,
+                }),
+                StatusCode::OK,
+            ))
         }
     }
 }
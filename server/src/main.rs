@@ -1,3 +1,5 @@
+#![allow(non_local_definitions)] // diesel 1.x derives
+
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -11,27 +13,69 @@ mod routes;
 mod schema;
 mod settings;
 
-use settings::DATABASE_URL;
+use std::process;
+
+use structopt::StructOpt;
 use warp::{hyper::Method, Filter};
 
-use routes::routes;
+use handlers::start_registry;
+use routes::{metrics_get, routes};
+use settings::{settings, Opts, Settings};
 
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
 
+    let opts = Opts::from_args();
+    let loaded = match Settings::load(&opts) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    // printed before validating so it can show why a config is invalid
+    if opts.print_config {
+        print!("{}", loaded.to_redacted_toml());
+    }
+    if let Err(e) = loaded.validate() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if opts.print_config {
+        return;
+    }
+    settings::init(loaded);
+
     info!("Creating connection pool");
-    let pool = db::pg_pool(DATABASE_URL.to_owned());
+    let pool = db::pg_pool(settings().database_url.clone());
+    let (registry, relays) = start_registry(settings().registry, pool.clone());
     let cors = warp::cors()
-        .allow_any_origin() // sketchy but Firefox 85 only gives Origin: null for extension network requests
         .allow_headers(vec!["content-type"])
-        .allow_methods(&[Method::POST, Method::DELETE, Method::GET]);
+        .allow_methods(&[Method::POST, Method::PATCH, Method::DELETE, Method::GET]);
+    let cors = if settings().cors_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(settings().cors_origins.iter().map(String::as_str))
+    };
 
-    let routes = routes(pool)
+    let routes = routes(pool, registry, relays)
         .with(warp::log("server::routes"))
         .with(cors)
         .recover(errors::handle_error);
 
+    // the counters are only for operators, so they're kept off the public address
+    match warp::serve(metrics_get()).try_bind_ephemeral(settings().metrics_bind_addr) {
+        Ok((addr, metrics_server)) => {
+            info!("Serving metrics on {}", addr);
+            tokio::spawn(metrics_server);
+        }
+        Err(e) => {
+            eprintln!("unable to serve metrics: {}", e);
+            process::exit(1);
+        }
+    }
+
     info!("Start the server");
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    warp::serve(routes).run(settings().bind_addr).await;
 }
@@ -1,41 +1,74 @@
-use std::convert::Infallible;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
 
 use serde::de::DeserializeOwned;
 use uuid::Uuid;
 use warp::Filter;
 
 use crate::{
-    auth::{for_authorized, for_authorized_ws},
+    auth::{for_authorized, for_authorized_ws, for_maybe_authorized, for_maybe_authorized_ws},
     db::PgPool,
     handlers::*,
+    settings::settings,
 };
 
 // all filters combined
 pub fn routes(
     pool: PgPool,
+    registry: Registry,
+    relays: RelayReceiver,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     let turn = turn_get();
 
-    let host_conns = HostConnections::default();
-    let listen_conns = ListenConnections::default();
-    let rooms = rooms_get(&pool, &host_conns)
-        .or(rooms_post(&pool))
-        .or(rooms_delete(&pool));
+    let live_rooms = LiveRooms::default();
+    let (room_events, directory_outbox) = RoomEvents::new();
+    let conns = Conns {
+        live_rooms: live_rooms.clone(),
+        lobbies: Lobbies::default(),
+        room_events: room_events.clone(),
+        cluster: Cluster::new(registry),
+    };
+    spawn_live_room_updates(pool.clone(), live_rooms.clone(), conns.cluster.clone());
+    spawn_relays(pool.clone(), conns.clone(), relays);
+    spawn_directory_relays(conns.cluster.clone(), directory_outbox);
+    let rooms = rooms_get(&pool, &conns)
+        .or(rooms_upcoming_get(&pool, &conns))
+        .or(rooms_events_get(&room_events))
+        .or(rooms_post(&pool, &room_events))
+        .or(rooms_patch(&pool, &conns))
+        .or(rooms_invites_post(&pool))
+        .or(rooms_bans_get(&pool))
+        .or(rooms_ban_delete(&pool))
+        .or(rooms_sessions_get(&pool, &live_rooms))
+        .or(rooms_members_get(&pool))
+        .or(rooms_members_post(&pool))
+        .or(rooms_member_delete(&pool))
+        .or(rooms_delete(&pool, &conns));
 
-    let room_conns = rooms_host_ws(&pool, &host_conns, &listen_conns).or(rooms_listen_ws(
-        &pool,
-        &host_conns,
-        &listen_conns,
-    ));
+    let room_conns = rooms_host_ws(&pool, &conns).or(rooms_listen_ws(&pool, &conns));
 
     let room_routes = warp::path("rooms").and(room_conns.or(rooms));
 
-    let users = warp::path("users").and(users_post(&pool).or(user_rooms_get(&pool, &host_conns)));
-    let my_routes =
-        warp::path("my").and(my_rooms_get(&pool, &host_conns).or(my_sessions_post(&pool)));
+    let users = warp::path("users").and(users_post(&pool).or(user_rooms_get(&pool, &conns)));
+    let my_routes = warp::path("my").and(
+        my_rooms_get(&pool, &conns)
+            .or(my_sessions_post(&pool))
+            .or(my_sessions_refresh_post(&pool))
+            .or(my_sessions_get(&pool))
+            .or(my_sessions_delete(&pool))
+            .or(my_session_delete(&pool)),
+    );
+
+    turn.or(room_routes).or(users).or(my_routes)
+}
 
-    let routes = turn.or(room_routes).or(users).or(my_routes);
-    routes
+// GET /metrics
+pub fn metrics_get() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and_then(get_metrics)
 }
 
 // POST /turn
@@ -55,29 +88,32 @@ pub fn users_post(
         .and_then(create_user)
 }
 
-// GET /users/<id>/rooms
+// GET /users/<id>/rooms?cursor=<NEXT_CURSOR>&limit=5
 pub fn user_rooms_get(
     pool: &PgPool,
-    host_conns: &HostConnections,
+    conns: &Conns,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(Uuid / "rooms")
         .and(warp::get())
+        .and(warp::query::<PageOptions>())
+        .and(for_maybe_authorized(pool))
         .and(with_db(pool.clone()))
-        .and(with_host_conns(host_conns.clone()))
+        .and(with_conns(conns.clone()))
         .and_then(list_rooms_for_user)
 }
 
-// GET /my/rooms
+// GET /my/rooms?cursor=<NEXT_CURSOR>&limit=5
 pub fn my_rooms_get(
     pool: &PgPool,
-    host_conns: &HostConnections,
+    conns: &Conns,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("rooms")
         .and(warp::get())
-        .and(for_authorized())
+        .and(for_authorized(pool))
+        .and(warp::query::<PageOptions>())
         .and(with_db(pool.clone()))
-        .and(with_host_conns(host_conns.clone()))
-        .and_then(list_rooms_for_user)
+        .and(with_conns(conns.clone()))
+        .and_then(list_my_rooms)
 }
 
 // POST /my/sessions with JSON body (this logs someone in)
@@ -91,78 +127,266 @@ pub fn my_sessions_post(
         .and_then(login_user)
 }
 
-// GET /rooms?offset=3&limit=5
+// POST /my/sessions/refresh with JSON body
+pub fn my_sessions_refresh_post(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions" / "refresh")
+        .and(warp::post())
+        .and(json_body::<SessionRefreshReq>())
+        .and(with_db(pool.clone()))
+        .and_then(refresh_session)
+}
+
+// GET /my/sessions
+pub fn my_sessions_get(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions")
+        .and(warp::get())
+        .and(for_authorized(pool))
+        .and(with_db(pool.clone()))
+        .and_then(list_sessions)
+}
+
+// DELETE /my/sessions (this logs someone out everywhere)
+pub fn my_sessions_delete(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions")
+        .and(warp::delete())
+        .and(for_authorized(pool))
+        .and(with_db(pool.clone()))
+        .and_then(revoke_all_sessions)
+}
+
+// DELETE /my/sessions/<ID>
+pub fn my_session_delete(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions" / Uuid)
+        .and(warp::delete())
+        .and(for_authorized(pool))
+        .and(with_db(pool.clone()))
+        .and_then(revoke_session)
+}
+
+// GET /rooms?q=<NAME>&live=true&owner=<NAME>&tags=<TAG>,<TAG>&sort=listeners&cursor=<NEXT_CURSOR>&limit=5
 pub fn rooms_get(
     pool: &PgPool,
-    host_conns: &HostConnections,
+    conns: &Conns,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::get()
-        .and(warp::query::<ListOptions>())
+    warp::path::end()
+        .and(warp::get())
+        .and(warp::query::<SearchOptions>())
+        .and(for_maybe_authorized(pool))
         .and(with_db(pool.clone()))
-        .and(with_host_conns(host_conns.clone()))
+        .and(with_conns(conns.clone()))
         .and_then(list_rooms)
 }
 
+// GET /rooms/upcoming?cursor=<CURSOR>&limit=5
+pub fn rooms_upcoming_get(
+    pool: &PgPool,
+    conns: &Conns,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("upcoming")
+        .and(warp::get())
+        .and(warp::query::<PageOptions>())
+        .and(with_db(pool.clone()))
+        .and(with_conns(conns.clone()))
+        .and_then(list_upcoming_rooms)
+}
+
+// GET /rooms/events?owner=<USER_ID>&rooms=<ID>,<ID> as Server-Sent Events
+pub fn rooms_events_get(
+    room_events: &RoomEvents,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("events")
+        .and(warp::get())
+        .and(warp::query::<EventsOptions>())
+        .and(with_room_events(room_events.clone()))
+        .and_then(stream_room_events)
+}
+
 // POST /rooms with JSON body
 pub fn rooms_post(
     pool: &PgPool,
+    room_events: &RoomEvents,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::post()
+    warp::path::end()
+        .and(warp::post())
         .and(with_db(pool.clone()))
-        .and(for_authorized())
+        .and(for_authorized(pool))
         .and(json_body::<RoomCreateReq>())
+        .and(with_room_events(room_events.clone()))
         .and_then(create_room)
 }
 
+// PATCH /rooms/<ID> with JSON body
+pub fn rooms_patch(
+    pool: &PgPool,
+    conns: &Conns,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(Uuid)
+        .and(warp::patch())
+        .and(with_db(pool.clone()))
+        .and(for_authorized(pool))
+        .and(json_body::<RoomUpdateReq>())
+        .and(with_conns(conns.clone()))
+        .and_then(update_room)
+}
+
+// POST /rooms/<ID>/invites with JSON body
+pub fn rooms_invites_post(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(Uuid / "invites")
+        .and(warp::post())
+        .and(with_db(pool.clone()))
+        .and(for_authorized(pool))
+        .and(json_body::<InviteCreateReq>())
+        .and_then(create_invite)
+}
+
+// GET /rooms/<ID>/bans
+pub fn rooms_bans_get(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(Uuid / "bans")
+        .and(warp::get())
+        .and(with_db(pool.clone()))
+        .and(for_authorized(pool))
+        .and_then(list_bans)
+}
+
+// GET /rooms/<ID>/sessions?offset=3&limit=5
+pub fn rooms_sessions_get(
+    pool: &PgPool,
+    live_rooms: &LiveRooms,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(Uuid / "sessions")
+        .and(warp::get())
+        .and(warp::query::<ListOptions>())
+        .and(with_db(pool.clone()))
+        .and(for_authorized(pool))
+        .and(with_live_rooms(live_rooms.clone()))
+        .and_then(list_room_sessions)
+}
+
+// DELETE /rooms/<ID>/bans/<BAN_ID>
+pub fn rooms_ban_delete(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(Uuid / "bans" / Uuid)
+        .and(warp::delete())
+        .and(with_db(pool.clone()))
+        .and(for_authorized(pool))
+        .and_then(delete_ban)
+}
+
+// GET /rooms/<ID>/members
+pub fn rooms_members_get(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(Uuid / "members")
+        .and(warp::get())
+        .and(with_db(pool.clone()))
+        .and(for_authorized(pool))
+        .and_then(list_members)
+}
+
+// POST /rooms/<ID>/members with JSON body
+pub fn rooms_members_post(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(Uuid / "members")
+        .and(warp::post())
+        .and(with_db(pool.clone()))
+        .and(for_authorized(pool))
+        .and(json_body::<MemberAddReq>())
+        .and_then(add_member)
+}
+
+// DELETE /rooms/<ID>/members/<USER_ID>
+pub fn rooms_member_delete(
+    pool: &PgPool,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(Uuid / "members" / Uuid)
+        .and(warp::delete())
+        .and(with_db(pool.clone()))
+        .and(for_authorized(pool))
+        .and_then(remove_member)
+}
+
 // DELETE /rooms/<ID>
 pub fn rooms_delete(
     pool: &PgPool,
+    conns: &Conns,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(Uuid)
         .and(warp::delete())
         .and(with_db(pool.clone()))
-        .and(for_authorized())
+        .and(for_authorized(pool))
+        .and(with_conns(conns.clone()))
         .and_then(delete_room)
 }
 
-// WS /rooms/<ID>/host?token=<TOKEN>
+// WS /rooms/<ID>/host?token=<TOKEN>[&takeover=true]
 pub fn rooms_host_ws(
     pool: &PgPool,
-    host_conns: &HostConnections,
-    listen_conns: &ListenConnections,
+    conns: &Conns,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(Uuid / "host")
-        .and(for_authorized_ws())
+        .and(for_authorized_ws(pool))
+        .and(warp::query::<HostOptions>())
         .and(warp::ws())
         .and(with_db(pool.clone()))
-        .and(with_conns(host_conns.clone(), listen_conns.clone()))
+        .and(with_conns(conns.clone()))
         .and_then(host_room)
 }
 
-// WS /rooms/<ID>/listen
+// WS /rooms/<ID>/listen[?token=<TOKEN>][&invite=<INVITE>][&password=<PASSWORD>]
 pub fn rooms_listen_ws(
     pool: &PgPool,
-    host_conns: &HostConnections,
-    listen_conns: &ListenConnections,
+    conns: &Conns,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(Uuid / "listen")
+        .and(warp::query::<ListenOptions>())
+        .and(for_maybe_authorized_ws(pool))
+        .and(with_client_ip())
         .and(warp::ws())
         .and(with_db(pool.clone()))
-        .and(with_conns(host_conns.clone(), listen_conns.clone()))
+        .and(with_conns(conns.clone()))
         .and_then(listen_room)
 }
 
-fn with_host_conns(
-    host_conns: HostConnections,
-) -> impl Filter<Extract = (HostConnections,), Error = Infallible> + Clone {
-    warp::any().map(move || host_conns.clone())
+fn with_live_rooms(
+    live_rooms: LiveRooms,
+) -> impl Filter<Extract = (LiveRooms,), Error = Infallible> + Clone {
+    warp::any().map(move || live_rooms.clone())
+}
+
+fn with_room_events(
+    room_events: RoomEvents,
+) -> impl Filter<Extract = (RoomEvents,), Error = Infallible> + Clone {
+    warp::any().map(move || room_events.clone())
+}
+
+fn with_conns(conns: Conns) -> impl Filter<Extract = (Conns,), Error = Infallible> + Clone {
+    warp::any().map(move || conns.clone())
 }
 
-fn with_conns(
-    host_conns: HostConnections,
-    listen_conns: ListenConnections,
-) -> impl Filter<Extract = ((HostConnections, ListenConnections),), Error = Infallible> + Clone {
-    warp::any().map(move || (host_conns.clone(), listen_conns.clone()))
+// the proxy in front appends the address it saw, anything before that came from the client
+fn with_client_ip() -> impl Filter<Extract = (Option<IpAddr>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("x-forwarded-for")
+        .and(warp::addr::remote())
+        .map(|forwarded: Option<String>, remote: Option<SocketAddr>| {
+            forwarded
+                .filter(|_| settings().trust_forwarded_for)
+                .and_then(|forwarded| forwarded.rsplit(',').next()?.trim().parse().ok())
+                .or_else(|| remote.map(|remote| remote.ip()))
+        })
 }
 
 fn with_db(pool: PgPool) -> impl Filter<Extract = (PgPool,), Error = Infallible> + Clone {
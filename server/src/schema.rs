@@ -1,3 +1,51 @@
+table! {
+    room_members (room_id, user_id) {
+        room_id -> Uuid,
+        user_id -> Uuid,
+        role -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    room_bans (id) {
+        id -> Uuid,
+        room_id -> Uuid,
+        ip_address -> Nullable<Varchar>,
+        user_id -> Nullable<Uuid>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    relay_payloads (id) {
+        id -> Uuid,
+        payload -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    room_hosts (room_id) {
+        room_id -> Uuid,
+        instance_id -> Uuid,
+        reconnecting -> Bool,
+        updated_at -> Timestamptz,
+        listener_count -> Int4,
+        now_playing -> Nullable<Text>,
+    }
+}
+
+table! {
+    room_sessions (id) {
+        id -> Uuid,
+        room_id -> Uuid,
+        started_at -> Timestamptz,
+        ended_at -> Nullable<Timestamptz>,
+        peak_listeners -> Int4,
+    }
+}
+
 table! {
     rooms (id) {
         id -> Uuid,
@@ -5,6 +53,27 @@ table! {
         room_name -> Varchar,
         created_at -> Timestamptz,
         last_connected -> Nullable<Timestamptz>,
+        max_listeners -> Nullable<Int4>,
+        visibility -> Varchar,
+        join_password_hash -> Nullable<Varchar>,
+        description -> Nullable<Varchar>,
+        tags -> Array<Text>,
+        require_login -> Bool,
+        scheduled_start -> Nullable<Timestamptz>,
+        scheduled_end -> Nullable<Timestamptz>,
+        recurrence -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        refresh_token_hash -> Bytea,
+        created_at -> Timestamptz,
+        refreshed_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -14,14 +83,26 @@ table! {
         display_name -> Varchar,
         email -> Varchar,
         created_at -> Timestamptz,
-        pass_hash -> Bytea,
-        salt -> Bytea,
+        password_hash -> Varchar,
     }
 }
 
+joinable!(room_bans -> rooms (room_id));
+joinable!(room_bans -> users (user_id));
+joinable!(room_members -> rooms (room_id));
+joinable!(room_hosts -> rooms (room_id));
+joinable!(room_members -> users (user_id));
+joinable!(room_sessions -> rooms (room_id));
 joinable!(rooms -> users (user_id));
+joinable!(sessions -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    relay_payloads,
+    room_bans,
+    room_hosts,
+    room_members,
+    room_sessions,
     rooms,
+    sessions,
     users,
 );
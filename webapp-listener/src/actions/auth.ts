@@ -1,4 +1,7 @@
+import { get } from "svelte/store";
+
 import { settings } from "@src/settings";
+import { userStore } from "@src/store";
 import type { UserStore } from "@src/store";
 
 import { handleError } from "./util";
@@ -47,3 +50,77 @@ export const login = async (email: string, password: string): Promise<UserStore>
     }).catch((reason) => rej(reason));
   });
 };
+
+type SessionTokens = {
+  session_id: string,
+  token: string,
+  refresh_token: string,
+};
+
+// refresh tokens are single-use, so concurrent callers share one refresh
+let refreshing: Promise<SessionTokens> | null = null;
+
+const refreshSession = async (refreshToken: string): Promise<SessionTokens> => {
+  const response = await fetch(`${settings.API_SERVER}/my/sessions/refresh`, {
+    method: 'POST',
+    headers: {
+      'Content-Type': 'application/json',
+    },
+    body: JSON.stringify({
+      refresh_token: refreshToken,
+    }),
+  });
+  if (!response.ok) {
+    if (response.status === 401) {
+      // the session was revoked or ran out, log in again
+      userStore.set(null);
+    }
+    throw new Error(await handleError(response));
+  }
+  return response.json();
+};
+
+// seconds since the epoch, 0 if the token can't be read
+const tokenExpiry = (token: string): number => {
+  try {
+    const payload = token.split('.')[1].replace(/-/g, '+').replace(/_/g, '/');
+    return JSON.parse(atob(payload)).exp;
+  } catch {
+    return 0;
+  }
+};
+
+// the logged in user's access token, refreshed first if it's about to run out
+export const freshToken = async (): Promise<string | undefined> => {
+  const user = get(userStore);
+  if (!user) return undefined;
+  if (tokenExpiry(user.token) - 60 > Date.now() / 1000) return user.token;
+  if (!user.refresh_token) {
+    // logged in before sessions could be refreshed
+    userStore.set(null);
+    return undefined;
+  }
+  if (!refreshing) {
+    refreshing = refreshSession(user.refresh_token).finally(() => {
+      refreshing = null;
+    });
+  }
+  const tokens = await refreshing;
+  userStore.update((current) => current && { ...current, ...tokens });
+  return tokens.token;
+};
+
+// logs out of this session on the server too, so its refresh token stops working
+export const logout = async (): Promise<void> => {
+  const user = get(userStore);
+  if (!user) return;
+  const token = await freshToken().catch(() => undefined);
+  userStore.set(null);
+  if (!token) return;
+  await fetch(`${settings.API_SERVER}/my/sessions/${encodeURIComponent(user.session_id)}`, {
+    method: 'DELETE',
+    headers: {
+      Authorization: `Bearer ${token}`,
+    },
+  }).catch((err) => console.error('unable to end session', err));
+};
@@ -2,6 +2,8 @@ import 'webrtc-adapter';
 
 import { settings } from '@src/settings';
 
+import type { Broadcast, NowPlaying } from './rooms';
+
 type TurnCreds = {
   username: string,
   credential: string,
@@ -48,10 +50,54 @@ const getConfig = async (): Promise<Configuration> => {
   return config;
 };
 
+const PROTOCOL_VERSION = 1;
+
+type Signal =
+  { type: 'offer' | 'answer', sdp: string }
+  | { type: 'ice-candidate', candidate: RTCIceCandidateInit | null }
+  | { type: 'renegotiate' }
+  | { type: 'bye', reason?: string }
+  | { type: 'error', message: string };
+
+export type ChatMessage = {
+  id: number,
+  author: { role: 'host' }
+    | { role: 'listener', id: string, display_name: string | null, user_id: string | null },
+  text: string,
+  sent_at: string,
+};
+
+type ToListenerMessage =
+  { type: 'welcome', version: number, id: string }
+  | { type: 'admitted' }
+  | { type: 'queued', position: number }
+  | { type: 'waiting-for-host', broadcast: Broadcast }
+  | { type: 'signal', signal: Signal }
+  | { type: 'host-reconnecting', grace_seconds: number }
+  | { type: 'host-resumed' }
+  | ({ type: 'now-playing' } & NowPlaying)
+  | { type: 'chat', message: ChatMessage }
+  | { type: 'chat-deleted', id: number }
+  | { type: 'chat-scrollback', messages: ChatMessage[] }
+  | { type: 'chat-muted', muted: boolean }
+  | { type: 'kicked', banned: boolean }
+  | { type: 'room-updated', room: { name: string, description: string | null, tags: string[] } }
+  | { type: 'room-closed', reason: 'host-left' | 'room-deleted' | 'host-replaced' }
+  | { type: 'error', code: string, message: string };
+
 const wsSend = (ws: WebSocket, msg: any) => {
   ws.send(JSON.stringify(msg));
 }
 
+const wsSendSignal = (ws: WebSocket, signal: Signal) => {
+  wsSend(ws, { type: 'signal', signal });
+}
+
+const descriptionSignal = (description: RTCSessionDescription): Signal => ({
+  type: description.type as 'offer' | 'answer',
+  sdp: description.sdp,
+});
+
 export async function initPeerConnection(wsParam: WebSocket) {
   console.log('initializing rtc peer connection');
   const ws = wsParam;
@@ -59,7 +105,8 @@ export async function initPeerConnection(wsParam: WebSocket) {
   // negotiate WebRTC connection
   const config = await getConfig();
   const pc = new RTCPeerConnection(config);
-  wsSend(ws, { msg: {} });
+  // ask the host to start negotiating with us
+  wsSendSignal(ws, { type: 'renegotiate' });
   let makingOffer = false;
   pc.onnegotiationneeded = async () => {
     try {
@@ -68,26 +115,66 @@ export async function initPeerConnection(wsParam: WebSocket) {
       // @ts-ignore: missing argument is OK
       await pc.setLocalDescription();
       console.debug('local description', pc.localDescription);
-      wsSend(ws, {
-        description: pc.localDescription,
-      });
+      wsSendSignal(ws, descriptionSignal(pc.localDescription));
     } catch (err) {
       console.error(err);
     } finally {
       makingOffer = false;
     }
   };
-  pc.onicecandidate = ({ candidate }) => wsSend(ws, {
-    candidate: candidate,
+  pc.onicecandidate = ({ candidate }) => wsSendSignal(ws, {
+    type: 'ice-candidate',
+    candidate: candidate ? candidate.toJSON() : null,
   });
 
   let ignoreOffer = false;
   ws.onmessage = async ({ data }) => {
     console.debug('received message with data', data);
-    const { description, candidate } = JSON.parse(data);
+    const message: ToListenerMessage = JSON.parse(data);
+    if (message.type === 'error') {
+      console.error('server rejected message', message.code, message.message);
+      return;
+    }
+    if (message.type === 'host-reconnecting') {
+      // audio keeps flowing over the peer connection, only signaling is down
+      console.log(`host disconnected, waiting up to ${message.grace_seconds}s for it`);
+      return;
+    }
+    if (message.type === 'host-resumed') {
+      console.log('host is back');
+      return;
+    }
+    if (message.type === 'chat' || message.type === 'chat-deleted'
+      || message.type === 'chat-scrollback' || message.type === 'chat-muted') {
+      console.debug('chat', message);
+      return;
+    }
+    if (message.type === 'now-playing') {
+      console.log('now playing', message.title, message.status);
+      return;
+    }
+    if (message.type === 'room-updated') {
+      console.log('room settings changed', message.room);
+      return;
+    }
+    if (message.type === 'kicked') {
+      // the server closes the websocket right after this
+      console.log(message.banned ? 'banned by the host' : 'kicked by the host');
+      pc.close();
+      return;
+    }
+    if (message.type === 'room-closed') {
+      // the server closes the websocket right after this
+      console.log('room closed', message.reason);
+      pc.close();
+      return;
+    }
+    if (message.type !== 'signal') return;
+    const { signal } = message;
     try {
-      console.debug('received message with', description, candidate);
-      if (description) {
+      console.debug('received signal', signal);
+      if (signal.type === 'offer' || signal.type === 'answer') {
+        const description = { type: signal.type, sdp: signal.sdp };
         const offerCollision = description.type === 'offer'
           && (makingOffer || pc.signalingState !== 'stable');
         ignoreOffer = !polite && offerCollision;
@@ -98,20 +185,25 @@ export async function initPeerConnection(wsParam: WebSocket) {
         if (description.type === 'offer') {
           // @ts-ignore: missing argument is OK
           await pc.setLocalDescription();
-          wsSend(ws, {
-            description: pc.localDescription,
-          });
+          wsSendSignal(ws, descriptionSignal(pc.localDescription));
         }
-      } else if (candidate) {
+      } else if (signal.type === 'ice-candidate' && signal.candidate) {
+        const { candidate } = signal;
         try {
           console.debug('candidate', candidate);
           await pc.addIceCandidate(candidate);
         } catch (err) {
           if (!ignoreOffer) throw err;
         }
+      } else if (signal.type === 'bye') {
+        console.log('host said bye', signal.reason);
+        pc.close();
+      } else if (signal.type === 'error') {
+        console.error('host reported error', signal.message);
       }
     } catch (err) {
       console.error(err);
+      wsSendSignal(ws, { type: 'error', message: String(err) });
     }
   };
 
@@ -127,25 +219,64 @@ export async function initPeerConnection(wsParam: WebSocket) {
   return pc;
 }
 
-export default function startListenConnection(roomId: string): Promise<[RTCPeerConnection, WebSocket]> {
-  const ws = new WebSocket(`${settings.WS_SERVER}/rooms/${roomId}/listen`);
+// needed for private rooms and rooms with a password, an invite covers both
+export type RoomAccess = {
+  // the listener's access token, to join as themselves rather than anonymously
+  token?: string,
+  invite?: string,
+  password?: string,
+};
+
+export default function startListenConnection(
+  roomId: string,
+  access: RoomAccess = {},
+): Promise<[RTCPeerConnection, WebSocket]> {
+  const params = new URLSearchParams();
+  if (access.token) params.set('token', access.token);
+  if (access.invite) params.set('invite', access.invite);
+  if (access.password) params.set('password', access.password);
+  const query = params.toString() ? `?${params}` : '';
+  const ws = new WebSocket(`${settings.WS_SERVER}/rooms/${roomId}/listen${query}`);
   return new Promise((res, rej) => {
+    // waiting in line for a full room can take a while, so only the handshake is timed
+    const timeout = setTimeout(() => {
+      rej();
+    }, 1000);
     ws.onopen = () => {
-      try {
-        initPeerConnection(ws).then((pc) => {
-          res([pc, ws]);
-        });
-      } catch (err) {
+      wsSend(ws, { type: 'hello', version: PROTOCOL_VERSION });
+    };
+    // the peer connection takes over onmessage once the server has let us into the room
+    ws.onmessage = ({ data }) => {
+      const message: ToListenerMessage = JSON.parse(data);
+      if (message.type === 'error') {
+        console.error('unable to join room', message.code, message.message);
+        rej(new Error(message.message));
+        return;
+      }
+      if (message.type === 'welcome') {
+        console.log('joined room as', message.id);
+        clearTimeout(timeout);
+        return;
+      }
+      if (message.type === 'queued') {
+        console.log(`room is full, number ${message.position} in line`);
+        return;
+      }
+      if (message.type === 'waiting-for-host') {
+        console.log(`waiting for the host, broadcast starts at ${message.broadcast.start}`);
+        return;
+      }
+      if (message.type !== 'admitted') return;
+      initPeerConnection(ws).then((pc) => {
+        res([pc, ws]);
+      }).catch((err) => {
         console.error(err);
         rej(err);
-      }
+      });
     };
     ws.onerror = () => {
       ws.close();
       rej();
     };
-    setTimeout(() => {
-      rej();
-    }, 1000);
   });
 }
@@ -2,28 +2,120 @@ import { settings } from "@src/settings";
 
 import { handleError } from "./util";
 
-type HostStatus = 'playing' | 'stopped';
+type HostStatus = 'playing' | 'paused' | 'stopped' | 'reconnecting' | 'unknown';
+
+export type NowPlaying = {
+  status: 'playing' | 'paused',
+  title: string | null,
+  artist: string | null,
+  artwork_url: string | null,
+  // seconds
+  duration: number | null,
+  // seconds into the track as of updated_at
+  position: number | null,
+  updated_at: string,
+};
+
+// one of a scheduled room's broadcasts
+export type Broadcast = {
+  start: string,
+  end: string,
+};
 
 export type RoomInfo = {
   id: string,
   name: string,
   hostName?: string,
   hostStatus?: HostStatus,
+  nowPlaying?: NowPlaying,
+  description?: string,
+  tags?: string[],
+  requireLogin?: boolean,
+  nextBroadcast?: Broadcast,
+  listenerCount: number,
 };
 
-export const getRooms = async (): Promise<RoomInfo[]> => {
-  const response = await fetch(`${settings.API_SERVER}/rooms`);
+export type RoomSearch = {
+  // part of the room's name
+  q?: string,
+  live?: boolean,
+  // the owner's display name
+  owner?: string,
+  tags?: string[],
+  sort?: 'created_at' | 'last_connected' | 'listeners',
+  // nextCursor of the page before
+  cursor?: string,
+  limit?: number,
+};
+
+export type RoomPage = {
+  rooms: RoomInfo[],
+  // undefined on the last page
+  nextCursor?: string,
+  total: number,
+};
+
+export const getRooms = async (search: RoomSearch = {}): Promise<RoomPage> => {
+  const params = new URLSearchParams();
+  if (search.q) params.set('q', search.q);
+  if (search.live !== undefined) params.set('live', String(search.live));
+  if (search.owner) params.set('owner', search.owner);
+  if (search.tags?.length) params.set('tags', search.tags.join(','));
+  if (search.sort) params.set('sort', search.sort);
+  if (search.cursor) params.set('cursor', search.cursor);
+  if (search.limit) params.set('limit', String(search.limit));
+  const query = params.toString() ? `?${params}` : '';
+  const response = await fetch(`${settings.API_SERVER}/rooms${query}`);
   return new Promise((res, rej) => {
     if (!response.ok) {
       rej(handleError(response));
     }
-    response.json().then((rooms) => {
-      res(rooms.map((roomInfo) => ({
+    response.json().then((page) => {
+      const rooms = page.rooms.map((roomInfo) => ({
         id: roomInfo.id,
         name: roomInfo.name,
         hostName: roomInfo.host_name,
         hostStatus: roomInfo.host_status,
-      })));
+        nowPlaying: roomInfo.now_playing || undefined,
+        description: roomInfo.description || undefined,
+        tags: roomInfo.tags,
+        requireLogin: roomInfo.require_login,
+        nextBroadcast: roomInfo.next_broadcast || undefined,
+        listenerCount: roomInfo.listener_count,
+      }));
+      res({ rooms, nextCursor: page.next_cursor || undefined, total: page.total });
     }).catch((reason) => rej(reason));
   });
 };
+
+export type RoomEvent = { roomId: string } & (
+  | { type: 'room-created' | 'room-deleted' | 'host-online' | 'host-offline' }
+  | { type: 'listener-count-changed', listenerCount: number }
+  | { type: 'now-playing', nowPlaying: NowPlaying }
+);
+
+const ROOM_EVENT_TYPES = [
+  'room-created',
+  'room-deleted',
+  'host-online',
+  'host-offline',
+  'listener-count-changed',
+  'now-playing',
+];
+
+// calls back with changes to public rooms as they happen, returns a function that stops it
+export const watchRooms = (onEvent: (event: RoomEvent) => void): (() => void) => {
+  const source = new EventSource(`${settings.API_SERVER}/rooms/events`);
+  ROOM_EVENT_TYPES.forEach((type) => {
+    source.addEventListener(type, (e: MessageEvent) => {
+      const data = JSON.parse(e.data);
+      onEvent({
+        type: data.type,
+        roomId: data.room_id,
+        listenerCount: data.listener_count,
+        nowPlaying: data.now_playing,
+      } as RoomEvent);
+    });
+  });
+  return () => source.close();
+};
@@ -3,7 +3,6 @@ export type Settings = {
   WS_SERVER: string,
   ICE_SERVER: string | null,
   FFT_SIZE: number,
-  WS_KEEPALIVE_MS: number,
 };
 
 let mySettings: Settings;
@@ -18,7 +17,6 @@ switch (__MYENV__) {
       WS_SERVER: 'wss://radiowo.edwlee.dev/ws',
       ICE_SERVER: 'turn:radiowo.edwlee.dev:3478',
       FFT_SIZE: 32,
-      WS_KEEPALIVE_MS: 10000,
     };
     break;
   }
@@ -28,7 +26,6 @@ switch (__MYENV__) {
       WS_SERVER: 'ws://localhost:3030',
       ICE_SERVER: null,
       FFT_SIZE: 32,
-      WS_KEEPALIVE_MS: 10000,
     };
     break;
   }
@@ -38,7 +35,6 @@ switch (__MYENV__) {
       WS_SERVER: 'ws://192.168.1.128:58008/ws',
       ICE_SERVER: null,
       FFT_SIZE: 32,
-      WS_KEEPALIVE_MS: 10000,
     };
     break;
   }
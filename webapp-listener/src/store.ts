@@ -3,7 +3,10 @@ import { Writable, writable } from 'svelte/store';
 import type { RoomInfo } from '@src/actions/rooms';
 
 export type UserStore = {
+  session_id: string,
+  // short-lived, get a new one with freshToken
   token: string,
+  refresh_token: string,
   result: {
     id: string,
     display_name: string,